@@ -0,0 +1,69 @@
+//! Times every `.mlk` script in this directory on each engine, taking the
+//! best of several runs of the release build:
+//!
+//!     cargo bench [-- <filter>]
+//!
+//! Only scripts whose name contains the filter are run. Each run is a whole
+//! `mollusk` process, so the times include parsing and compiling. An engine
+//! that cannot run a script is shown as failed.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const ENGINES: [&str; 3] = ["stack", "bytecode", "register"];
+const RUNS: usize = 5;
+
+/// The best time of `RUNS` runs of `script` on `engine`, or `None` if it
+/// fails.
+fn time(script: &Path, engine: &str) -> Option<Duration> {
+    let mut best: Option<Duration> = None;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let status = Command::new(env!("CARGO_BIN_EXE_mollusk"))
+            .arg(format!("--engine={}", engine))
+            .arg(script)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .ok()?;
+        let elapsed = start.elapsed();
+        if !status.success() {
+            return None;
+        }
+        best = Some(best.map_or(elapsed, |best| best.min(elapsed)));
+    }
+    best
+}
+
+fn main() {
+    // Cargo passes `--bench`; anything else is a filter.
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches");
+    let mut scripts: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .expect("benches directory")
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "mlk"))
+        .collect();
+    scripts.sort();
+
+    print!("{:<16}", "bench");
+    for engine in ENGINES {
+        print!("{:>10}", engine);
+    }
+    println!();
+    for script in scripts {
+        let name = script.file_stem().unwrap_or_default().to_string_lossy();
+        if filter.as_ref().is_some_and(|filter| !name.contains(filter.as_str())) {
+            continue;
+        }
+        print!("{:<16}", name);
+        for engine in ENGINES {
+            match time(&script, engine) {
+                Some(best) => print!("{:>9.3}s", best.as_secs_f64()),
+                None => print!("{:>10}", "failed"),
+            }
+        }
+        println!();
+    }
+}
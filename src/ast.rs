@@ -1,13 +1,24 @@
 use crate::tokenizer::Token;
+use crate::typecheck::Type;
+use miette::SourceSpan;
+
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: String,
+    pub ty: Option<Type>,
+}
 
 #[derive(Debug, Clone)]
 pub enum ASTNode {
     Number(i32),
     String(String),
+    Bytes(Vec<u8>),
+    Null,
     BinOp {
         left: Box<ASTNode>,
         op: Token,
         right: Box<ASTNode>,
+        span: SourceSpan,
     },
     If {
         condition: Box<ASTNode>,
@@ -18,18 +29,99 @@ pub enum ASTNode {
         condition: Box<ASTNode>,
         body: Vec<ASTNode>,
     },
-    VarDecl(String, Box<ASTNode>),
-    VarAssign(String, Box<ASTNode>),
+    For {
+        var: String,
+        iterable: Box<ASTNode>,
+        body: Vec<ASTNode>,
+    },
+    VarDecl {
+        name: String,
+        ty: Option<Type>,
+        value: Box<ASTNode>,
+        span: SourceSpan,
+    },
     VarRef(String),
     Block(Vec<ASTNode>),
     Array(Vec<ASTNode>),
+    Tuple(Vec<ASTNode>),
+    /// `(a, b) = value`: unpacks a tuple into variables.
+    Destructure {
+        names: Vec<String>,
+        value: Box<ASTNode>,
+        span: SourceSpan,
+    },
     ArrayIndex {
         array: Box<ASTNode>,
         index: Box<ASTNode>,
+        /// `array?[index]`: evaluates to null when `array` is null.
+        optional: bool,
+        span: SourceSpan,
     },
     ArrayAssign {
         array: Box<ASTNode>,
         index: Box<ASTNode>,
         value: Box<ASTNode>,
+        span: SourceSpan,
+    },
+    FnDecl {
+        name: String,
+        params: Vec<Param>,
+        return_type: Option<Type>,
+        body: Vec<ASTNode>,
+        is_generator: bool,
+        /// A `const fn` may also be called from `comptime` blocks.
+        is_const: bool,
+    },
+    Call {
+        callee: Box<ASTNode>,
+        args: Vec<ASTNode>,
+        span: SourceSpan,
+    },
+    MethodCall {
+        object: Box<ASTNode>,
+        method: String,
+        args: Vec<ASTNode>,
+        optional: bool,
+    },
+    ClassDecl {
+        name: String,
+        methods: Vec<ASTNode>,
+    },
+    FieldAccess {
+        object: Box<ASTNode>,
+        field: String,
+        optional: bool,
+    },
+    NullCoalesce {
+        left: Box<ASTNode>,
+        right: Box<ASTNode>,
+    },
+    FieldAssign {
+        object: Box<ASTNode>,
+        field: String,
+        value: Box<ASTNode>,
+    },
+    Return {
+        value: Option<Box<ASTNode>>,
+        span: SourceSpan,
+    },
+    Yield {
+        value: Box<ASTNode>,
+        span: SourceSpan,
+    },
+    /// Evaluated while compiling; the value of the last statement is
+    /// embedded in the program.
+    Comptime {
+        body: Vec<ASTNode>,
+        span: SourceSpan,
+    },
+    /// Evaluates `value` when the enclosing scope is left.
+    Defer(Box<ASTNode>),
+    Assert {
+        condition: Box<ASTNode>,
+        message: Option<Box<ASTNode>>,
+        /// Source text of the condition, for the failure report.
+        text: String,
+        span: SourceSpan,
     },
 }
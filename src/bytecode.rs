@@ -0,0 +1,503 @@
+use crate::error::VMError;
+use crate::types::{Function, Value};
+use crate::vm::{map_address, ArrayOperation, Instruction, Program};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The first byte of each encoded instruction.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    PushConst,
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Greater,
+    Less,
+    Equal,
+    NotEqual,
+    Jmp,
+    Jz,
+    JmpIfNull,
+    JmpIfNotNull,
+    Label,
+    LoadLocal,
+    StoreLocal,
+    LoadGlobal,
+    StoreGlobal,
+    TeeLocal,
+    TeeGlobal,
+    BeginScope,
+    EndScope,
+    CreateArray,
+    ArrayPush,
+    ArrayPop,
+    ArrayGet,
+    ArraySet,
+    Call,
+    CallMethod,
+    Return,
+    Yield,
+    Defer,
+    EndDefer,
+    ForIter,
+    Assert,
+    MakeTuple,
+    UnpackTuple,
+    MakeClass,
+    GetField,
+    SetField,
+}
+
+impl Opcode {
+    const ALL: [Opcode; 41] = [
+        Opcode::PushConst,
+        Opcode::Pop,
+        Opcode::Add,
+        Opcode::Sub,
+        Opcode::Mul,
+        Opcode::Div,
+        Opcode::Greater,
+        Opcode::Less,
+        Opcode::Equal,
+        Opcode::NotEqual,
+        Opcode::Jmp,
+        Opcode::Jz,
+        Opcode::JmpIfNull,
+        Opcode::JmpIfNotNull,
+        Opcode::Label,
+        Opcode::LoadLocal,
+        Opcode::StoreLocal,
+        Opcode::LoadGlobal,
+        Opcode::StoreGlobal,
+        Opcode::TeeLocal,
+        Opcode::TeeGlobal,
+        Opcode::BeginScope,
+        Opcode::EndScope,
+        Opcode::CreateArray,
+        Opcode::ArrayPush,
+        Opcode::ArrayPop,
+        Opcode::ArrayGet,
+        Opcode::ArraySet,
+        Opcode::Call,
+        Opcode::CallMethod,
+        Opcode::Return,
+        Opcode::Yield,
+        Opcode::Defer,
+        Opcode::EndDefer,
+        Opcode::ForIter,
+        Opcode::Assert,
+        Opcode::MakeTuple,
+        Opcode::UnpackTuple,
+        Opcode::MakeClass,
+        Opcode::GetField,
+        Opcode::SetField,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Opcode::ALL.get(byte as usize).copied()
+    }
+}
+
+/// A program encoded as bytes: a one-byte opcode per instruction followed
+/// by its operands as LEB128 varints. Code addresses are byte offsets into
+/// `code`, and strings are indices into `names`.
+#[derive(Debug, Clone, Default)]
+pub struct Bytecode {
+    pub code: Vec<u8>,
+    /// The constant pool. Function entry points are byte offsets.
+    pub constants: Vec<Value>,
+    /// Method, field and class names and assertion texts.
+    pub names: Vec<String>,
+    pub globals: Vec<String>,
+}
+
+/// Appends `value` as a LEB128 varint.
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Reads the varint at `at`, moving `at` past it.
+#[inline]
+pub fn read_varint(code: &[u8], at: &mut usize) -> Result<u64, VMError> {
+    // Most operands are small enough for a single byte.
+    match code.get(*at) {
+        Some(&byte) if byte < 0x80 => {
+            *at += 1;
+            Ok(u64::from(byte))
+        }
+        _ => read_long_varint(code, at),
+    }
+}
+
+/// Reads the varint operand at `at` as a `T`, moving `at` past it. Both
+/// decoders read operands through this, so an operand too large for the
+/// field it fills is rejected the same way by each.
+#[inline]
+pub fn read_operand<T: TryFrom<u64>>(code: &[u8], at: &mut usize) -> Result<T, VMError> {
+    let start = *at;
+    let value = read_varint(code, at)?;
+    T::try_from(value).map_err(|_| VMError::InvalidBytecode {
+        offset: start,
+        message: format!("operand {} is out of range", value),
+    })
+}
+
+fn read_long_varint(code: &[u8], at: &mut usize) -> Result<u64, VMError> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *code.get(*at).ok_or_else(|| VMError::InvalidBytecode {
+            offset: *at,
+            message: "operand runs past the end of the code".to_string(),
+        })?;
+        *at += 1;
+        if shift >= 64 || (shift == 63 && byte > 1) {
+            return Err(VMError::InvalidBytecode {
+                offset: *at - 1,
+                message: "varint operand is too long".to_string(),
+            });
+        }
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Interns names while encoding.
+#[derive(Default)]
+struct Names {
+    names: Vec<String>,
+    indices: HashMap<String, usize>,
+}
+
+impl Names {
+    fn index(&mut self, name: &str) -> usize {
+        if let Some(&index) = self.indices.get(name) {
+            return index;
+        }
+        self.names.push(name.to_string());
+        self.indices.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+}
+
+/// Appends the encoding of `instruction`, with its code address already
+/// translated to a byte offset.
+fn encode_instruction(instruction: &Instruction, names: &mut Names, out: &mut Vec<u8>) {
+    let mut op = |opcode: Opcode, operands: &[usize]| {
+        out.push(opcode as u8);
+        for &operand in operands {
+            write_varint(out, operand as u64);
+        }
+    };
+    match instruction {
+        Instruction::Push(_) => unreachable!("inline constants are pooled before encoding"),
+        Instruction::PushConst(index) => op(Opcode::PushConst, &[*index as usize]),
+        Instruction::Pop => op(Opcode::Pop, &[]),
+        Instruction::Add => op(Opcode::Add, &[]),
+        Instruction::Sub => op(Opcode::Sub, &[]),
+        Instruction::Mul => op(Opcode::Mul, &[]),
+        Instruction::Div => op(Opcode::Div, &[]),
+        Instruction::Greater => op(Opcode::Greater, &[]),
+        Instruction::Less => op(Opcode::Less, &[]),
+        Instruction::Equal => op(Opcode::Equal, &[]),
+        Instruction::NotEqual => op(Opcode::NotEqual, &[]),
+        Instruction::Jmp(target) => op(Opcode::Jmp, &[*target]),
+        Instruction::Jz(target) => op(Opcode::Jz, &[*target]),
+        Instruction::JmpIfNull(target) => op(Opcode::JmpIfNull, &[*target]),
+        Instruction::JmpIfNotNull(target) => op(Opcode::JmpIfNotNull, &[*target]),
+        Instruction::Label(label) => op(Opcode::Label, &[*label]),
+        Instruction::LoadLocal(slot) => op(Opcode::LoadLocal, &[*slot as usize]),
+        Instruction::StoreLocal(slot) => op(Opcode::StoreLocal, &[*slot as usize]),
+        Instruction::LoadGlobal(index) => op(Opcode::LoadGlobal, &[*index as usize]),
+        Instruction::StoreGlobal(index) => op(Opcode::StoreGlobal, &[*index as usize]),
+        Instruction::TeeLocal(slot) => op(Opcode::TeeLocal, &[*slot as usize]),
+        Instruction::TeeGlobal(index) => op(Opcode::TeeGlobal, &[*index as usize]),
+        Instruction::BeginScope => op(Opcode::BeginScope, &[]),
+        Instruction::EndScope => op(Opcode::EndScope, &[]),
+        Instruction::CreateArray => op(Opcode::CreateArray, &[]),
+        Instruction::ArrayOp(ArrayOperation::Push) => op(Opcode::ArrayPush, &[]),
+        Instruction::ArrayOp(ArrayOperation::Pop) => op(Opcode::ArrayPop, &[]),
+        Instruction::ArrayOp(ArrayOperation::Get(operand)) => op(Opcode::ArrayGet, &[*operand]),
+        Instruction::ArrayOp(ArrayOperation::Set(operand)) => op(Opcode::ArraySet, &[*operand]),
+        Instruction::Call(argc) => op(Opcode::Call, &[*argc]),
+        Instruction::CallMethod(name, argc) => op(Opcode::CallMethod, &[names.index(name), *argc]),
+        Instruction::Return => op(Opcode::Return, &[]),
+        Instruction::Yield => op(Opcode::Yield, &[]),
+        Instruction::Defer(entry) => op(Opcode::Defer, &[*entry]),
+        Instruction::EndDefer => op(Opcode::EndDefer, &[]),
+        Instruction::ForIter(exit) => op(Opcode::ForIter, &[*exit]),
+        Instruction::Assert {
+            text,
+            span,
+            has_message,
+        } => op(
+            Opcode::Assert,
+            &[names.index(text), span.offset(), span.len(), *has_message as usize],
+        ),
+        Instruction::MakeTuple(count) => op(Opcode::MakeTuple, &[*count]),
+        Instruction::UnpackTuple(count) => op(Opcode::UnpackTuple, &[*count]),
+        Instruction::MakeClass(name, count) => op(Opcode::MakeClass, &[names.index(name), *count]),
+        Instruction::GetField(name) => op(Opcode::GetField, &[names.index(name)]),
+        Instruction::SetField(name) => op(Opcode::SetField, &[names.index(name)]),
+    }
+}
+
+impl Bytecode {
+    pub fn encode(program: &Program) -> Self {
+        // Constants still inline in the code join the pool first.
+        let mut constants = program.constants.clone();
+        let instructions: Vec<Instruction> = program
+            .instructions
+            .iter()
+            .map(|instruction| match instruction {
+                Instruction::Push(value) => {
+                    constants.push(value.clone());
+                    Instruction::PushConst(constants.len() as u32 - 1)
+                }
+                other => other.clone(),
+            })
+            .collect();
+
+        // An address takes more bytes as it grows, which can move the code
+        // after it, so lay the code out until the offsets settle. They only
+        // ever grow, starting from the instruction indices.
+        let mut names = Names::default();
+        let mut offsets: Vec<usize> = (0..=instructions.len()).collect();
+        let code = loop {
+            let mut code = Vec::new();
+            let mut laid_out = Vec::with_capacity(offsets.len());
+            for instruction in &instructions {
+                laid_out.push(code.len());
+                let mut instruction = instruction.clone();
+                map_address(&mut instruction, |target| byte_offset(&offsets, target));
+                encode_instruction(&instruction, &mut names, &mut code);
+            }
+            laid_out.push(code.len());
+            if laid_out == offsets {
+                break code;
+            }
+            offsets = laid_out;
+        };
+
+        for constant in &mut constants {
+            if let Value::Function(function) = constant {
+                let mut moved = Function::clone(function);
+                moved.entry = byte_offset(&offsets, moved.entry);
+                *function = Rc::new(moved);
+            }
+        }
+        Bytecode {
+            code,
+            constants,
+            names: names.names,
+            globals: program.globals.clone(),
+        }
+    }
+
+    fn name(&self, index: usize, at: usize) -> Result<String, VMError> {
+        self.names.get(index).cloned().ok_or_else(|| VMError::InvalidBytecode {
+            offset: at,
+            message: format!("name {} out of range", index),
+        })
+    }
+
+    /// Decodes the instruction at byte offset `at`, returning it with the
+    /// offset of the next one.
+    #[inline]
+    pub fn decode(&self, at: usize) -> Result<(Instruction, usize), VMError> {
+        let byte = *self.code.get(at).ok_or_else(|| VMError::InvalidBytecode {
+            offset: at,
+            message: "no instruction here".to_string(),
+        })?;
+        let opcode = Opcode::from_byte(byte).ok_or_else(|| VMError::InvalidBytecode {
+            offset: at,
+            message: format!("unknown opcode {}", byte),
+        })?;
+        let mut next = at + 1;
+        let code = &self.code;
+        let instruction = match opcode {
+            Opcode::PushConst => Instruction::PushConst(read_operand(code, &mut next)?),
+            Opcode::Pop => Instruction::Pop,
+            Opcode::Add => Instruction::Add,
+            Opcode::Sub => Instruction::Sub,
+            Opcode::Mul => Instruction::Mul,
+            Opcode::Div => Instruction::Div,
+            Opcode::Greater => Instruction::Greater,
+            Opcode::Less => Instruction::Less,
+            Opcode::Equal => Instruction::Equal,
+            Opcode::NotEqual => Instruction::NotEqual,
+            Opcode::Jmp => Instruction::Jmp(read_operand(code, &mut next)?),
+            Opcode::Jz => Instruction::Jz(read_operand(code, &mut next)?),
+            Opcode::JmpIfNull => Instruction::JmpIfNull(read_operand(code, &mut next)?),
+            Opcode::JmpIfNotNull => Instruction::JmpIfNotNull(read_operand(code, &mut next)?),
+            Opcode::Label => Instruction::Label(read_operand(code, &mut next)?),
+            Opcode::LoadLocal => Instruction::LoadLocal(read_operand(code, &mut next)?),
+            Opcode::StoreLocal => Instruction::StoreLocal(read_operand(code, &mut next)?),
+            Opcode::LoadGlobal => Instruction::LoadGlobal(read_operand(code, &mut next)?),
+            Opcode::StoreGlobal => Instruction::StoreGlobal(read_operand(code, &mut next)?),
+            Opcode::TeeLocal => Instruction::TeeLocal(read_operand(code, &mut next)?),
+            Opcode::TeeGlobal => Instruction::TeeGlobal(read_operand(code, &mut next)?),
+            Opcode::BeginScope => Instruction::BeginScope,
+            Opcode::EndScope => Instruction::EndScope,
+            Opcode::CreateArray => Instruction::CreateArray,
+            Opcode::ArrayPush => Instruction::ArrayOp(ArrayOperation::Push),
+            Opcode::ArrayPop => Instruction::ArrayOp(ArrayOperation::Pop),
+            Opcode::ArrayGet => Instruction::ArrayOp(ArrayOperation::Get(read_operand(code, &mut next)?)),
+            Opcode::ArraySet => Instruction::ArrayOp(ArrayOperation::Set(read_operand(code, &mut next)?)),
+            Opcode::Call => Instruction::Call(read_operand(code, &mut next)?),
+            Opcode::CallMethod => {
+                let name = read_operand(code, &mut next)?;
+                Instruction::CallMethod(self.name(name, at)?, read_operand(code, &mut next)?)
+            }
+            Opcode::Return => Instruction::Return,
+            Opcode::Yield => Instruction::Yield,
+            Opcode::Defer => Instruction::Defer(read_operand(code, &mut next)?),
+            Opcode::EndDefer => Instruction::EndDefer,
+            Opcode::ForIter => Instruction::ForIter(read_operand(code, &mut next)?),
+            Opcode::Assert => {
+                let text = read_operand(code, &mut next)?;
+                let offset: usize = read_operand(code, &mut next)?;
+                let span = (offset, read_operand(code, &mut next)?).into();
+                let has_message = read_operand::<u64>(code, &mut next)? != 0;
+                Instruction::Assert {
+                    text: self.name(text, at)?,
+                    span,
+                    has_message,
+                }
+            }
+            Opcode::MakeTuple => Instruction::MakeTuple(read_operand(code, &mut next)?),
+            Opcode::UnpackTuple => Instruction::UnpackTuple(read_operand(code, &mut next)?),
+            Opcode::MakeClass => {
+                let name = read_operand(code, &mut next)?;
+                Instruction::MakeClass(self.name(name, at)?, read_operand(code, &mut next)?)
+            }
+            Opcode::GetField => Instruction::GetField(self.name(read_operand(code, &mut next)?, at)?),
+            Opcode::SetField => Instruction::SetField(self.name(read_operand(code, &mut next)?, at)?),
+        };
+        Ok((instruction, next))
+    }
+}
+
+/// Where instruction `target` starts in the encoded code. Addresses past
+/// the end stay past it.
+fn byte_offset(offsets: &[usize], target: usize) -> usize {
+    let end = offsets.len() - 1;
+    match offsets.get(target) {
+        Some(&offset) => offset,
+        None => offsets[end] + (target - end),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+    use crate::vm::{Compiler, VM};
+
+    fn compile(source: &str) -> Program {
+        let nodes = Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .expect("test program parses");
+        Compiler::default()
+            .compile_program(nodes)
+            .expect("test program compiles")
+    }
+
+    /// Bytecode made of `code` alone.
+    fn raw(code: Vec<u8>) -> Bytecode {
+        Bytecode {
+            code,
+            ..Bytecode::default()
+        }
+    }
+
+    fn invalid(result: Result<impl std::fmt::Debug, VMError>) -> (usize, String) {
+        match result {
+            Err(VMError::InvalidBytecode { offset, message }) => (offset, message),
+            other => panic!("expected invalid bytecode, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn varints_roundtrip() {
+        for value in [0, 1, 127, 128, 300, u64::from(u32::MAX), u64::MAX] {
+            let mut code = Vec::new();
+            write_varint(&mut code, value);
+            let mut at = 0;
+            assert_eq!(read_varint(&code, &mut at).unwrap(), value);
+            assert_eq!(at, code.len());
+        }
+    }
+
+    #[test]
+    fn decoding_gives_back_the_encoded_instructions() {
+        let program = compile(
+            "fn f(a) { b = [a, 2]; return b[0] }\nclass C { fn m(self) { return self.x } }\nx = f(1)\nassert x == 1, \"ok\"",
+        );
+        let bytecode = Bytecode::encode(&program);
+        let mut decoded = Vec::new();
+        let mut at = 0;
+        while at < bytecode.code.len() {
+            let (instruction, next) = bytecode.decode(at).unwrap();
+            decoded.push(instruction);
+            at = next;
+        }
+        assert_eq!(decoded.len(), program.instructions.len());
+        // Jump targets become byte offsets, so compare the jump-free ones.
+        for (decoded, original) in decoded.iter().zip(&program.instructions) {
+            if crate::vm::address(original).is_none() {
+                assert_eq!(format!("{:?}", decoded), format!("{:?}", original));
+            }
+        }
+    }
+
+    #[test]
+    fn operands_too_large_for_their_field_are_rejected() {
+        let mut code = vec![Opcode::LoadLocal as u8];
+        write_varint(&mut code, u64::from(u16::MAX) + 1);
+        assert_eq!(
+            invalid(raw(code.clone()).decode(0)),
+            (1, "operand 65536 is out of range".to_string())
+        );
+        // The run loop decodes LoadLocal itself and must agree.
+        assert_eq!(
+            invalid(VM::new().execute_bytecode(&raw(code))),
+            (1, "operand 65536 is out of range".to_string())
+        );
+
+        let mut code = vec![Opcode::PushConst as u8];
+        write_varint(&mut code, u64::from(u32::MAX) + 1);
+        assert_eq!(
+            invalid(raw(code).decode(0)),
+            (1, "operand 4294967296 is out of range".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_code_is_rejected() {
+        assert_eq!(invalid(raw(vec![200]).decode(0)), (0, "unknown opcode 200".to_string()));
+        assert_eq!(
+            invalid(raw(vec![Opcode::Jmp as u8, 0x80]).decode(0)),
+            (2, "operand runs past the end of the code".to_string())
+        );
+        assert_eq!(
+            invalid(raw(vec![Opcode::GetField as u8, 3]).decode(0)),
+            (0, "name 3 out of range".to_string())
+        );
+    }
+}
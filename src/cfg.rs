@@ -0,0 +1,359 @@
+use crate::types::Value;
+use crate::vm::{Instruction, Program};
+use std::fmt::Write;
+
+/// How control gets from one block to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// An unconditional jump.
+    Jump,
+    /// A conditional jump that is taken.
+    Branch,
+    /// Running on into the next instruction, including after a call or a
+    /// `yield` is resumed.
+    Fallthrough,
+}
+
+/// Where an edge leads: a block, or off the end of the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Block(usize),
+    Exit,
+}
+
+/// A straight run of instructions, `start..end`, that is only entered at
+/// its first instruction and only left after its last.
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<(Target, EdgeKind)>,
+}
+
+/// The control-flow graph of a compiled program. Each function body and
+/// deferred action is laid out inline but only entered through its own
+/// entry, so they are roots of the graph alongside the program start.
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    /// The blocks control can start in, with what starts there.
+    pub entries: Vec<(usize, String)>,
+}
+
+/// The target of a jump that stays within the current function.
+fn jump_target(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Jmp(target)
+        | Instruction::Jz(target)
+        | Instruction::JmpIfNull(target)
+        | Instruction::JmpIfNotNull(target)
+        | Instruction::ForIter(target) => Some(*target),
+        _ => None,
+    }
+}
+
+/// Code that starts running without a jump to it: a function entry point
+/// or a deferred action.
+fn entry(program: &Program, instruction: &Instruction) -> Option<(usize, String)> {
+    let function = match instruction {
+        Instruction::Defer(action) => return Some((*action, "defer".to_string())),
+        Instruction::Push(Value::Function(function)) => function,
+        Instruction::PushConst(index) => match program.constants.get(*index as usize) {
+            Some(Value::Function(function)) => function,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some((function.entry, format!("fn {}", function.name)))
+}
+
+/// Whether control may leave a block after `instruction` other than by
+/// running on into the next one.
+fn ends_block(instruction: &Instruction) -> bool {
+    jump_target(instruction).is_some()
+        || matches!(
+            instruction,
+            Instruction::Return | Instruction::Yield | Instruction::EndDefer
+        )
+}
+
+impl Cfg {
+    pub fn build(program: &Program) -> Self {
+        let code = &program.instructions;
+        let len = code.len();
+        let mut is_leader = vec![false; len + 1];
+        let mut entries = vec![(0, "main".to_string())];
+        is_leader[0] = true;
+        for (ip, instruction) in code.iter().enumerate() {
+            if let Some(target) = jump_target(instruction) {
+                is_leader[target.min(len)] = true;
+            }
+            if let Some((start, name)) = entry(program, instruction) {
+                is_leader[start.min(len)] = true;
+                entries.push((start, name));
+            }
+            if ends_block(instruction) {
+                is_leader[ip + 1] = true;
+            }
+        }
+
+        // Where each instruction's block is, to resolve targets.
+        let mut block_of = vec![0; len];
+        let mut starts = Vec::new();
+        for ip in 0..len {
+            if is_leader[ip] {
+                starts.push(ip);
+            }
+            block_of[ip] = starts.len().saturating_sub(1);
+        }
+        let target = |address: usize| match block_of.get(address) {
+            Some(&block) => Target::Block(block),
+            None => Target::Exit,
+        };
+
+        let blocks = starts
+            .iter()
+            .enumerate()
+            .map(|(index, &start)| {
+                let end = starts.get(index + 1).copied().unwrap_or(len);
+                let last = end - 1;
+                let successors = match &code[last] {
+                    Instruction::Jmp(to) => vec![(target(*to), EdgeKind::Jump)],
+                    Instruction::Return | Instruction::EndDefer => Vec::new(),
+                    instruction => {
+                        let mut successors = vec![(target(end), EdgeKind::Fallthrough)];
+                        if let Some(to) = jump_target(instruction) {
+                            successors.push((target(to), EdgeKind::Branch));
+                        }
+                        successors
+                    }
+                };
+                BasicBlock {
+                    start,
+                    end,
+                    successors,
+                }
+            })
+            .collect();
+
+        let entries = entries
+            .into_iter()
+            .filter(|(start, _)| *start < len)
+            .map(|(start, name)| (block_of[start], name))
+            .collect();
+        Cfg { blocks, entries }
+    }
+
+    /// Which blocks can run at all, starting from any entry.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut pending: Vec<usize> = self.entries.iter().map(|(block, _)| *block).collect();
+        while let Some(block) = pending.pop() {
+            if std::mem::replace(&mut reachable[block], true) {
+                continue;
+            }
+            for (target, _) in &self.blocks[block].successors {
+                if let Target::Block(next) = target {
+                    pending.push(*next);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// The edges `(from, to)` that close a loop: those leading back to a
+    /// block still being explored by a depth-first walk from the entries.
+    pub fn back_edges(&self) -> Vec<(usize, usize)> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum State {
+            New,
+            Active,
+            Done,
+        }
+        let mut state = vec![State::New; self.blocks.len()];
+        let mut back_edges = Vec::new();
+        for &(entry, _) in &self.entries {
+            if state[entry] != State::New {
+                continue;
+            }
+            // Each block on the path with the index of its next successor.
+            let mut path = vec![(entry, 0)];
+            state[entry] = State::Active;
+            while let Some((block, next)) = path.last_mut() {
+                let block = *block;
+                let Some((target, _)) = self.blocks[block].successors.get(*next) else {
+                    state[block] = State::Done;
+                    path.pop();
+                    continue;
+                };
+                *next += 1;
+                let Target::Block(to) = *target else {
+                    continue;
+                };
+                match state[to] {
+                    State::New => {
+                        state[to] = State::Active;
+                        path.push((to, 0));
+                    }
+                    State::Active => back_edges.push((block, to)),
+                    State::Done => {}
+                }
+            }
+        }
+        back_edges
+    }
+
+    /// Renders the graph in Graphviz DOT. Unreachable blocks are dashed and
+    /// loop back edges are drawn in blue.
+    pub fn to_dot(&self, program: &Program) -> String {
+        let reachable = self.reachable();
+        let back_edges = self.back_edges();
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for (index, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for (_, name) in self.entries.iter().filter(|(entry, _)| *entry == index) {
+                let _ = write!(label, "{}:\\l", escape(name));
+            }
+            for ip in block.start..block.end {
+                let instruction = &program.instructions[ip];
+                let text = match instruction {
+                    Instruction::PushConst(constant) => match program.constants.get(*constant as usize) {
+                        Some(value) => format!("{:?} = {:?}", instruction, value),
+                        None => format!("{:?}", instruction),
+                    },
+                    _ => format!("{:?}", instruction),
+                };
+                let _ = write!(label, "{}: {}\\l", ip, escape(&text));
+            }
+            let style = if reachable[index] { "" } else { ", style=dashed, color=gray" };
+            let _ = writeln!(dot, "    b{} [label=\"{}\"{}];", index, label, style);
+        }
+        let mut has_exit = false;
+        for (index, block) in self.blocks.iter().enumerate() {
+            for (target, kind) in &block.successors {
+                let to = match target {
+                    Target::Block(to) => format!("b{}", to),
+                    Target::Exit => {
+                        has_exit = true;
+                        "exit".to_string()
+                    }
+                };
+                let mut attributes = match kind {
+                    EdgeKind::Branch => vec!["label=\"taken\""],
+                    EdgeKind::Jump | EdgeKind::Fallthrough => vec![],
+                };
+                if let Target::Block(to) = target {
+                    if back_edges.contains(&(index, *to)) {
+                        attributes.push("color=blue");
+                    }
+                }
+                if attributes.is_empty() {
+                    let _ = writeln!(dot, "    b{} -> {};", index, to);
+                } else {
+                    let _ = writeln!(dot, "    b{} -> {} [{}];", index, to, attributes.join(", "));
+                }
+            }
+        }
+        if has_exit {
+            dot.push_str("    exit [shape=oval];\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Escapes text for a double-quoted DOT string.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+    use crate::vm::Compiler;
+
+    fn compile(source: &str) -> Program {
+        let nodes = Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .expect("test program parses");
+        Compiler::default()
+            .compile_program(nodes)
+            .expect("test program compiles")
+    }
+
+    fn successors(cfg: &Cfg) -> Vec<Vec<(Target, EdgeKind)>> {
+        cfg.blocks.iter().map(|block| block.successors.clone()).collect()
+    }
+
+    #[test]
+    fn splits_at_jumps_and_their_targets() {
+        let program = Program::new(
+            vec![
+                Instruction::Push(Value::Number(1)),
+                Instruction::Jz(4),
+                Instruction::Push(Value::Number(2)),
+                Instruction::Jmp(5),
+                Instruction::Push(Value::Number(3)),
+                Instruction::Pop,
+            ],
+            Vec::new(),
+        );
+        let cfg = Cfg::build(&program);
+        let ranges: Vec<_> = cfg.blocks.iter().map(|block| (block.start, block.end)).collect();
+        assert_eq!(ranges, [(0, 2), (2, 4), (4, 5), (5, 6)]);
+        assert_eq!(
+            successors(&cfg),
+            [
+                vec![(Target::Block(1), EdgeKind::Fallthrough), (Target::Block(2), EdgeKind::Branch)],
+                vec![(Target::Block(3), EdgeKind::Jump)],
+                vec![(Target::Block(3), EdgeKind::Fallthrough)],
+                vec![(Target::Exit, EdgeKind::Fallthrough)],
+            ]
+        );
+        assert!(cfg.back_edges().is_empty());
+    }
+
+    #[test]
+    fn code_jumped_over_is_unreachable() {
+        let program = Program::new(
+            vec![
+                Instruction::Jmp(2),
+                Instruction::Pop,
+                Instruction::Push(Value::Null),
+            ],
+            Vec::new(),
+        );
+        let cfg = Cfg::build(&program);
+        assert_eq!(cfg.reachable(), [true, false, true]);
+        assert!(cfg.to_dot(&program).contains("b1 [label=\"1: Pop\\l\", style=dashed, color=gray];"));
+    }
+
+    #[test]
+    fn loops_have_a_back_edge() {
+        let program = compile("i = 0\nwhile (i < 3) { i = i + 1 }");
+        let cfg = Cfg::build(&program);
+        let back_edges = cfg.back_edges();
+        assert_eq!(back_edges.len(), 1);
+        let (from, to) = back_edges[0];
+        assert!(matches!(program.instructions[cfg.blocks[from].end - 1], Instruction::Jmp(_)));
+        assert!(cfg.blocks[to].start < cfg.blocks[from].start);
+        assert!(cfg.to_dot(&program).contains(&format!("b{} -> b{} [color=blue];", from, to)));
+    }
+
+    #[test]
+    fn functions_and_deferred_actions_are_entries() {
+        let program = compile("fn f() { defer g(); return 1 }\nx = f()");
+        let cfg = Cfg::build(&program);
+        let names: Vec<&str> = cfg.entries.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(names, ["main", "defer", "fn f"]);
+        let (action, _) = cfg.entries[1];
+        let block = &cfg.blocks[action];
+        assert!(matches!(program.instructions[block.end - 1], Instruction::EndDefer));
+        assert!(block.successors.is_empty());
+        // The implicit `return null` after `return 1` can never run.
+        let reachable = cfg.reachable();
+        assert_eq!(reachable.iter().filter(|&&reachable| !reachable).count(), 1);
+    }
+}
@@ -1,13 +1,50 @@
-use miette::{Diagnostic, SourceSpan};
+use miette::{Diagnostic, MietteError, MietteSpanContents, SourceCode, SourceSpan, SpanContents};
 use thiserror::Error;
 
+/// Program source attached to a diagnostic. Unlike a plain `String`, it
+/// reports columns in characters rather than bytes.
+#[derive(Debug, Clone)]
+pub struct SourceText(String);
+
+impl From<String> for SourceText {
+    fn from(src: String) -> Self {
+        SourceText(src)
+    }
+}
+
+impl SourceCode for SourceText {
+    fn read_span<'a>(
+        &'a self,
+        span: &SourceSpan,
+        context_lines_before: usize,
+        context_lines_after: usize,
+    ) -> Result<Box<dyn SpanContents<'a> + 'a>, MietteError> {
+        let contents = self.0.read_span(span, context_lines_before, context_lines_after)?;
+        // miette only reports a column when no leading context is requested.
+        let column = if context_lines_before == 0 {
+            let prefix = self.0.get(..span.offset()).ok_or(MietteError::OutOfBounds)?;
+            let line_start = prefix.rfind(['\n', '\r']).map_or(0, |i| i + 1);
+            prefix[line_start..].chars().count()
+        } else {
+            contents.column()
+        };
+        Ok(Box::new(MietteSpanContents::new(
+            contents.data(),
+            *contents.span(),
+            contents.line(),
+            column,
+            contents.line_count(),
+        )))
+    }
+}
+
 #[derive(Error, Debug, Diagnostic)]
 pub enum VMError {
     #[error("Parse error: {message}")]
     #[diagnostic(code(vm::parse_error))]
     ParseError {
         #[source_code]
-        src: String,
+        src: SourceText,
         message: String,
         #[label("here")]
         span: SourceSpan,
@@ -17,12 +54,86 @@ pub enum VMError {
     #[diagnostic(code(vm::tokenization_error))]
     TokenizationError {
         #[source_code]
-        src: String,
+        src: SourceText,
+        message: String,
+        #[label("here")]
+        span: SourceSpan,
+    },
+
+    #[error("Type error: {message}")]
+    #[diagnostic(code(vm::type_check_error))]
+    TypeCheckError {
+        #[source_code]
+        src: SourceText,
+        message: String,
+        #[label("here")]
+        span: SourceSpan,
+    },
+
+    #[error("Warning: {message}")]
+    #[diagnostic(code(vm::type_check_warning), severity(Warning))]
+    TypeCheckWarning {
+        #[source_code]
+        src: SourceText,
         message: String,
         #[label("here")]
         span: SourceSpan,
     },
 
+    #[error("Type check failed with {} errors", errors.len())]
+    #[diagnostic(code(vm::type_check_failed))]
+    TypeCheckFailed {
+        #[related]
+        errors: Vec<VMError>,
+    },
+
+    #[error("Assertion failed: {expression}")]
+    #[diagnostic(code(vm::assertion_failed))]
+    AssertionFailed {
+        expression: String,
+        #[help]
+        message: Option<String>,
+        #[label("this is false")]
+        span: SourceSpan,
+    },
+
+    #[error("Compile-time evaluation failed: {message}")]
+    #[diagnostic(code(vm::comptime_error))]
+    ComptimeError {
+        message: String,
+        #[label("evaluated here")]
+        span: SourceSpan,
+    },
+
+    #[error("Compile error: {message}")]
+    #[diagnostic(code(vm::compile_error))]
+    CompileError {
+        message: String,
+        #[label("here")]
+        span: SourceSpan,
+    },
+
+    /// A jump to a label the compiler never placed, which is a bug in the
+    /// compiler rather than in the program.
+    #[error("Compile error: jump to undefined label {label}")]
+    #[diagnostic(code(vm::undefined_label))]
+    UndefinedLabel {
+        label: usize,
+    },
+
+    #[error("The register engine does not support {feature}")]
+    #[diagnostic(code(vm::unsupported))]
+    Unsupported {
+        feature: &'static str,
+    },
+
+    #[error("Compilation failed with {} errors", errors.len())]
+    #[diagnostic(code(vm::compile_failed))]
+    CompileFailed {
+        #[related]
+        errors: Vec<VMError>,
+    },
+
     #[error("Execution error: {message}")]
     #[diagnostic(code(vm::execution_error))]
     ExecutionError {
@@ -50,18 +161,68 @@ pub enum VMError {
         name: String,
     },
 
+    #[error("{type_name} has no member {name}")]
+    UndefinedMember {
+        type_name: String,
+        name: String,
+    },
+
     #[error("Stack underflow")]
     StackUnderflow,
 
     #[error("Division by zero")]
     DivisionByZero,
 
+    #[error("Integer overflow")]
+    IntegerOverflow,
+
     #[error("No scope to end")]
     NoScopeToEnd,
 
     #[error("Stack overflow")]
     StackOverflow,
 
+    #[error("Ran out of fuel")]
+    OutOfFuel,
+
+    #[error("Function {name} expects {expected} arguments, got {got}")]
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        got: usize,
+    },
+
+    #[error("Invalid bytecode at byte {offset}: {message}")]
+    InvalidBytecode {
+        offset: usize,
+        message: String,
+    },
+
+    #[error("Not a compiled mollusk file")]
+    #[diagnostic(code(vm::not_compiled))]
+    NotCompiled,
+
+    #[error("Compiled file has format version {found}, but this build reads version {expected}")]
+    #[diagnostic(code(vm::version_mismatch), help("recompile the script with `mollusk compile`"))]
+    VersionMismatch {
+        found: u16,
+        expected: u16,
+    },
+
+    #[error("Compiled file is corrupt: checksum {expected:08x} does not match contents ({actual:08x})")]
+    #[diagnostic(code(vm::checksum_mismatch))]
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+
+    #[error("Code rejected at {address}: {message}")]
+    #[diagnostic(code(vm::verify_error))]
+    VerifyError {
+        address: usize,
+        message: String,
+    },
+
     #[error("Invalid jump destination: {target} (max: {max})")]
     InvalidJump {
         target: usize,
@@ -72,15 +233,23 @@ pub enum VMError {
 impl VMError {
     pub fn tokenization_error(src: String, message: String, pos: usize, len: usize) -> Self {
         VMError::TokenizationError {
-            src,
+            src: src.into(),
             message,
             span: (pos, len).into(),
         }
     }
 
+    pub fn type_check_error(src: String, message: String, span: SourceSpan) -> Self {
+        VMError::TypeCheckError {
+            src: src.into(),
+            message,
+            span,
+        }
+    }
+
     pub fn parse_error(src: String, message: String, pos: usize, len: usize) -> Self {
         VMError::ParseError {
-            src,
+            src: src.into(),
             message,
             span: (pos, len).into(),
         }
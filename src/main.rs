@@ -1,40 +1,379 @@
 mod ast;
+mod bytecode;
+mod cfg;
 mod error;
+mod mlkc;
+mod optimize;
 mod parser;
+mod peephole;
+mod register;
 mod tokenizer;
+mod typecheck;
 mod types;
+mod verify;
 mod vm;
 
+use crate::bytecode::Bytecode;
+use crate::cfg::Cfg;
+use crate::error::{SourceText, VMError};
+use crate::mlkc::DebugInfo;
+use crate::optimize::Optimizer;
 use crate::parser::Parser;
+use crate::register::{RegisterCompiler, RegisterVM};
 use crate::tokenizer::Tokenizer;
-use crate::vm::{run_instructions, VM};
+use crate::typecheck::TypeChecker;
+use crate::vm::{Compiler, Instruction, VM};
+use miette::{miette, IntoDiagnostic, WrapErr};
 
-fn main() -> miette::Result<()> {
-    let program = r#"
+const DEMO_PROGRAM: &str = r#"
     x = [1,2,3]
      x[0] = 10
     y = x[0] + 5
     (y + 5)
-    "#
-    .to_string();
+    "#;
+
+const USAGE: &str = "Usage: mollusk [run|check] [--no-assert] [--no-peephole] [--engine=stack|bytecode|register] [--emit=cfg-dot] [file]
+       mollusk compile [--no-assert] [--no-peephole] [--strip] -o <out.mlkc> [file]";
+
+enum Command {
+    Run,
+    Check,
+    /// Compile to a `.mlkc` file.
+    Compile,
+}
+
+/// A program as read from its file.
+enum Input {
+    Source(String),
+    /// A `.mlkc` file.
+    Compiled(Vec<u8>),
+}
+
+/// What to print instead of running the program.
+#[derive(Clone, Copy)]
+enum Emit {
+    /// The control-flow graph, in Graphviz DOT.
+    CfgDot,
+}
+
+/// Which execution engine runs the program.
+#[derive(Clone, Copy)]
+enum Engine {
+    /// The stack machine, which runs the whole language.
+    Stack,
+    /// The stack machine, decoding compact bytecode as it runs.
+    Bytecode,
+    /// The register machine, which runs a core of the language faster.
+    Register,
+}
+
+/// A compiler for the chosen engine.
+enum Backend {
+    Stack(Compiler),
+    Bytecode(Compiler),
+    Register(RegisterCompiler),
+}
+
+struct Cli {
+    command: Command,
+    path: Option<String>,
+    /// Compile `assert` statements out.
+    no_assert: bool,
+    /// Skip the peephole pass, e.g. to read the unoptimised instructions.
+    no_peephole: bool,
+    engine: Engine,
+    emit: Option<Emit>,
+    /// Where `compile` writes to.
+    output: Option<String>,
+    /// Leave the source out of compiled files.
+    strip: bool,
+}
+
+impl Cli {
+    fn parse(args: impl Iterator<Item = String>) -> miette::Result<Self> {
+        let mut cli = Cli {
+            command: Command::Run,
+            path: None,
+            no_assert: false,
+            no_peephole: false,
+            engine: Engine::Stack,
+            emit: None,
+            output: None,
+            strip: false,
+        };
+        let mut args = args.peekable();
+        match args.peek().map(String::as_str) {
+            Some("run") => {
+                args.next();
+            }
+            Some("check") => {
+                args.next();
+                cli.command = Command::Check;
+            }
+            Some("compile") => {
+                args.next();
+                cli.command = Command::Compile;
+            }
+            _ => {}
+        }
+        while let Some(arg) = args.next() {
+            if arg == "--no-assert" {
+                cli.no_assert = true;
+                continue;
+            }
+            if arg == "--no-peephole" {
+                cli.no_peephole = true;
+                continue;
+            }
+            if arg == "--strip" {
+                cli.strip = true;
+                continue;
+            }
+            if arg == "-o" {
+                let output = args.next().ok_or_else(|| miette!("-o needs a file name\n{}", USAGE))?;
+                cli.output = Some(output);
+                continue;
+            }
+            if let Some(engine) = arg.strip_prefix("--engine=") {
+                cli.engine = match engine {
+                    "stack" => Engine::Stack,
+                    "bytecode" => Engine::Bytecode,
+                    "register" => Engine::Register,
+                    _ => return Err(miette!("Unknown --engine: {}\n{}", engine, USAGE)),
+                };
+                continue;
+            }
+            if let Some(kind) = arg.strip_prefix("--emit=") {
+                cli.emit = match kind {
+                    "cfg-dot" => Some(Emit::CfgDot),
+                    _ => return Err(miette!("Unknown --emit kind: {}\n{}", kind, USAGE)),
+                };
+                continue;
+            }
+            if arg.starts_with('-') || cli.path.is_some() {
+                return Err(miette!("Unexpected argument: {}\n{}", arg, USAGE));
+            }
+            cli.path = Some(arg);
+        }
+        if let (Engine::Register, Some(_)) = (cli.engine, cli.emit) {
+            return Err(miette!("--emit only works with the stack engine\n{}", USAGE));
+        }
+        match cli.command {
+            Command::Compile if cli.output.is_none() => {
+                return Err(miette!("compile needs an output file, given with -o\n{}", USAGE))
+            }
+            Command::Run | Command::Check if cli.output.is_some() || cli.strip => {
+                return Err(miette!("-o and --strip only work with compile\n{}", USAGE))
+            }
+            _ => {}
+        }
+        Ok(cli)
+    }
+
+    fn input(&self) -> miette::Result<Input> {
+        let Some(path) = &self.path else {
+            return Ok(Input::Source(DEMO_PROGRAM.to_string()));
+        };
+        let bytes = std::fs::read(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {}", path))?;
+        if mlkc::is_compiled(&bytes) {
+            return Ok(Input::Compiled(bytes));
+        }
+        String::from_utf8(bytes)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {}", path))
+            .map(Input::Source)
+    }
 
-    let tokenizer = Tokenizer::new(program);
-    let mut parser = Parser::new(tokenizer);
-    let ast_nodes = parser.parse_program();
-    println!("AST: {:?}\n", ast_nodes);
+    fn source(&self) -> miette::Result<String> {
+        match self.input()? {
+            Input::Source(source) => Ok(source),
+            Input::Compiled(_) => {
+                let path = self.path.as_deref().unwrap_or_default();
+                Err(miette!("{} is already compiled", path))
+            }
+        }
+    }
+
+    fn compiler(&self) -> Compiler {
+        Compiler::default()
+            .with_asserts(!self.no_assert)
+            .with_peephole(!self.no_peephole)
+    }
+}
+
+fn main() -> miette::Result<()> {
+    let cli = Cli::parse(std::env::args().skip(1))?;
+    match cli.command {
+        Command::Run => {
+            let program = match cli.input()? {
+                Input::Source(program) => program,
+                Input::Compiled(bytes) => {
+                    if let (Engine::Register, _) | (_, Some(_)) = (cli.engine, cli.emit) {
+                        return Err(miette!("Compiled files only run on the stack engine, without --emit"));
+                    }
+                    return run_compiled(&bytes);
+                }
+            };
+            let backend = match cli.engine {
+                Engine::Stack => Backend::Stack(cli.compiler()),
+                Engine::Bytecode => Backend::Bytecode(cli.compiler()),
+                Engine::Register => Backend::Register(RegisterCompiler::default().with_asserts(!cli.no_assert)),
+            };
+            run(program, backend, cli.emit)
+        }
+        Command::Check => check(cli.source()?),
+        Command::Compile => {
+            let output = cli.output.as_deref().unwrap_or_default();
+            let source_name = cli.path.as_deref().unwrap_or("<demo>");
+            compile(cli.source()?, cli.compiler(), source_name, output, cli.strip)
+        }
+    }
+}
+
+fn run(program: String, backend: Backend, emit: Option<Emit>) -> miette::Result<()> {
+    let tokenizer = Tokenizer::new(program.clone());
+    let ast_nodes = Parser::new(tokenizer).and_then(|mut parser| parser.parse_program());
+    if emit.is_none() {
+        println!("AST: {:?}\n", ast_nodes);
+    }
     if let Ok(nodes) = ast_nodes {
-        let instructions = run_instructions(nodes);
-        println!("Instructions: {:?}\n", instructions);
+        TypeChecker::new(&program).check_program(&nodes)?;
+        let with_source = |e: VMError| miette::Report::new(e).with_source_code(SourceText::from(program.clone()));
+        let nodes = Optimizer::default().optimize_program(nodes).map_err(with_source)?;
+        let (mut compiler, bytecode) = match backend {
+            Backend::Stack(compiler) => (compiler, false),
+            Backend::Bytecode(compiler) => (compiler, true),
+            Backend::Register(mut compiler) => {
+                let compiled = compiler.compile_program(nodes).map_err(with_source)?;
+                println!("Instructions: {:?}", compiled.instructions);
+                println!("Constants: {:?}\n", compiled.constants);
+                let mut vm = RegisterVM::new();
+                return report(vm.execute(&compiled), Some(&program), || {
+                    println!("VM stack: {:?}", vm.results);
+                    println!("VM globals: {:?}", vm.variables(&compiled));
+                });
+            }
+        };
+        let compiled = compiler.compile_program(nodes).map_err(with_source)?;
+        if let Some(Emit::CfgDot) = emit {
+            print!("{}", Cfg::build(&compiled).to_dot(&compiled));
+            return Ok(());
+        }
+        println!("Instructions: {:?}", compiled.instructions);
+        println!("Constants: {:?}\n", compiled.constants);
         let mut vm = VM::new();
-        match vm.execute(&instructions) {
-            Ok(_) => {
+        if bytecode {
+            let encoded = Bytecode::encode(&compiled);
+            println!(
+                "Bytecode: {} bytes for {} instructions ({} bytes as `Instruction`s)\n",
+                encoded.code.len(),
+                compiled.instructions.len(),
+                compiled.instructions.len() * std::mem::size_of::<Instruction>()
+            );
+            return report(vm.execute_bytecode(&encoded), Some(&program), || {
                 println!("VM stack: {:?}", vm.stack);
-                println!("VM env: {:?}", vm.env_stack);
-            }
-            Err(e) => println!("Runtime error: {}", e),
+                println!("VM globals: {:?}", vm.global_values(&compiled));
+            });
         }
+        return report(vm.execute(&compiled), Some(&program), || {
+            println!("VM stack: {:?}", vm.stack);
+            println!("VM globals: {:?}", vm.global_values(&compiled));
+        });
     } else if let Err(err) = ast_nodes {
         return Err(err.into());
     }
     Ok(())
 }
+
+/// Compiles the program and writes it to `output` as a `.mlkc` file,
+/// along with its source unless `strip` is set.
+fn compile(
+    program: String,
+    mut compiler: Compiler,
+    source_name: &str,
+    output: &str,
+    strip: bool,
+) -> miette::Result<()> {
+    let nodes = Parser::new(Tokenizer::new(program.clone()))
+        .and_then(|mut parser| parser.parse_program())?;
+    TypeChecker::new(&program).check_program(&nodes)?;
+    let with_source = |e: VMError| miette::Report::new(e).with_source_code(SourceText::from(program.clone()));
+    let nodes = Optimizer::default().optimize_program(nodes).map_err(with_source)?;
+    let compiled = compiler.compile_program(nodes).map_err(with_source)?;
+    verify::verify(&compiled)?;
+    let debug = (!strip).then(|| DebugInfo {
+        source_name: source_name.to_string(),
+        source: program.clone(),
+    });
+    let bytes = mlkc::write(&Bytecode::encode(&compiled), debug.as_ref())?;
+    std::fs::write(output, &bytes)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", output))?;
+    println!("Wrote {} ({} bytes)", output, bytes.len());
+    Ok(())
+}
+
+/// Runs a `.mlkc` file on the stack engine.
+fn run_compiled(bytes: &[u8]) -> miette::Result<()> {
+    let file = mlkc::read(bytes)?;
+    let bytecode = &file.bytecode;
+    println!("Constants: {:?}\n", bytecode.constants);
+    let mut vm = VM::new();
+    let source = file.debug.as_ref().map(|debug| debug.source.as_str());
+    report(vm.execute_bytecode(bytecode), source, || {
+        println!("VM stack: {:?}", vm.stack);
+        println!("VM globals: {:?}", vm.named_globals(&bytecode.globals));
+    })
+}
+
+/// Prints the final state of a program that ran to completion, or turns
+/// its runtime error into the command's error so that it exits with a
+/// failure status. Errors that carry a span, like failed assertions, point
+/// into `source` when it is known.
+fn report(outcome: Result<(), VMError>, source: Option<&str>, state: impl FnOnce()) -> miette::Result<()> {
+    if let Err(e) = outcome {
+        let report = miette::Report::new(e);
+        return Err(match source {
+            Some(source) => report.with_source_code(SourceText::from(source.to_string())),
+            None => report,
+        });
+    }
+    state();
+    Ok(())
+}
+
+/// Infers types for the whole program and reports problems without running it.
+fn check(program: String) -> miette::Result<()> {
+    let nodes = Parser::new(Tokenizer::new(program.clone()))
+        .and_then(|mut parser| parser.parse_program())?;
+    let mut checker = TypeChecker::inferring(&program);
+    let result = checker.check_program(&nodes);
+    for warning in checker.take_warnings() {
+        eprintln!("{:?}", miette::Report::new(warning));
+    }
+    result?;
+    println!("No type errors found");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_runtime_error_fails_the_command() {
+        let mut printed = false;
+        assert!(report(Err(VMError::StackUnderflow), None, || printed = true).is_err());
+        let failed = VMError::AssertionFailed {
+            expression: "x == 1".to_string(),
+            message: None,
+            span: (7, 6).into(),
+        };
+        assert!(report(Err(failed), Some("assert x == 1"), || printed = true).is_err());
+        assert!(!printed);
+        assert!(report(Ok(()), None, || printed = true).is_ok());
+        assert!(printed);
+    }
+}
@@ -0,0 +1,519 @@
+use crate::bytecode::{read_varint, write_varint, Bytecode};
+use crate::error::VMError;
+use crate::types::{Function, Value};
+use crate::verify::verify_bytecode;
+use std::rc::Rc;
+
+/// The first bytes of every `.mlkc` file.
+pub const MAGIC: &[u8; 4] = b"MLKC";
+
+/// The version of the layout below. Files of any other version are
+/// rejected rather than guessed at.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// The magic, the version and the checksum.
+const HEADER_LEN: usize = 10;
+
+// A file is the header followed by sections, each a tag, a varint length
+// and that many bytes. The sections come in this order, and only the
+// debug section may be left out:
+//
+//   constants  a varint count, then that many values
+//   names      a varint count, then that many strings
+//   globals    a varint count, then that many strings
+//   code       the encoded instructions
+//   debug      the name and text of the source the code was compiled from
+//
+// The header's checksum is the CRC-32 of everything after the header.
+const CONSTANTS: u8 = 1;
+const NAMES: u8 = 2;
+const GLOBALS: u8 = 3;
+const CODE: u8 = 4;
+const DEBUG: u8 = 5;
+
+// Value tags in the constant pool.
+const NULL: u8 = 0;
+const NUMBER: u8 = 1;
+const BOOLEAN: u8 = 2;
+const STRING: u8 = 3;
+const CHAR: u8 = 4;
+const BYTES: u8 = 5;
+const ARRAY: u8 = 6;
+const TUPLE: u8 = 7;
+const FUNCTION: u8 = 8;
+
+/// How deeply arrays and tuples may nest in the constant pool. Reading a
+/// constant recurses once per level, so deeper files are rejected rather
+/// than allowed to overflow the stack.
+pub const MAX_NESTING: usize = 64;
+
+/// What a compiled file keeps of its source, to report errors against.
+#[derive(Debug, Clone)]
+pub struct DebugInfo {
+    pub source_name: String,
+    pub source: String,
+}
+
+/// The contents of a `.mlkc` file.
+#[derive(Debug, Clone)]
+pub struct CompiledFile {
+    pub bytecode: Bytecode,
+    pub debug: Option<DebugInfo>,
+}
+
+/// CRC-32 (IEEE), as used by zip and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Whether `bytes` start like a compiled file, rather than source text.
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) -> Result<(), VMError> {
+    match value {
+        Value::Null => out.push(NULL),
+        Value::Number(n) => {
+            out.push(NUMBER);
+            // Zigzag, so that small negative numbers stay short.
+            write_varint(out, u64::from(((n << 1) ^ (n >> 31)) as u32));
+        }
+        Value::Boolean(b) => out.extend([BOOLEAN, *b as u8]),
+        Value::String(s) => {
+            out.push(STRING);
+            write_bytes(out, s.as_bytes());
+        }
+        Value::Char(c) => {
+            out.push(CHAR);
+            write_varint(out, u64::from(*c as u32));
+        }
+        Value::Bytes(bytes) => {
+            out.push(BYTES);
+            write_bytes(out, bytes);
+        }
+        Value::Array(items) => {
+            out.push(ARRAY);
+            write_values(out, items)?;
+        }
+        Value::Tuple(items) => {
+            out.push(TUPLE);
+            write_values(out, items)?;
+        }
+        Value::Function(function) => {
+            out.push(FUNCTION);
+            write_bytes(out, function.name.as_bytes());
+            write_varint(out, function.params.len() as u64);
+            for param in &function.params {
+                write_bytes(out, param.as_bytes());
+            }
+            write_varint(out, function.entry as u64);
+            out.push(function.is_generator as u8);
+        }
+        Value::Generator(_) | Value::Class(_) | Value::Instance(_) => {
+            return Err(VMError::TypeError {
+                message: format!("Cannot save a {} constant", value.type_name()),
+            })
+        }
+    }
+    Ok(())
+}
+
+fn write_values(out: &mut Vec<u8>, values: &[Value]) -> Result<(), VMError> {
+    write_varint(out, values.len() as u64);
+    values.iter().try_for_each(|value| write_value(out, value))
+}
+
+fn write_strings(out: &mut Vec<u8>, strings: &[String]) {
+    write_varint(out, strings.len() as u64);
+    for string in strings {
+        write_bytes(out, string.as_bytes());
+    }
+}
+
+fn write_section(out: &mut Vec<u8>, tag: u8, payload: &[u8]) {
+    out.push(tag);
+    write_bytes(out, payload);
+}
+
+/// Lays out `bytecode`, with `debug` if given, as a `.mlkc` file.
+pub fn write(bytecode: &Bytecode, debug: Option<&DebugInfo>) -> Result<Vec<u8>, VMError> {
+    let mut body = Vec::new();
+    let mut payload = Vec::new();
+    write_values(&mut payload, &bytecode.constants)?;
+    write_section(&mut body, CONSTANTS, &payload);
+    payload.clear();
+    write_strings(&mut payload, &bytecode.names);
+    write_section(&mut body, NAMES, &payload);
+    payload.clear();
+    write_strings(&mut payload, &bytecode.globals);
+    write_section(&mut body, GLOBALS, &payload);
+    write_section(&mut body, CODE, &bytecode.code);
+    if let Some(debug) = debug {
+        payload.clear();
+        write_bytes(&mut payload, debug.source_name.as_bytes());
+        write_bytes(&mut payload, debug.source.as_bytes());
+        write_section(&mut body, DEBUG, &payload);
+    }
+
+    let mut file = Vec::with_capacity(HEADER_LEN + body.len());
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    file.extend_from_slice(&crc32(&body).to_le_bytes());
+    file.extend_from_slice(&body);
+    Ok(file)
+}
+
+/// Reads the parts of a file, reporting problems at their offset in it.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: impl Into<String>) -> VMError {
+        VMError::InvalidBytecode {
+            offset: self.at,
+            message: message.into(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.at >= self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, VMError> {
+        let byte = *self.bytes.get(self.at).ok_or_else(|| self.error("file ends early"))?;
+        self.at += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, VMError> {
+        read_varint(self.bytes, &mut self.at)
+    }
+
+    /// A varint that counts or sizes something still in the file, so it
+    /// cannot be more than the bytes left.
+    fn len(&mut self) -> Result<usize, VMError> {
+        let len = self.varint()?;
+        if len > (self.bytes.len() - self.at) as u64 {
+            return Err(self.error(format!("length {} runs past the end of the file", len)));
+        }
+        Ok(len as usize)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], VMError> {
+        let len = self.len()?;
+        let bytes = &self.bytes[self.at..self.at + len];
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, VMError> {
+        let start = self.at;
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| VMError::InvalidBytecode {
+            offset: start,
+            message: "string is not valid UTF-8".to_string(),
+        })
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, VMError> {
+        (0..self.len()?).map(|_| self.string()).collect()
+    }
+
+    /// Reads a constant nested `depth` arrays and tuples deep.
+    fn value(&mut self, depth: usize) -> Result<Value, VMError> {
+        let start = self.at;
+        let value = match self.byte()? {
+            NULL => Value::Null,
+            NUMBER => {
+                let zigzag = self.varint()?;
+                let zigzag = u32::try_from(zigzag).map_err(|_| self.error("number out of range"))?;
+                Value::Number((zigzag >> 1) as i32 ^ -((zigzag & 1) as i32))
+            }
+            BOOLEAN => Value::Boolean(self.byte()? != 0),
+            STRING => Value::String(self.string()?.into()),
+            CHAR => {
+                let code = self.varint()?;
+                let c = u32::try_from(code).ok().and_then(char::from_u32);
+                Value::Char(c.ok_or_else(|| self.error(format!("{} is not a character", code)))?)
+            }
+            BYTES => Value::Bytes(self.bytes()?.to_vec()),
+            ARRAY | TUPLE if depth >= MAX_NESTING => {
+                return Err(VMError::InvalidBytecode {
+                    offset: start,
+                    message: "constants nested too deeply".to_string(),
+                })
+            }
+            ARRAY => Value::Array(self.values(depth + 1)?),
+            TUPLE => Value::Tuple(self.values(depth + 1)?.into()),
+            FUNCTION => {
+                let name = self.string()?;
+                let params = self.strings()?;
+                let entry = self.varint()? as usize;
+                let is_generator = self.byte()? != 0;
+                Value::Function(Rc::new(Function {
+                    name,
+                    params,
+                    entry,
+                    is_generator,
+                }))
+            }
+            tag => {
+                return Err(VMError::InvalidBytecode {
+                    offset: start,
+                    message: format!("unknown value tag {}", tag),
+                })
+            }
+        };
+        Ok(value)
+    }
+
+    fn values(&mut self, depth: usize) -> Result<Vec<Value>, VMError> {
+        (0..self.len()?).map(|_| self.value(depth)).collect()
+    }
+
+    /// Reads the section with `tag`, which has to come next, and checks
+    /// that `read` uses exactly its bytes.
+    fn section<T>(
+        &mut self,
+        tag: u8,
+        read: impl FnOnce(&mut Self) -> Result<T, VMError>,
+    ) -> Result<T, VMError> {
+        let found = self.byte()?;
+        if found != tag {
+            self.at -= 1;
+            return Err(self.error(format!("expected section {}, found {}", tag, found)));
+        }
+        let len = self.len()?;
+        let end = self.at + len;
+        let mut section = Reader {
+            bytes: &self.bytes[..end],
+            at: self.at,
+        };
+        let value = read(&mut section)?;
+        if section.at != end {
+            let unread = end - section.at;
+            return Err(section.error(format!("{} unread bytes at the end of section {}", unread, tag)));
+        }
+        self.at = end;
+        Ok(value)
+    }
+}
+
+/// Loads a `.mlkc` file, checking its version and checksum before it looks
+/// at anything else, and verifying its code before it is run.
+pub fn read(bytes: &[u8]) -> Result<CompiledFile, VMError> {
+    if !is_compiled(bytes) {
+        return Err(VMError::NotCompiled);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(VMError::InvalidBytecode {
+            offset: bytes.len(),
+            message: "header is cut short".to_string(),
+        });
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(VMError::VersionMismatch {
+            found: version,
+            expected: FORMAT_VERSION,
+        });
+    }
+    let expected = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    let actual = crc32(&bytes[HEADER_LEN..]);
+    if expected != actual {
+        return Err(VMError::ChecksumMismatch { expected, actual });
+    }
+
+    let mut reader = Reader {
+        bytes,
+        at: HEADER_LEN,
+    };
+    let constants = reader.section(CONSTANTS, |section| section.values(0))?;
+    let names = reader.section(NAMES, Reader::strings)?;
+    let globals = reader.section(GLOBALS, Reader::strings)?;
+    let code = reader.section(CODE, |section| {
+        let code = section.bytes[section.at..].to_vec();
+        section.at = section.bytes.len();
+        Ok(code)
+    })?;
+    let debug = if reader.is_empty() {
+        None
+    } else {
+        Some(reader.section(DEBUG, |section| {
+            Ok(DebugInfo {
+                source_name: section.string()?,
+                source: section.string()?,
+            })
+        })?)
+    };
+    if !reader.is_empty() {
+        return Err(reader.error("unexpected data after the last section"));
+    }
+    let bytecode = Bytecode {
+        code,
+        constants,
+        names,
+        globals,
+    };
+    verify_bytecode(&bytecode)?;
+    Ok(CompiledFile { bytecode, debug })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+    use crate::vm::Compiler;
+
+    const SOURCE: &str = "fn f(a) { return [a, \"s\", 'c', b\"\\x00\"] }\nx = f(7)\nassert x[0] == 7";
+
+    fn bytecode(source: &str) -> Bytecode {
+        let nodes = Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .expect("test program parses");
+        let program = Compiler::default()
+            .compile_program(nodes)
+            .expect("test program compiles");
+        Bytecode::encode(&program)
+    }
+
+    fn debug_info() -> DebugInfo {
+        DebugInfo {
+            source_name: "test.mlk".to_string(),
+            source: SOURCE.to_string(),
+        }
+    }
+
+    /// Replaces the checksum of an edited file with the right one.
+    fn fix_checksum(mut file: Vec<u8>) -> Vec<u8> {
+        let crc = crc32(&file[HEADER_LEN..]);
+        file[6..HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    /// A file whose sections hold `constants` and nothing else.
+    fn file_with_constants(constants: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        write_section(&mut body, CONSTANTS, constants);
+        write_section(&mut body, NAMES, &[0]);
+        write_section(&mut body, GLOBALS, &[0]);
+        write_section(&mut body, CODE, &[]);
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        file.extend_from_slice(&[0; 4]);
+        file.extend_from_slice(&body);
+        fix_checksum(file)
+    }
+
+    fn invalid(bytes: &[u8]) -> (usize, String) {
+        match read(bytes) {
+            Err(VMError::InvalidBytecode { offset, message }) => (offset, message),
+            other => panic!("expected invalid bytecode, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    fn assert_same(read: &Bytecode, written: &Bytecode) {
+        assert_eq!(read.code, written.code);
+        assert_eq!(format!("{:?}", read.constants), format!("{:?}", written.constants));
+        assert_eq!(read.names, written.names);
+        assert_eq!(read.globals, written.globals);
+    }
+
+    #[test]
+    fn files_roundtrip_with_their_source() {
+        let written = bytecode(SOURCE);
+        let file = read(&write(&written, Some(&debug_info())).unwrap()).unwrap();
+        assert_same(&file.bytecode, &written);
+        let debug = file.debug.expect("debug section");
+        assert_eq!(debug.source_name, "test.mlk");
+        assert_eq!(debug.source, SOURCE);
+    }
+
+    #[test]
+    fn stripped_files_roundtrip_without_a_debug_section() {
+        let written = bytecode(SOURCE);
+        let bytes = write(&written, None).unwrap();
+        assert!(bytes.len() < write(&written, Some(&debug_info())).unwrap().len());
+        let file = read(&bytes).unwrap();
+        assert_same(&file.bytecode, &written);
+        assert!(file.debug.is_none());
+    }
+
+    #[test]
+    fn files_with_the_wrong_header_are_rejected() {
+        let file = write(&bytecode(SOURCE), None).unwrap();
+        assert!(matches!(read(b"x = 1"), Err(VMError::NotCompiled)));
+
+        let mut newer = file.clone();
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            read(&newer),
+            Err(VMError::VersionMismatch { found, expected: FORMAT_VERSION }) if found == FORMAT_VERSION + 1
+        ));
+
+        let mut damaged = file.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert!(matches!(read(&damaged), Err(VMError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        assert_eq!(invalid(b"MLKC\x01\x00"), (6, "header is cut short".to_string()));
+        let file = write(&bytecode(SOURCE), None).unwrap();
+        let (_, message) = invalid(&fix_checksum(file[..file.len() - 3].to_vec()));
+        assert!(message.contains("runs past the end of the file"), "{}", message);
+    }
+
+    #[test]
+    fn data_after_the_last_section_is_rejected() {
+        let mut file = write(&bytecode(SOURCE), Some(&debug_info())).unwrap();
+        let end = file.len();
+        file.push(0);
+        assert_eq!(
+            invalid(&fix_checksum(file)),
+            (end, "unexpected data after the last section".to_string())
+        );
+    }
+
+    #[test]
+    fn deeply_nested_constants_are_rejected() {
+        // One constant: arrays of one element, nested far past the limit.
+        let depth = 100_000;
+        let mut constants = vec![1];
+        for _ in 0..depth {
+            constants.extend([ARRAY, 1]);
+        }
+        constants.push(NULL);
+        let file = file_with_constants(&constants);
+        // The constants section starts after its tag and a 3-byte length.
+        let first = HEADER_LEN + 1 + 3 + 1;
+        assert_eq!(
+            invalid(&file),
+            (first + 2 * MAX_NESTING, "constants nested too deeply".to_string())
+        );
+
+        let mut constants = vec![1];
+        for _ in 0..MAX_NESTING {
+            constants.extend([TUPLE, 1]);
+        }
+        constants.push(NULL);
+        assert!(read(&file_with_constants(&constants)).is_ok());
+    }
+}
@@ -0,0 +1,585 @@
+use crate::ast::{ASTNode, Param};
+use crate::error::VMError;
+use crate::tokenizer::Token;
+use crate::types::{VMBinaryOp, VMCompare, Value};
+use crate::vm::produces_value;
+use miette::SourceSpan;
+use std::collections::HashMap;
+
+/// The variables of one function (or of the top level, or of a `comptime`
+/// block) and whether each of them only ever holds a number.
+struct Frame {
+    numbers: HashMap<String, bool>,
+    /// The top level and `comptime` blocks have no enclosing globals.
+    is_root: bool,
+}
+
+/// Simplifies the AST before it is compiled: folds operations on literals,
+/// drops arithmetic identities on numbers and collapses `if`s whose
+/// condition is a constant.
+#[derive(Default)]
+pub struct Optimizer {
+    frames: Vec<Frame>,
+    errors: Vec<VMError>,
+}
+
+impl Optimizer {
+    pub fn optimize_program(&mut self, nodes: Vec<ASTNode>) -> Result<Vec<ASTNode>, VMError> {
+        self.enter(&nodes, &[], true);
+        let nodes = self.body(nodes, true);
+        self.frames.pop();
+        let mut errors = std::mem::take(&mut self.errors);
+        match errors.len() {
+            0 => Ok(nodes),
+            1 => Err(errors.remove(0)),
+            _ => Err(VMError::CompileFailed { errors }),
+        }
+    }
+
+    /// Starts a frame for `body`. A variable holds a number if every
+    /// assignment to it in the frame does, assuming the same of the others
+    /// until that stops changing, so loop counters qualify.
+    fn enter(&mut self, body: &[ASTNode], params: &[Param], is_root: bool) {
+        let mut assignments = Vec::new();
+        collect_assignments(body, &mut assignments);
+        let mut numbers: HashMap<String, bool> = assignments
+            .iter()
+            .map(|(name, _)| (name.to_string(), true))
+            .collect();
+        for param in params {
+            numbers.insert(param.name.clone(), false);
+        }
+        self.frames.push(Frame { numbers, is_root });
+        loop {
+            let demoted: Vec<&str> = assignments
+                .iter()
+                .filter(|(name, value)| {
+                    self.is_number(&ASTNode::VarRef(name.to_string()))
+                        && !value.is_some_and(|value| self.is_number(value))
+                })
+                .map(|(name, _)| *name)
+                .collect();
+            if demoted.is_empty() {
+                break;
+            }
+            let frame = self.frames.last_mut().expect("frame just entered");
+            for name in demoted {
+                frame.numbers.insert(name.to_string(), false);
+            }
+        }
+    }
+
+    /// Whether `node` is known to evaluate to a number, if it evaluates at
+    /// all.
+    fn is_number(&self, node: &ASTNode) -> bool {
+        match node {
+            ASTNode::Number(_) => true,
+            ASTNode::VarRef(name) => {
+                let Some(frame) = self.frames.last() else {
+                    return false;
+                };
+                match frame.numbers.get(name) {
+                    Some(&number) => number,
+                    None if frame.is_root => false,
+                    // Functions only see their own variables and the globals.
+                    None => self
+                        .frames
+                        .iter()
+                        .rev()
+                        .find(|frame| frame.is_root)
+                        .and_then(|root| root.numbers.get(name))
+                        .is_some_and(|&number| number),
+                }
+            }
+            ASTNode::BinOp {
+                left, op, right, ..
+            } => {
+                matches!(op, Token::Plus | Token::Minus | Token::Star | Token::Slash)
+                    && self.is_number(left)
+                    && self.is_number(right)
+            }
+            _ => false,
+        }
+    }
+
+    /// Optimises a statement list. When `keeps_values` is set the values of
+    /// its statements stay on the stack, so a collapsed `if` is only spliced
+    /// in if its branch leaves nothing behind.
+    fn body(&mut self, nodes: Vec<ASTNode>, keeps_values: bool) -> Vec<ASTNode> {
+        let mut result = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let ASTNode::If {
+                condition,
+                if_block,
+                else_block,
+            } = node
+            else {
+                result.push(self.node(node));
+                continue;
+            };
+            let condition = self.node(*condition);
+            let Some(value) = constant(&condition) else {
+                result.push(ASTNode::If {
+                    condition: Box::new(condition),
+                    if_block: self.body(if_block, false),
+                    else_block: self.body(else_block, false),
+                });
+                continue;
+            };
+            // Branches share the enclosing block's variables, so the taken one
+            // can stand in for the whole statement unless it defers something:
+            // then it opens a scope that must end with the branch.
+            let (taken, skipped) = if value.is_truthy() {
+                (if_block, else_block)
+            } else {
+                (else_block, if_block)
+            };
+            let taken = self.body(taken, false);
+            let defers = taken.iter().any(|node| matches!(node, ASTNode::Defer(_)));
+            if !defers && (!keeps_values || !taken.iter().any(produces_value)) {
+                result.extend(taken);
+            } else {
+                let (if_block, else_block) = if value.is_truthy() {
+                    (taken, self.body(skipped, false))
+                } else {
+                    (self.body(skipped, false), taken)
+                };
+                result.push(ASTNode::If {
+                    condition: Box::new(condition),
+                    if_block,
+                    else_block,
+                });
+            }
+        }
+        result
+    }
+
+    fn node(&mut self, node: ASTNode) -> ASTNode {
+        match node {
+            ASTNode::BinOp {
+                left,
+                op,
+                right,
+                span,
+            } => {
+                let left = self.node(*left);
+                let right = self.node(*right);
+                self.binary(left, op, right, span)
+            }
+            ASTNode::If {
+                condition,
+                if_block,
+                else_block,
+            } => ASTNode::If {
+                condition: Box::new(self.node(*condition)),
+                if_block: self.body(if_block, false),
+                else_block: self.body(else_block, false),
+            },
+            ASTNode::While { condition, body } => ASTNode::While {
+                condition: Box::new(self.node(*condition)),
+                body: self.body(body, false),
+            },
+            ASTNode::For {
+                var,
+                iterable,
+                body,
+            } => ASTNode::For {
+                var,
+                iterable: Box::new(self.node(*iterable)),
+                body: self.body(body, false),
+            },
+            ASTNode::VarDecl {
+                name,
+                ty,
+                value,
+                span,
+            } => ASTNode::VarDecl {
+                name,
+                ty,
+                value: Box::new(self.node(*value)),
+                span,
+            },
+            ASTNode::Block(nodes) => ASTNode::Block(self.body(nodes, false)),
+            ASTNode::Array(elements) => ASTNode::Array(self.nodes(elements)),
+            ASTNode::Tuple(elements) => ASTNode::Tuple(self.nodes(elements)),
+            ASTNode::Destructure { names, value, span } => ASTNode::Destructure {
+                names,
+                value: Box::new(self.node(*value)),
+                span,
+            },
+            ASTNode::ArrayIndex {
+                array,
+                index,
+                optional,
+                span,
+            } => ASTNode::ArrayIndex {
+                array: Box::new(self.node(*array)),
+                index: Box::new(self.node(*index)),
+                optional,
+                span,
+            },
+            ASTNode::ArrayAssign {
+                array,
+                index,
+                value,
+                span,
+            } => ASTNode::ArrayAssign {
+                array: Box::new(self.node(*array)),
+                index: Box::new(self.node(*index)),
+                value: Box::new(self.node(*value)),
+                span,
+            },
+            ASTNode::FnDecl {
+                name,
+                params,
+                return_type,
+                body,
+                is_generator,
+                is_const,
+            } => {
+                self.enter(&body, &params, false);
+                let body = self.body(body, false);
+                self.frames.pop();
+                ASTNode::FnDecl {
+                    name,
+                    params,
+                    return_type,
+                    body,
+                    is_generator,
+                    is_const,
+                }
+            }
+            ASTNode::Call { callee, args, span } => ASTNode::Call {
+                callee: Box::new(self.node(*callee)),
+                args: self.nodes(args),
+                span,
+            },
+            ASTNode::MethodCall {
+                object,
+                method,
+                args,
+                optional,
+            } => ASTNode::MethodCall {
+                object: Box::new(self.node(*object)),
+                method,
+                args: self.nodes(args),
+                optional,
+            },
+            ASTNode::ClassDecl { name, methods } => ASTNode::ClassDecl {
+                name,
+                methods: self.nodes(methods),
+            },
+            ASTNode::FieldAccess {
+                object,
+                field,
+                optional,
+            } => ASTNode::FieldAccess {
+                object: Box::new(self.node(*object)),
+                field,
+                optional,
+            },
+            ASTNode::NullCoalesce { left, right } => ASTNode::NullCoalesce {
+                left: Box::new(self.node(*left)),
+                right: Box::new(self.node(*right)),
+            },
+            ASTNode::FieldAssign {
+                object,
+                field,
+                value,
+            } => ASTNode::FieldAssign {
+                object: Box::new(self.node(*object)),
+                field,
+                value: Box::new(self.node(*value)),
+            },
+            ASTNode::Return { value, span } => ASTNode::Return {
+                value: value.map(|value| Box::new(self.node(*value))),
+                span,
+            },
+            ASTNode::Yield { value, span } => ASTNode::Yield {
+                value: Box::new(self.node(*value)),
+                span,
+            },
+            ASTNode::Comptime { body, span } => {
+                // The block runs on its own, so it gets a frame of its own.
+                let ends_with_value = body.last().is_some_and(produces_value);
+                self.enter(&body, &[], true);
+                let mut body = self.body(body, true);
+                self.frames.pop();
+                // A collapsed `if` must not expose the value before it.
+                if !ends_with_value && body.last().is_some_and(produces_value) {
+                    body.push(ASTNode::Null);
+                }
+                ASTNode::Comptime { body, span }
+            }
+            ASTNode::Defer(value) => ASTNode::Defer(Box::new(self.node(*value))),
+            ASTNode::Assert {
+                condition,
+                message,
+                text,
+                span,
+            } => ASTNode::Assert {
+                condition: Box::new(self.node(*condition)),
+                message: message.map(|message| Box::new(self.node(*message))),
+                text,
+                span,
+            },
+            leaf @ (ASTNode::Number(_)
+            | ASTNode::String(_)
+            | ASTNode::Bytes(_)
+            | ASTNode::Null
+            | ASTNode::VarRef(_)) => leaf,
+        }
+    }
+
+    fn nodes(&mut self, nodes: Vec<ASTNode>) -> Vec<ASTNode> {
+        nodes.into_iter().map(|node| self.node(node)).collect()
+    }
+
+    fn binary(&mut self, left: ASTNode, op: Token, right: ASTNode, span: SourceSpan) -> ASTNode {
+        if let (Some(a), Some(b)) = (constant(&left), constant(&right)) {
+            match fold(&op, &a, &b) {
+                Ok(Some(value)) => {
+                    if let Some(node) = literal(value) {
+                        return node;
+                    }
+                }
+                Err(VMError::DivisionByZero) => self.division_by_zero(span),
+                Err(VMError::IntegerOverflow) => self.overflow(span),
+                // Anything else is left for the type checker or the VM to report.
+                _ => {}
+            }
+        } else if op == Token::Slash && matches!(right, ASTNode::Number(0)) && self.is_number(&left) {
+            self.division_by_zero(span);
+        }
+
+        let identity = match (&left, &op, &right) {
+            (_, Token::Plus | Token::Minus, ASTNode::Number(0))
+            | (_, Token::Star | Token::Slash, ASTNode::Number(1)) => Some(left.clone()),
+            (ASTNode::Number(0), Token::Plus, _) | (ASTNode::Number(1), Token::Star, _) => {
+                Some(right.clone())
+            }
+            _ => None,
+        };
+        match identity {
+            // Only numbers are sure not to be converted or overloaded.
+            Some(operand) if self.is_number(&left) && self.is_number(&right) => operand,
+            _ => ASTNode::BinOp {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+                span,
+            },
+        }
+    }
+
+    fn division_by_zero(&mut self, span: SourceSpan) {
+        self.errors.push(VMError::CompileError {
+            message: "Division by zero".to_string(),
+            span,
+        });
+    }
+
+    fn overflow(&mut self, span: SourceSpan) {
+        self.errors.push(VMError::CompileError {
+            message: "Integer overflow".to_string(),
+            span,
+        });
+    }
+}
+
+/// Records every assignment to a variable of the frame that `nodes` belong
+/// to, with the value assigned when it is an expression of its own.
+fn collect_assignments<'a>(nodes: &'a [ASTNode], assignments: &mut Vec<(&'a str, Option<&'a ASTNode>)>) {
+    for node in nodes {
+        match node {
+            ASTNode::VarDecl { name, value, .. } => {
+                assignments.push((name, Some(value)));
+            }
+            ASTNode::Destructure { names, .. } => {
+                assignments.extend(names.iter().map(|name| (name.as_str(), None)));
+            }
+            ASTNode::FnDecl { name, .. } | ASTNode::ClassDecl { name, .. } => {
+                assignments.push((name, None));
+            }
+            ASTNode::For { var, body, .. } => {
+                assignments.push((var, None));
+                collect_assignments(body, assignments);
+            }
+            ASTNode::If {
+                if_block,
+                else_block,
+                ..
+            } => {
+                collect_assignments(if_block, assignments);
+                collect_assignments(else_block, assignments);
+            }
+            ASTNode::While { body, .. } | ASTNode::Block(body) => {
+                collect_assignments(body, assignments);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The value of an expression made only of literals.
+fn constant(node: &ASTNode) -> Option<Value> {
+    match node {
+        ASTNode::Number(n) => Some(Value::Number(*n)),
+        ASTNode::String(s) => Some(Value::String(s.as_str().into())),
+        ASTNode::Bytes(bytes) => Some(Value::Bytes(bytes.clone())),
+        ASTNode::Null => Some(Value::Null),
+        ASTNode::BinOp {
+            left, op, right, ..
+        } => fold(op, &constant(left)?, &constant(right)?).ok()?,
+        _ => None,
+    }
+}
+
+/// Applies `op` the way the VM would.
+fn fold(op: &Token, a: &Value, b: &Value) -> Result<Option<Value>, VMError> {
+    let value = match op {
+        Token::Plus => a.add(b)?,
+        Token::Minus => a.sub(b)?,
+        Token::Star => a.mul(b)?,
+        Token::Slash => a.div(b)?,
+        Token::Greater => Value::Boolean(a.gt(b)?),
+        Token::Less => Value::Boolean(a.lt(b)?),
+        Token::Equal => Value::Boolean(a.eq(b)),
+        Token::NotEqual => Value::Boolean(!a.eq(b)),
+        _ => return Ok(None),
+    };
+    Ok(Some(value))
+}
+
+/// The literal that evaluates to `value`, if the language has one.
+fn literal(value: Value) -> Option<ASTNode> {
+    match value {
+        Value::Number(n) => Some(ASTNode::Number(n)),
+        Value::String(s) => Some(ASTNode::String(s.to_string())),
+        Value::Bytes(bytes) => Some(ASTNode::Bytes(bytes)),
+        Value::Null => Some(ASTNode::Null),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+    use crate::vm::{Compiler, VM};
+
+    fn optimize(source: &str) -> Result<Vec<ASTNode>, VMError> {
+        let nodes = Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .expect("test program parses");
+        Optimizer::default().optimize_program(nodes)
+    }
+
+    /// The message and the source text of the span of each error `source`
+    /// fails to optimize with.
+    fn errors(source: &str) -> Vec<(String, String)> {
+        let errors = match optimize(source) {
+            Ok(_) => panic!("{} optimized without error", source),
+            Err(VMError::CompileFailed { errors }) => errors,
+            Err(error) => vec![error],
+        };
+        errors
+            .into_iter()
+            .map(|error| match error {
+                VMError::CompileError { message, span } => {
+                    (message, source[span.offset()..span.offset() + span.len()].to_string())
+                }
+                other => panic!("expected a compile error, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn constant_arithmetic_is_folded() {
+        let nodes = optimize("x = 2 * 3 + 4").unwrap();
+        assert!(matches!(
+            &nodes[..],
+            [ASTNode::VarDecl { value, .. }] if matches!(**value, ASTNode::Number(10))
+        ));
+    }
+
+    #[test]
+    fn folded_overflow_is_reported_at_the_expression() {
+        assert_eq!(
+            errors("y = 2147483647 + 1\nz = 2 * (0 - 2147483647 - 1)"),
+            [
+                ("Integer overflow".to_string(), "2147483647 + 1".to_string()),
+                ("Integer overflow".to_string(), "2 * (0 - 2147483647 - 1)".to_string()),
+            ]
+        );
+        assert_eq!(
+            errors("y = 1 / 0"),
+            [("Division by zero".to_string(), "1 / 0".to_string())]
+        );
+    }
+
+    #[test]
+    fn division_by_a_literal_zero_is_reported_at_the_expression() {
+        assert_eq!(
+            errors("x = 4\ny = x / 0"),
+            [("Division by zero".to_string(), "x / 0".to_string())]
+        );
+        // Only numbers are known to divide the built-in way.
+        assert!(optimize("s = \"a\"\nt = s / 0").is_ok());
+    }
+
+    #[test]
+    fn identities_on_numbers_are_dropped() {
+        let nodes = optimize("x = 4\ny = x * 1\nz = 0 + x").unwrap();
+        for node in &nodes[1..] {
+            assert!(
+                matches!(node, ASTNode::VarDecl { value, .. } if matches!(&**value, ASTNode::VarRef(name) if name == "x")),
+                "{:?} was not simplified",
+                node
+            );
+        }
+    }
+
+    #[test]
+    fn identities_on_other_values_are_kept() {
+        // "a" + 0 is "a0", so the addition has to stay.
+        let nodes = optimize("s = \"a\"\nt = s + 0\nfn f(p) { return p * 1 }").unwrap();
+        assert!(matches!(
+            &nodes[1],
+            ASTNode::VarDecl { value, .. } if matches!(**value, ASTNode::BinOp { .. })
+        ));
+        let ASTNode::FnDecl { body, .. } = &nodes[2] else {
+            panic!("expected a function, got {:?}", nodes[2]);
+        };
+        assert!(matches!(
+            &body[..],
+            [ASTNode::Return { value: Some(value), .. }] if matches!(**value, ASTNode::BinOp { .. })
+        ));
+    }
+
+    #[test]
+    fn ifs_with_a_constant_condition_are_collapsed() {
+        let nodes = optimize("if (1 < 2) { y = 2 } else { y = 3 }\nif (0) { z = 1 }").unwrap();
+        assert!(matches!(
+            &nodes[..],
+            [ASTNode::VarDecl { name, value, .. }] if name == "y" && matches!(**value, ASTNode::Number(2))
+        ));
+    }
+
+    #[test]
+    fn collapsed_branches_still_run_their_deferred_actions_when_they_finish() {
+        let source = "class Log {\n  fn init(self) { self.s = 0 }\n  fn add(self, i) { self.s = self.s * 10 + i }\n}\nl = Log()\nfn h() { if (1) { defer l.add(1) }; l.add(2) }\nh()\ns = l.s";
+        let program = Compiler::default()
+            .compile_program(optimize(source).unwrap())
+            .expect("test program compiles");
+        let mut vm = VM::new();
+        vm.execute(&program).expect("test program runs");
+        let s = vm
+            .global_values(&program)
+            .into_iter()
+            .find(|(name, _)| *name == "s")
+            .map(|(_, value)| format!("{:?}", value));
+        assert_eq!(s.as_deref(), Some("Number(12)"));
+    }
+}
@@ -1,23 +1,32 @@
-use crate::ast::ASTNode;
+use crate::ast::{ASTNode, Param};
 use crate::error::VMError;
 use crate::tokenizer::{Token, Tokenizer};
+use crate::typecheck::Type;
+use miette::SourceSpan;
 
 #[derive(Debug)]
 pub struct Parser {
     pub tokenizer: Tokenizer,
     pub current_token: Token,
     token_start: usize,
+    previous_end: usize,
 }
 
 impl Parser {
-    pub fn new(mut tokenizer: Tokenizer) -> Self {
-        let token_start = tokenizer.position;
-        let current_token = tokenizer.next_token().unwrap();
-        Parser {
+    /// Reads the first token, so a malformed one is reported here.
+    pub fn new(mut tokenizer: Tokenizer) -> Result<Self, VMError> {
+        let current_token = tokenizer.next_token()?;
+        Ok(Parser {
+            token_start: tokenizer.token_start,
             tokenizer,
             current_token,
-            token_start,
-        }
+            previous_end: 0,
+        })
+    }
+
+    /// Span from `start` to the end of the last consumed token.
+    fn span_from(&self, start: usize) -> SourceSpan {
+        (start, self.previous_end.saturating_sub(start)).into()
     }
 
     fn create_error(&self, message: String) -> VMError {
@@ -31,8 +40,9 @@ impl Parser {
 
     fn eat(&mut self, token: Token) -> Result<(), VMError> {
         if self.current_token == token {
-            self.token_start = self.tokenizer.position;
+            self.previous_end = self.tokenizer.position;
             self.current_token = self.tokenizer.next_token()?;
+            self.token_start = self.tokenizer.token_start;
             Ok(())
         } else {
             Err(self.create_error(
@@ -42,10 +52,11 @@ impl Parser {
     }
 
     fn error(&self, message: &str) -> VMError {
-        self.create_error(format!("{}", message))
+        self.create_error(message.to_string())
     }
 
     fn factor(&mut self) -> Result<ASTNode, VMError> {
+        let start = self.token_start;
         match &self.current_token {
             Token::Number(n) => {
                 let num = *n;
@@ -55,31 +66,139 @@ impl Parser {
             Token::String(s) => {
                 let str = s.clone();
                 self.eat(Token::String(str.clone()))?;
-                Ok(ASTNode::String(str))
+                self.postfix(ASTNode::String(str), start)
+            }
+            Token::Bytes(bytes) => {
+                let bytes = bytes.clone();
+                self.eat(Token::Bytes(bytes.clone()))?;
+                self.postfix(ASTNode::Bytes(bytes), start)
+            }
+            Token::Null => {
+                self.eat(Token::Null)?;
+                Ok(ASTNode::Null)
             }
             Token::LParen => {
-                self.eat(Token::LParen)?;
-                let node = self.comparison()?;
-                self.eat(Token::RParen)?;
-                Ok(node)
+                let node = self.parenthesized()?;
+                self.postfix(node, start)
             }
             Token::Ident(name) => {
                 let var_name = name.clone();
                 self.eat(Token::Ident(var_name.clone()))?;
-                if self.current_token == Token::LBracket {
-                    self.array_index(ASTNode::VarRef(var_name))
-                } else {
-                    Ok(ASTNode::VarRef(var_name))
-                }
+                self.postfix(ASTNode::VarRef(var_name), start)
+            }
+            Token::LBracket => {
+                let node = self.array_literal()?;
+                self.postfix(node, start)
+            }
+            Token::Comptime => {
+                self.eat(Token::Comptime)?;
+                let body = self.block()?;
+                let node = ASTNode::Comptime {
+                    body,
+                    span: self.span_from(start),
+                };
+                self.postfix(node, start)
             }
-            Token::LBracket => self.array_literal(),
 
             _ => Err(self.error("Expected number, string, identifier, or '('")),
 
         }
     }
 
+    /// A parenthesised expression, or a tuple if the parentheses are empty or
+    /// contain a comma: `()`, `(a,)`, `(a, b)`.
+    fn parenthesized(&mut self) -> Result<ASTNode, VMError> {
+        self.eat(Token::LParen)?;
+        if self.current_token == Token::RParen {
+            self.eat(Token::RParen)?;
+            return Ok(ASTNode::Tuple(Vec::new()));
+        }
+        let first = self.null_coalescing()?;
+        if self.current_token != Token::Comma {
+            self.eat(Token::RParen)?;
+            return Ok(first);
+        }
+        let mut elements = vec![first];
+        while self.current_token == Token::Comma {
+            self.eat(Token::Comma)?;
+            if self.current_token == Token::RParen {
+                break; // Allow trailing comma
+            }
+            elements.push(self.null_coalescing()?);
+        }
+        self.eat(Token::RParen).map_err(|_| self.error("Expected closing parenthesis ')'"))?;
+        Ok(ASTNode::Tuple(elements))
+    }
+
+    fn postfix(&mut self, mut node: ASTNode, start: usize) -> Result<ASTNode, VMError> {
+        loop {
+            // A `(` or `[` on a new line starts a new statement rather than
+            // calling or indexing the expression before it.
+            let continues_line = !self.tokenizer.newline_before;
+            node = match self.current_token {
+                Token::LBracket | Token::QuestionBracket if continues_line => {
+                    self.array_index(node, start)?
+                }
+                Token::LParen if continues_line => {
+                    let args = self.arguments()?;
+                    ASTNode::Call {
+                        callee: Box::new(node),
+                        args,
+                        span: self.span_from(start),
+                    }
+                }
+                Token::Dot | Token::QuestionDot => {
+                    let optional = self.current_token == Token::QuestionDot;
+                    let token = self.current_token.clone();
+                    self.eat(token)?;
+                    let name = self.identifier("Expected field or method name after '.'")?;
+                    if self.current_token == Token::LParen {
+                        let args = self.arguments()?;
+                        ASTNode::MethodCall {
+                            object: Box::new(node),
+                            method: name,
+                            args,
+                            optional,
+                        }
+                    } else {
+                        ASTNode::FieldAccess {
+                            object: Box::new(node),
+                            field: name,
+                            optional,
+                        }
+                    }
+                }
+                _ => return Ok(node),
+            };
+        }
+    }
+
+    fn arguments(&mut self) -> Result<Vec<ASTNode>, VMError> {
+        self.eat(Token::LParen)?;
+        let mut args = Vec::new();
+        if self.current_token != Token::RParen {
+            args.push(self.null_coalescing()?);
+            while self.current_token == Token::Comma {
+                self.eat(Token::Comma)?;
+                args.push(self.null_coalescing()?);
+            }
+        }
+        self.eat(Token::RParen).map_err(|_| self.error("Expected closing parenthesis ')'"))?;
+        Ok(args)
+    }
+
+    fn identifier(&mut self, message: &str) -> Result<String, VMError> {
+        if let Token::Ident(name) = &self.current_token {
+            let name = name.clone();
+            self.eat(Token::Ident(name.clone()))?;
+            Ok(name)
+        } else {
+            Err(self.error(message))
+        }
+    }
+
     fn term(&mut self) -> Result<ASTNode, VMError> {
+        let start = self.token_start;
         let mut node = self.factor()?;
         while matches!(self.current_token, Token::Star | Token::Slash) {
             let op = self.current_token.clone();
@@ -88,16 +207,19 @@ impl Parser {
                 Token::Slash => self.eat(Token::Slash)?,
                 _ => unreachable!(),
             }
+            let right = self.factor()?;
             node = ASTNode::BinOp {
                 left: Box::new(node),
                 op,
-                right: Box::new(self.factor()?),
+                right: Box::new(right),
+                span: self.span_from(start),
             };
         }
         Ok(node)
     }
 
     fn expr(&mut self) -> Result<ASTNode, VMError> {
+        let start = self.token_start;
         let mut node = self.term()?;
         while matches!(self.current_token, Token::Plus | Token::Minus) {
             let op = self.current_token.clone();
@@ -106,16 +228,19 @@ impl Parser {
                 Token::Minus => self.eat(Token::Minus)?,
                 _ => unreachable!(),
             }
+            let right = self.term()?;
             node = ASTNode::BinOp {
                 left: Box::new(node),
                 op,
-                right: Box::new(self.term()?),
+                right: Box::new(right),
+                span: self.span_from(start),
             };
         }
         Ok(node)
     }
 
     fn comparison(&mut self) -> Result<ASTNode, VMError> {
+        let start = self.token_start;
         let mut node = self.expr()?;
         while matches!(
             self.current_token,
@@ -129,10 +254,25 @@ impl Parser {
                 Token::NotEqual => self.eat(Token::NotEqual)?,
                 _ => unreachable!(),
             }
+            let right = self.expr()?;
             node = ASTNode::BinOp {
                 left: Box::new(node),
                 op,
-                right: Box::new(self.expr()?),
+                right: Box::new(right),
+                span: self.span_from(start),
+            };
+        }
+        Ok(node)
+    }
+
+    fn null_coalescing(&mut self) -> Result<ASTNode, VMError> {
+        let mut node = self.comparison()?;
+        while self.current_token == Token::QuestionQuestion {
+            self.eat(Token::QuestionQuestion)?;
+            let right = self.comparison()?;
+            node = ASTNode::NullCoalesce {
+                left: Box::new(node),
+                right: Box::new(right),
             };
         }
         Ok(node)
@@ -141,7 +281,7 @@ impl Parser {
     fn if_statement(&mut self) -> Result<ASTNode, VMError> {
         self.eat(Token::If)?;
         self.eat(Token::LParen)?;
-        let condition = self.comparison()?;
+        let condition = self.null_coalescing()?;
         self.eat(Token::RParen)?;
         let if_block = self.block()?;
         let else_block = if self.current_token == Token::Else {
@@ -160,7 +300,7 @@ impl Parser {
     fn while_loop(&mut self) -> Result<ASTNode, VMError> {
         self.eat(Token::While)?;
         self.eat(Token::LParen)?;
-        let condition = self.comparison()?;
+        let condition = self.null_coalescing()?;
         self.eat(Token::RParen)?;
         let body = if self.current_token == Token::LBrace {
             self.block()?
@@ -183,47 +323,277 @@ impl Parser {
         Ok(nodes)
     }
 
-    fn statement(&mut self) -> Result<ASTNode, VMError> {
-        match self.current_token {
-            Token::If => self.if_statement(),
-            Token::While => self.while_loop(),
-            Token::LBrace => Ok(ASTNode::Block(self.block()?)),
-            Token::Ident(_) => self.var_statement(),
-            _ => self.expr(),
+    fn for_loop(&mut self) -> Result<ASTNode, VMError> {
+        self.eat(Token::For)?;
+        let var = self.identifier("Expected loop variable after 'for'")?;
+        self.eat(Token::In)?;
+        let iterable = self.null_coalescing()?;
+        let body = self.block()?;
+        Ok(ASTNode::For {
+            var,
+            iterable: Box::new(iterable),
+            body,
+        })
+    }
+
+    fn fn_declaration(&mut self) -> Result<ASTNode, VMError> {
+        self.eat(Token::Fn)?;
+        let is_generator = if self.current_token == Token::Star {
+            self.eat(Token::Star)?;
+            true
+        } else {
+            false
+        };
+        let name = self.identifier("Expected function name")?;
+        self.eat(Token::LParen)?;
+        let mut params = Vec::new();
+        if self.current_token != Token::RParen {
+            params.push(self.param()?);
+            while self.current_token == Token::Comma {
+                self.eat(Token::Comma)?;
+                params.push(self.param()?);
+            }
+        }
+        self.eat(Token::RParen)?;
+        let return_type = if self.current_token == Token::Arrow {
+            self.eat(Token::Arrow)?;
+            Some(self.type_annotation()?)
+        } else {
+            None
+        };
+        let body = self.block()?;
+        Ok(ASTNode::FnDecl {
+            name,
+            params,
+            return_type,
+            body,
+            is_generator,
+            is_const: false,
+        })
+    }
+
+    fn const_fn_declaration(&mut self) -> Result<ASTNode, VMError> {
+        self.eat(Token::Const)?;
+        if self.current_token != Token::Fn {
+            return Err(self.error("Expected 'fn' after 'const'"));
+        }
+        let mut declaration = self.fn_declaration()?;
+        if let ASTNode::FnDecl { is_const, .. } = &mut declaration {
+            *is_const = true;
         }
+        Ok(declaration)
     }
 
-    fn var_statement(&mut self) -> Result<ASTNode, VMError> {
-        let var_name = if let Token::Ident(name) = &self.current_token {
-            name.clone()
+    fn class_declaration(&mut self) -> Result<ASTNode, VMError> {
+        self.eat(Token::Class)?;
+        let name = self.identifier("Expected class name")?;
+        self.eat(Token::LBrace)?;
+        let mut methods = Vec::new();
+        while self.current_token == Token::Fn {
+            let method = self.fn_declaration()?;
+            if let ASTNode::FnDecl { params, .. } = &method {
+                if params.first().map(|param| param.name.as_str()) != Some("self") {
+                    return Err(self.error("Methods must take 'self' as their first parameter"));
+                }
+            }
+            methods.push(method);
+        }
+        self.eat(Token::RBrace)
+            .map_err(|_| self.error("Expected method declaration or '}'"))?;
+        Ok(ASTNode::ClassDecl { name, methods })
+    }
+
+    fn param(&mut self) -> Result<Param, VMError> {
+        let name = self.identifier("Expected parameter name")?;
+        let ty = if self.current_token == Token::Colon {
+            self.eat(Token::Colon)?;
+            Some(self.type_annotation()?)
         } else {
-            return Err(self.error("Expected variable name"));
+            None
         };
-        self.eat(Token::Ident(var_name.clone()))?;
-    
-        if self.current_token == Token::Assignment {
-            self.eat(Token::Assignment)?;
-            let value = self.expr()?;
-            Ok(ASTNode::VarDecl(var_name, Box::new(value)))
-        } else if self.current_token == Token::LBracket {
-            let array_index = self.array_index(ASTNode::VarRef(var_name.clone()))?;
-            if self.current_token == Token::Assignment {
-                self.eat(Token::Assignment)?;
-                let value = self.expr()?;
-                if let ASTNode::ArrayIndex { array, index } = array_index {
-                    Ok(ASTNode::ArrayAssign {
-                        array,
-                        index,
-                        value: Box::new(value),
-                    })
-                } else {
-                    Err(self.error("Expected array index"))
+        Ok(Param { name, ty })
+    }
+
+    fn type_annotation(&mut self) -> Result<Type, VMError> {
+        if self.current_token == Token::LParen {
+            self.eat(Token::LParen)?;
+            let mut elements = Vec::new();
+            while self.current_token != Token::RParen {
+                elements.push(self.type_annotation()?);
+                if self.current_token != Token::Comma {
+                    break;
                 }
-            } else {
-                Ok(array_index)
+                self.eat(Token::Comma)?;
             }
+            self.eat(Token::RParen).map_err(|_| self.error("Expected closing parenthesis ')'"))?;
+            return Ok(Type::Tuple(elements));
+        }
+        if self.current_token == Token::LBracket {
+            self.eat(Token::LBracket)?;
+            let element = self.type_annotation()?;
+            self.eat(Token::RBracket).map_err(|_| self.error("Expected closing bracket ']'"))?;
+            return Ok(Type::Array(Box::new(element)));
+        }
+        let ty = match &self.current_token {
+            Token::Ident(name) => Type::from_name(name),
+            Token::Fn => Some(Type::Function),
+            _ => None,
+        };
+        let ty = ty.ok_or_else(|| self.error("Expected a type"))?;
+        let token = self.current_token.clone();
+        self.eat(token)?;
+        Ok(ty)
+    }
+
+    fn return_statement(&mut self) -> Result<ASTNode, VMError> {
+        let start = self.token_start;
+        self.eat(Token::Return)?;
+        let value = if self.tokenizer.newline_before
+            || matches!(self.current_token, Token::RBrace | Token::Eof | Token::Semicolon)
+        {
+            None
+        } else {
+            Some(Box::new(self.null_coalescing()?))
+        };
+        Ok(ASTNode::Return {
+            value,
+            span: self.span_from(start),
+        })
+    }
+
+    fn yield_statement(&mut self) -> Result<ASTNode, VMError> {
+        let start = self.token_start;
+        self.eat(Token::Yield)?;
+        let value = Box::new(self.null_coalescing()?);
+        Ok(ASTNode::Yield {
+            value,
+            span: self.span_from(start),
+        })
+    }
+
+    fn defer_statement(&mut self) -> Result<ASTNode, VMError> {
+        self.eat(Token::Defer)?;
+        Ok(ASTNode::Defer(Box::new(self.null_coalescing()?)))
+    }
+
+    fn assert_statement(&mut self) -> Result<ASTNode, VMError> {
+        self.eat(Token::Assert)?;
+        let start = self.token_start;
+        let condition = Box::new(self.null_coalescing()?);
+        let span = self.span_from(start);
+        let text = self.tokenizer.input[start..self.previous_end].to_string();
+        let message = if self.current_token == Token::Comma {
+            self.eat(Token::Comma)?;
+            Some(Box::new(self.null_coalescing()?))
         } else {
-            Ok(ASTNode::VarRef(var_name))
+            None
+        };
+        Ok(ASTNode::Assert {
+            condition,
+            message,
+            text,
+            span,
+        })
+    }
+
+    fn statement(&mut self) -> Result<ASTNode, VMError> {
+        let statement = match self.current_token {
+            Token::If => self.if_statement()?,
+            Token::While => self.while_loop()?,
+            Token::For => self.for_loop()?,
+            Token::Fn => self.fn_declaration()?,
+            Token::Const => self.const_fn_declaration()?,
+            Token::Class => self.class_declaration()?,
+            Token::LBrace => ASTNode::Block(self.block()?),
+            _ => {
+                let statement = match self.current_token {
+                    Token::Return => self.return_statement()?,
+                    Token::Yield => self.yield_statement()?,
+                    Token::Defer => self.defer_statement()?,
+                    Token::Assert => self.assert_statement()?,
+                    _ => self.expression_statement()?,
+                };
+                self.end_statement()?;
+                return Ok(statement);
+            }
+        };
+        // Statements ending in a block need no separator, but may have one.
+        if self.current_token == Token::Semicolon {
+            self.eat(Token::Semicolon)?;
+        }
+        Ok(statement)
+    }
+
+    /// A simple statement ends at a `;`, a line break, a closing `}` or the
+    /// end of the input.
+    fn end_statement(&mut self) -> Result<(), VMError> {
+        match self.current_token {
+            Token::Semicolon => self.eat(Token::Semicolon),
+            Token::RBrace | Token::Eof => Ok(()),
+            _ if self.tokenizer.newline_before => Ok(()),
+            _ => Err(self.error(
+                "Expected ';' or a line break before the next statement",
+            )),
+        }
+    }
+
+    fn expression_statement(&mut self) -> Result<ASTNode, VMError> {
+        let start = self.token_start;
+        let target = self.null_coalescing()?;
+        let ty = match (&target, &self.current_token) {
+            (ASTNode::VarRef(_), Token::Colon) => {
+                self.eat(Token::Colon)?;
+                Some(self.type_annotation()?)
+            }
+            _ => None,
+        };
+        if ty.is_none() && self.current_token != Token::Assignment {
+            return Ok(target);
+        }
+        self.eat(Token::Assignment)?;
+        let value = Box::new(self.null_coalescing()?);
+        match target {
+            ASTNode::VarRef(name) => Ok(ASTNode::VarDecl {
+                name,
+                ty,
+                value,
+                span: self.span_from(start),
+            }),
+            ASTNode::Tuple(elements) => {
+                let names = elements
+                    .into_iter()
+                    .map(|element| match element {
+                        ASTNode::VarRef(name) => Ok(name),
+                        _ => Err(self.error("Only variables can be unpacked from a tuple")),
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(ASTNode::Destructure {
+                    names,
+                    value,
+                    span: self.span_from(start),
+                })
+            }
+            ASTNode::FieldAccess {
+                object,
+                field,
+                optional: false,
+            } => Ok(ASTNode::FieldAssign {
+                object,
+                field,
+                value,
+            }),
+            ASTNode::ArrayIndex {
+                array,
+                index,
+                optional: false,
+                ..
+            } => Ok(ASTNode::ArrayAssign {
+                array,
+                index,
+                value,
+                span: self.span_from(start),
+            }),
+            _ => Err(self.error("Invalid assignment target")),
         }
     }
 
@@ -246,22 +616,98 @@ impl Parser {
         Ok(ASTNode::Array(elements))
     }
     
-    fn array_index(&mut self, array: ASTNode) -> Result<ASTNode, VMError> {
-        self.eat(Token::LBracket)?;
+    fn array_index(&mut self, array: ASTNode, start: usize) -> Result<ASTNode, VMError> {
+        let optional = self.current_token == Token::QuestionBracket;
+        let token = self.current_token.clone();
+        self.eat(token)?;
         let index = self.expr()?;
         self.eat(Token::RBracket).map_err(|_| self.error("Expected closing bracket ']'"))?;
         
         Ok(ASTNode::ArrayIndex {
             array: Box::new(array),
             index: Box::new(index),
+            optional,
+            span: self.span_from(start),
         })
     }
 
     pub fn parse_program(&mut self) -> Result<Vec<ASTNode>, VMError> {
         let mut statements = Vec::new();
-        while self.current_token != Token::EOF {
+        while self.current_token != Token::Eof {
             statements.push(self.statement()?);
         }
         Ok(statements)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Vec<ASTNode> {
+        Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .unwrap_or_else(|error| panic!("{} failed to parse: {}", source, error))
+    }
+
+    /// The message and the source text of the span `source` fails to parse
+    /// with.
+    fn error(source: &str) -> (String, String) {
+        match Parser::new(Tokenizer::new(source.to_string())).and_then(|mut parser| parser.parse_program()) {
+            Ok(_) => panic!("{} parsed without error", source),
+            Err(VMError::ParseError { message, span, .. }) => {
+                let text = source[span.offset()..span.offset() + span.len()].to_string();
+                (message, text)
+            }
+            Err(error) => panic!("{} failed with {:?}", source, error),
+        }
+    }
+
+    const SEPARATOR: &str = "Expected ';' or a line break before the next statement";
+
+    #[test]
+    fn statements_on_one_line_need_a_semicolon() {
+        assert_eq!(error("a b c"), (SEPARATOR.to_string(), "b".to_string()));
+        assert_eq!(error("x = 1 y = 2"), (SEPARATOR.to_string(), "y".to_string()));
+        assert_eq!(parse("x = 1; y = 2;").len(), 2);
+        assert_eq!(parse("x = 1\ny = 2\n").len(), 2);
+        assert_eq!(parse("if (1) { x = 1 } y = 2").len(), 2);
+        assert_eq!(parse("{ x = 1 }").len(), 1);
+    }
+
+    #[test]
+    fn a_line_starting_with_a_bracket_is_a_new_statement() {
+        assert!(matches!(&parse("f = g\n(1)")[..], [ASTNode::VarDecl { .. }, ASTNode::Number(1)]));
+        assert!(matches!(&parse("f = g(1)")[..], [ASTNode::VarDecl { .. }]));
+        assert!(matches!(&parse("a = b\n[1]")[..], [ASTNode::VarDecl { .. }, ASTNode::Array(_)]));
+    }
+
+    #[test]
+    fn a_bare_return_ends_at_the_line_break() {
+        let nodes = parse("fn f() {\n  return\n  g()\n}");
+        let [ASTNode::FnDecl { body, .. }] = &nodes[..] else {
+            panic!("expected one function, got {:?}", nodes);
+        };
+        assert!(matches!(&body[..], [ASTNode::Return { value: None, .. }, ASTNode::Call { .. }]));
+        assert_eq!(
+            error("fn f() { return 1 2 }"),
+            (SEPARATOR.to_string(), "2".to_string())
+        );
+    }
+
+    #[test]
+    fn a_malformed_first_token_is_an_error() {
+        for (source, expected) in [
+            ("99999999999", "Number literal 99999999999 does not fit in an int"),
+            ("\"abc", "Unterminated string literal"),
+        ] {
+            match Parser::new(Tokenizer::new(source.to_string())) {
+                Err(VMError::TokenizationError { message, span, .. }) => {
+                    assert_eq!(message, expected);
+                    assert_eq!(&source[span.offset()..span.offset() + span.len()], source);
+                }
+                other => panic!("{} failed with {:?}", source, other.map(|_| ())),
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::vm::{address, map_address, Instruction};
+
+/// Rewrites wasteful instruction sequences in finished code: jumps to jumps
+/// go straight to the final target, jumps to the next instruction are
+/// dropped and a store followed by a load of the same variable becomes a
+/// single store that keeps the value. Code addresses, including function
+/// entry points, are remapped to match.
+pub fn optimize(mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+    loop {
+        thread_jumps(&mut instructions);
+        let len = instructions.len();
+        instructions = rewrite(instructions);
+        if instructions.len() == len {
+            return instructions;
+        }
+    }
+}
+
+/// Where control really ends up when it reaches `target`.
+fn final_target(instructions: &[Instruction], mut target: usize) -> usize {
+    // A cycle of jumps never settles, so give up after visiting every one.
+    for _ in 0..instructions.len() {
+        match instructions.get(target) {
+            Some(Instruction::Jmp(next)) if *next != target => target = *next,
+            _ => break,
+        }
+    }
+    target
+}
+
+fn thread_jumps(instructions: &mut [Instruction]) {
+    let targets: Vec<Option<usize>> = instructions
+        .iter()
+        .map(|instruction| address(instruction).map(|target| final_target(instructions, target)))
+        .collect();
+    for (instruction, target) in instructions.iter_mut().zip(targets) {
+        if let Some(target) = target {
+            map_address(instruction, |_| target);
+        }
+    }
+}
+
+fn rewrite(mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let len = instructions.len();
+    let mut is_target = vec![false; len + 1];
+    for target in instructions.iter().filter_map(address) {
+        if let Some(is_target) = is_target.get_mut(target) {
+            *is_target = true;
+        }
+    }
+
+    let mut keep = vec![true; len];
+    let mut i = 0;
+    while i < len {
+        match (&instructions[i], instructions.get(i + 1)) {
+            (Instruction::Jmp(target), _) if *target == i + 1 => keep[i] = false,
+            (Instruction::StoreLocal(slot), Some(Instruction::LoadLocal(loaded)))
+                if slot == loaded && !is_target[i + 1] =>
+            {
+                instructions[i] = Instruction::TeeLocal(*slot);
+                keep[i + 1] = false;
+                i += 1;
+            }
+            (Instruction::StoreGlobal(index), Some(Instruction::LoadGlobal(loaded)))
+                if index == loaded && !is_target[i + 1] =>
+            {
+                instructions[i] = Instruction::TeeGlobal(*index);
+                keep[i + 1] = false;
+                i += 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    // An address of a dropped instruction now means the one after it.
+    let mut new_index = Vec::with_capacity(len + 1);
+    let mut kept = 0;
+    for &keep in &keep {
+        new_index.push(kept);
+        kept += keep as usize;
+    }
+    new_index.push(kept);
+
+    instructions
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(mut instruction, _)| {
+            map_address(&mut instruction, |target| new_index.get(target).copied().unwrap_or(target));
+            instruction
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+    use crate::types::Value;
+    use crate::vm::{Compiler, VM};
+
+    fn debug(instructions: &[Instruction]) -> String {
+        format!("{:?}", instructions)
+    }
+
+    /// Runs `source` and describes the final stack and globals, along with
+    /// the size of the compiled code. Functions, classes and objects print
+    /// code addresses and hash maps, so only plain values are described.
+    fn run(source: &str, peephole: bool) -> (String, usize) {
+        let nodes = Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .expect("test program parses");
+        let program = Compiler::default()
+            .with_peephole(peephole)
+            .compile_program(nodes)
+            .expect("test program compiles");
+        let mut vm = VM::new();
+        let outcome = match vm.execute(&program) {
+            Ok(()) => {
+                let globals: Vec<_> = vm
+                    .global_values(&program)
+                    .into_iter()
+                    .filter(|(_, value)| {
+                        !matches!(
+                            value,
+                            Value::Function(_) | Value::Class(_) | Value::Instance(_) | Value::Generator(_)
+                        )
+                    })
+                    .collect();
+                format!("{:?} {:?}", vm.stack, globals)
+            }
+            Err(error) => format!("error: {}", error),
+        };
+        (outcome, program.instructions.len())
+    }
+
+    fn assert_unchanged(source: &str) -> (usize, usize) {
+        let (plain, plain_len) = run(source, false);
+        let (optimized, optimized_len) = run(source, true);
+        assert_eq!(plain, optimized, "behaviour changed for:\n{}", source);
+        (plain_len, optimized_len)
+    }
+
+    #[test]
+    fn drops_jump_to_next_instruction() {
+        let optimized = optimize(vec![
+            Instruction::Push(Value::Number(1)),
+            Instruction::Jz(3),
+            Instruction::Jmp(3),
+            Instruction::Push(Value::Number(2)),
+        ]);
+        assert_eq!(
+            debug(&optimized),
+            debug(&[
+                Instruction::Push(Value::Number(1)),
+                Instruction::Jz(2),
+                Instruction::Push(Value::Number(2)),
+            ])
+        );
+    }
+
+    #[test]
+    fn merges_store_and_load_of_same_variable() {
+        let optimized = optimize(vec![
+            Instruction::Push(Value::Number(1)),
+            Instruction::StoreGlobal(0),
+            Instruction::LoadGlobal(0),
+            Instruction::StoreLocal(2),
+            Instruction::LoadLocal(3),
+        ]);
+        assert_eq!(
+            debug(&optimized),
+            debug(&[
+                Instruction::Push(Value::Number(1)),
+                Instruction::TeeGlobal(0),
+                Instruction::StoreLocal(2),
+                Instruction::LoadLocal(3),
+            ])
+        );
+    }
+
+    #[test]
+    fn keeps_load_that_is_a_jump_target() {
+        let instructions = vec![
+            Instruction::Jmp(2),
+            Instruction::StoreLocal(0),
+            Instruction::LoadLocal(0),
+        ];
+        assert_eq!(debug(&optimize(instructions.clone())), debug(&instructions));
+    }
+
+    #[test]
+    fn threads_jump_chains() {
+        let optimized = optimize(vec![
+            Instruction::Jz(2),
+            Instruction::Push(Value::Null),
+            Instruction::Jmp(4),
+            Instruction::Push(Value::Null),
+            Instruction::Jmp(6),
+            Instruction::Push(Value::Null),
+            Instruction::Pop,
+        ]);
+        assert_eq!(
+            debug(&optimized),
+            debug(&[
+                Instruction::Jz(6),
+                Instruction::Push(Value::Null),
+                Instruction::Jmp(6),
+                Instruction::Push(Value::Null),
+                Instruction::Jmp(6),
+                Instruction::Push(Value::Null),
+                Instruction::Pop,
+            ])
+        );
+    }
+
+    #[test]
+    fn jump_cycles_stay_cycles() {
+        let optimized = optimize(vec![Instruction::Jmp(1), Instruction::Jmp(0)]);
+        assert_eq!(debug(&optimized), debug(&[Instruction::Jmp(0)]));
+    }
+
+    #[test]
+    fn if_without_else_gets_shorter() {
+        let (plain, optimized) = assert_unchanged("x = 1\nif (x > 0) { x = x + 1 }\ny = x");
+        assert!(optimized < plain);
+    }
+
+    #[test]
+    fn nested_conditionals_behave_the_same() {
+        assert_unchanged(
+            "n = 0
+             i = 0
+             while (i < 10) {
+                 if (i > 5) { if (i > 7) { n = n + 100 } else { n = n + 10 } } else { n = n + 1 }
+                 i = i + 1
+             }",
+        );
+    }
+
+    #[test]
+    fn functions_and_generators_behave_the_same() {
+        assert_unchanged(
+            "fn pick(a, b) { if (a > b) { return a } return b }
+             fn* upto(n) { i = 0; while (i < n) { yield i; i = i + 1 } }
+             total = 0
+             for v in upto(5) { total = total + pick(v, 2) }
+             fn fact(n) { if (n < 2) { return 1 } return n * fact(n - 1) }
+             f = fact(6)",
+        );
+    }
+
+    #[test]
+    fn blocks_defers_and_classes_behave_the_same() {
+        assert_unchanged(
+            "class Log {
+                 fn init(self) { self.s = \"\" }
+                 fn add(self, x) { self.s = self.s + x; return self }
+             }
+             log = Log()
+             fn work(log) { defer log.add(\"a\"); { k = 1; defer log.add(\"b\"); if (k > 0) { log.add(\"c\") } } return 5 }
+             r = work(log)
+             s = log.s
+             arr = [1, 2, 3]
+             arr[1] = 20
+             { t = arr[1]; u = t }",
+        );
+    }
+
+    #[test]
+    fn runtime_errors_are_unchanged() {
+        assert_unchanged("x = 1\nif (x > 0) { y = x - \"s\" }");
+    }
+}
@@ -0,0 +1,1127 @@
+use crate::ast::ASTNode;
+use crate::error::VMError;
+use crate::tokenizer::Token;
+use crate::types::{Function, VMBinaryOp, VMCompare, Value};
+use crate::vm::{produces_value, ConstantKey};
+use miette::SourceSpan;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// A register of the current frame's window.
+pub type Reg = u16;
+
+#[derive(Debug, Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Greater,
+    Less,
+    Equal,
+    NotEqual,
+}
+
+/// An instruction of the register engine. Operands name registers of the
+/// current frame, so an `Add` reads its operands and writes its result in
+/// place instead of going through a stack.
+#[derive(Debug, Clone)]
+pub enum RegInstruction {
+    /// Sizes the register window of the frame being entered. Every function,
+    /// and the program itself, starts with one.
+    Frame(u16),
+    LoadConst { dst: Reg, constant: u32 },
+    Move { dst: Reg, src: Reg },
+    LoadGlobal { dst: Reg, global: u32 },
+    StoreGlobal { global: u32, src: Reg },
+    Binary { op: BinaryOp, dst: Reg, left: Reg, right: Reg },
+    Jmp(usize),
+    JumpIfFalse { cond: Reg, target: usize },
+    /// Collects `count` registers starting at `start` into a new array.
+    NewArray { dst: Reg, start: Reg, count: u16 },
+    GetIndex { dst: Reg, array: Reg, index: Reg },
+    /// Updates an element of the array held in `array` in place.
+    SetIndex { array: Reg, index: Reg, value: Reg },
+    Len { dst: Reg, src: Reg },
+    /// Writes the element of `iterable` under `cursor` to `dst` and advances
+    /// the cursor, or jumps to `exit` when there are no elements left.
+    ForIter { iterable: Reg, cursor: Reg, dst: Reg, exit: usize },
+    /// Calls `callee` with the `argc` registers starting at `args` and
+    /// writes its result to `dst` once it returns.
+    Call { dst: Reg, callee: Reg, args: Reg, argc: u16 },
+    Return(Reg),
+    Assert {
+        cond: Reg,
+        message: Option<Reg>,
+        text: String,
+        span: SourceSpan,
+    },
+    /// Keeps the value of a top-level expression statement, as the stack
+    /// engine leaves it on its stack.
+    Keep(Reg),
+}
+
+/// Code for the register engine. Function bodies come first; the program
+/// itself starts at `entry` and runs to the end of the code.
+#[derive(Debug, Clone, Default)]
+pub struct RegisterProgram {
+    pub instructions: Vec<RegInstruction>,
+    pub entry: usize,
+    pub constants: Vec<Value>,
+    pub globals: Vec<String>,
+    /// The top-level variables that live in registers of the program's
+    /// frame rather than in the globals table.
+    pub variables: Vec<(String, Reg)>,
+}
+
+/// The names of one open block.
+#[derive(Debug, Default)]
+struct Block {
+    names: HashMap<String, Reg>,
+    /// Registers set aside when the block opened for the names it assigns,
+    /// which only become visible at their first assignment.
+    reserved: HashMap<String, Reg>,
+    /// The first register of the block, freed again when it closes.
+    start: Reg,
+}
+
+/// The registers of the function being compiled, or of the top level.
+#[derive(Debug, Default)]
+struct RegisterScope {
+    /// Open blocks, innermost last. The first holds a function's parameters
+    /// and the variables of its body, or the top level's names outside any
+    /// block.
+    blocks: Vec<Block>,
+    is_main: bool,
+    /// The next free register; temporaries are allocated and freed above it.
+    next: Reg,
+    /// The size of the register window.
+    size: Reg,
+}
+
+/// Where a variable lives at run time.
+#[derive(Debug, Clone, Copy)]
+enum Resolved {
+    Register(Reg),
+    Global(u32),
+}
+
+/// A compiled function body, waiting to be laid out in front of the
+/// program. Its jump targets are relative to its first instruction.
+#[derive(Debug)]
+struct PendingFunction {
+    name: String,
+    params: Vec<String>,
+    code: Vec<RegInstruction>,
+    constant: u32,
+}
+
+/// Turns the AST into register-engine code. It covers the core of the
+/// language: numbers, strings, arrays, variables, control flow and plain
+/// functions. Anything else is reported as unsupported.
+#[derive(Debug)]
+pub struct RegisterCompiler {
+    /// Whether `assert` statements are compiled in.
+    asserts: bool,
+    code: Vec<RegInstruction>,
+    scope: RegisterScope,
+    functions: Vec<PendingFunction>,
+    constants: Vec<Value>,
+    constant_indices: HashMap<ConstantKey, u32>,
+    globals: Vec<String>,
+    global_indices: HashMap<String, u32>,
+    /// Names read from inside a function. Top-level variables of these names
+    /// are kept in the globals table so that functions can see them.
+    shared: HashSet<String>,
+    variables: Vec<(String, Reg)>,
+    errors: Vec<VMError>,
+}
+
+impl Default for RegisterCompiler {
+    fn default() -> Self {
+        RegisterCompiler {
+            asserts: true,
+            code: Vec::new(),
+            scope: RegisterScope::default(),
+            functions: Vec::new(),
+            constants: Vec::new(),
+            constant_indices: HashMap::new(),
+            globals: Vec::new(),
+            global_indices: HashMap::new(),
+            shared: HashSet::new(),
+            variables: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+/// The names assigned directly in a block: by assignments, loops and
+/// function declarations, including inside `if` and loop bodies but not in
+/// nested blocks, which have their own.
+fn assigned_names<'a>(nodes: &'a [ASTNode], names: &mut Vec<&'a str>) {
+    for node in nodes {
+        match node {
+            ASTNode::VarDecl { name, .. } | ASTNode::FnDecl { name, .. } => {
+                names.push(name)
+            }
+            ASTNode::For { var, body, .. } => {
+                names.push(var);
+                assigned_names(body, names);
+            }
+            ASTNode::If {
+                if_block,
+                else_block,
+                ..
+            } => {
+                assigned_names(if_block, names);
+                assigned_names(else_block, names);
+            }
+            ASTNode::While { body, .. } => assigned_names(body, names),
+            _ => {}
+        }
+    }
+}
+
+/// Collects the names read anywhere inside function bodies.
+fn function_reads(node: &ASTNode, in_function: bool, reads: &mut HashSet<String>) {
+    let mut visit = |nodes: &[ASTNode], in_function: bool| {
+        for node in nodes {
+            function_reads(node, in_function, reads);
+        }
+    };
+    match node {
+        ASTNode::VarRef(name) if in_function => {
+            reads.insert(name.clone());
+        }
+        ASTNode::FnDecl { body, .. } => visit(body, true),
+        ASTNode::BinOp { left, right, .. } => {
+            visit(std::slice::from_ref(left), in_function);
+            visit(std::slice::from_ref(right), in_function);
+        }
+        ASTNode::If {
+            condition,
+            if_block,
+            else_block,
+        } => {
+            visit(std::slice::from_ref(condition), in_function);
+            visit(if_block, in_function);
+            visit(else_block, in_function);
+        }
+        ASTNode::While { condition, body } => {
+            visit(std::slice::from_ref(condition), in_function);
+            visit(body, in_function);
+        }
+        ASTNode::For { iterable, body, .. } => {
+            visit(std::slice::from_ref(iterable), in_function);
+            visit(body, in_function);
+        }
+        ASTNode::VarDecl { value, .. } => {
+            visit(std::slice::from_ref(value), in_function)
+        }
+        ASTNode::Block(nodes) | ASTNode::Array(nodes) => visit(nodes, in_function),
+        ASTNode::ArrayIndex { array, index, .. } => {
+            visit(std::slice::from_ref(array), in_function);
+            visit(std::slice::from_ref(index), in_function);
+        }
+        ASTNode::ArrayAssign {
+            array, index, value, ..
+        } => {
+            visit(std::slice::from_ref(array), in_function);
+            visit(std::slice::from_ref(index), in_function);
+            visit(std::slice::from_ref(value), in_function);
+        }
+        ASTNode::Call { callee, args, .. } => {
+            visit(std::slice::from_ref(callee), in_function);
+            visit(args, in_function);
+        }
+        ASTNode::MethodCall { object, args, .. } => {
+            visit(std::slice::from_ref(object), in_function);
+            visit(args, in_function);
+        }
+        ASTNode::Return { value: Some(value), .. } => visit(std::slice::from_ref(value), in_function),
+        ASTNode::Assert {
+            condition, message, ..
+        } => {
+            visit(std::slice::from_ref(condition), in_function);
+            if let Some(message) = message {
+                visit(std::slice::from_ref(message), in_function);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Moves code laid out from address 0 to start at `offset`.
+fn relocate(code: Vec<RegInstruction>, offset: usize) -> impl Iterator<Item = RegInstruction> {
+    code.into_iter().map(move |mut instruction| {
+        match &mut instruction {
+            RegInstruction::Jmp(target)
+            | RegInstruction::JumpIfFalse { target, .. }
+            | RegInstruction::ForIter { exit: target, .. } => *target += offset,
+            _ => {}
+        }
+        instruction
+    })
+}
+
+/// What a node the register engine cannot compile is called in errors.
+fn unsupported_feature(node: &ASTNode) -> &'static str {
+    match node {
+        ASTNode::Tuple(_) => "tuples",
+        ASTNode::Destructure { .. } => "destructuring",
+        ASTNode::ClassDecl { .. } => "classes",
+        ASTNode::FieldAccess { .. } | ASTNode::FieldAssign { .. } => "fields",
+        ASTNode::NullCoalesce { .. } => "`??`",
+        ASTNode::ArrayIndex { optional: true, .. } | ASTNode::MethodCall { optional: true, .. } => {
+            "optional chaining"
+        }
+        ASTNode::MethodCall { .. } => "method calls other than `len()`",
+        ASTNode::Yield { .. } | ASTNode::FnDecl { is_generator: true, .. } => "generators",
+        ASTNode::Comptime { .. } => "`comptime` blocks",
+        ASTNode::Defer(_) => "`defer`",
+        ASTNode::ArrayAssign { .. } => "assigning to elements of this expression",
+        _ => "this construct",
+    }
+}
+
+impl RegisterCompiler {
+    pub fn with_asserts(mut self, asserts: bool) -> Self {
+        self.asserts = asserts;
+        self
+    }
+
+    fn unsupported(&mut self, node: &ASTNode) {
+        self.errors.push(VMError::Unsupported {
+            feature: unsupported_feature(node),
+        });
+    }
+
+    fn constant(&mut self, value: Value) -> u32 {
+        let key = ConstantKey::of(&value);
+        if let Some(&index) = key.as_ref().and_then(|key| self.constant_indices.get(key)) {
+            return index;
+        }
+        let index = self.constants.len() as u32;
+        self.constants.push(value);
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+        index
+    }
+
+    fn global(&mut self, name: &str) -> u32 {
+        if let Some(&index) = self.global_indices.get(name) {
+            return index;
+        }
+        let index = self.globals.len() as u32;
+        self.globals.push(name.to_string());
+        self.global_indices.insert(name.to_string(), index);
+        index
+    }
+
+    /// Allocates a register above everything in use.
+    fn temp(&mut self) -> Reg {
+        let Some(next) = self.scope.next.checked_add(1) else {
+            self.errors.push(VMError::TypeError {
+                message: "Too many registers in one function".to_string(),
+            });
+            return Reg::MAX;
+        };
+        let reg = std::mem::replace(&mut self.scope.next, next);
+        self.scope.size = self.scope.size.max(next);
+        reg
+    }
+
+    /// Frees every register allocated since `mark`.
+    fn free(&mut self, mark: Reg) {
+        self.scope.next = mark;
+    }
+
+    /// Opens a block, reserving registers for the names it assigns.
+    fn open_block(&mut self, nodes: &[ASTNode], mut block: Block) {
+        block.start = self.scope.next;
+        let is_root = self.scope.is_main && self.scope.blocks.is_empty();
+        let mut names = Vec::new();
+        assigned_names(nodes, &mut names);
+        for name in names {
+            if block.names.contains_key(name)
+                || block.reserved.contains_key(name)
+                || (is_root && self.shared.contains(name))
+            {
+                continue;
+            }
+            let reg = self.temp();
+            block.reserved.insert(name.to_string(), reg);
+        }
+        self.scope.blocks.push(block);
+    }
+
+    fn close_block(&mut self) {
+        if let Some(block) = self.scope.blocks.pop() {
+            self.free(block.start);
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> Resolved {
+        let local = self
+            .scope
+            .blocks
+            .iter()
+            .rev()
+            .find_map(|block| block.names.get(name).copied());
+        match local {
+            Some(reg) => Resolved::Register(reg),
+            None => Resolved::Global(self.global(name)),
+        }
+    }
+
+    /// Finds the variable an assignment to `name` writes: the innermost block
+    /// declares it if it does not yet. Top-level names that functions read
+    /// are globals.
+    fn declare(&mut self, name: &str) -> Resolved {
+        let is_root = self.scope.is_main && self.scope.blocks.len() == 1;
+        if is_root && self.shared.contains(name) {
+            return Resolved::Global(self.global(name));
+        }
+        let Some(block) = self.scope.blocks.last_mut() else {
+            return Resolved::Global(self.global(name));
+        };
+        if let Some(&reg) = block.names.get(name) {
+            return Resolved::Register(reg);
+        }
+        let reg = match block.reserved.get(name) {
+            Some(&reg) => reg,
+            None => self.temp(),
+        };
+        if let Some(block) = self.scope.blocks.last_mut() {
+            block.names.insert(name.to_string(), reg);
+        }
+        if is_root {
+            self.variables.push((name.to_string(), reg));
+        }
+        Resolved::Register(reg)
+    }
+
+    /// Compiles `value` and assigns it to `name`.
+    fn assign(&mut self, name: &str, value: &ASTNode) {
+        match self.declare(name) {
+            Resolved::Register(reg) => self.expr(value, reg),
+            Resolved::Global(global) => {
+                let mark = self.scope.next;
+                let src = self.temp();
+                self.expr(value, src);
+                self.code.push(RegInstruction::StoreGlobal { global, src });
+                self.free(mark);
+            }
+        }
+    }
+
+    /// The register holding the value of `node`: a variable's own register,
+    /// or a new temporary.
+    fn operand(&mut self, node: &ASTNode) -> Reg {
+        if let ASTNode::VarRef(name) = node {
+            if let Resolved::Register(reg) = self.resolve(name) {
+                return reg;
+            }
+        }
+        let reg = self.temp();
+        self.expr(node, reg);
+        reg
+    }
+
+    /// Compiles an expression that leaves its value in `dst`. Only the last
+    /// instruction writes `dst`, so the expression may still read a variable
+    /// that lives there.
+    fn expr(&mut self, node: &ASTNode, dst: Reg) {
+        let mark = self.scope.next;
+        match node {
+            ASTNode::Number(n) => self.load_const(dst, Value::Number(*n)),
+            ASTNode::String(s) => self.load_const(dst, Value::String(s.as_str().into())),
+            ASTNode::Bytes(bytes) => self.load_const(dst, Value::Bytes(bytes.clone())),
+            ASTNode::Null => self.load_const(dst, Value::Null),
+            ASTNode::VarRef(name) => match self.resolve(name) {
+                Resolved::Register(src) if src == dst => {}
+                Resolved::Register(src) => self.code.push(RegInstruction::Move { dst, src }),
+                Resolved::Global(global) => self.code.push(RegInstruction::LoadGlobal { dst, global }),
+            },
+            ASTNode::BinOp {
+                left, op, right, ..
+            } => {
+                let op = match op {
+                    Token::Plus => BinaryOp::Add,
+                    Token::Minus => BinaryOp::Sub,
+                    Token::Star => BinaryOp::Mul,
+                    Token::Slash => BinaryOp::Div,
+                    Token::Greater => BinaryOp::Greater,
+                    Token::Less => BinaryOp::Less,
+                    Token::Equal => BinaryOp::Equal,
+                    Token::NotEqual => BinaryOp::NotEqual,
+                    _ => return self.unsupported(node),
+                };
+                let left = self.operand(left);
+                let right = self.operand(right);
+                self.code.push(RegInstruction::Binary { op, dst, left, right });
+            }
+            ASTNode::Array(elements) => {
+                let start = self.scope.next;
+                let registers: Vec<Reg> = elements.iter().map(|_| self.temp()).collect();
+                for (element, reg) in elements.iter().zip(registers) {
+                    self.expr(element, reg);
+                }
+                let count = elements.len() as u16;
+                self.code.push(RegInstruction::NewArray { dst, start, count });
+            }
+            ASTNode::ArrayIndex {
+                array,
+                index,
+                optional: false,
+                ..
+            } => {
+                let array = self.operand(array);
+                let index = self.operand(index);
+                self.code.push(RegInstruction::GetIndex { dst, array, index });
+            }
+            ASTNode::Call { callee, args, .. } => {
+                let callee = self.operand(callee);
+                let start = self.scope.next;
+                let registers: Vec<Reg> = args.iter().map(|_| self.temp()).collect();
+                for (arg, reg) in args.iter().zip(registers) {
+                    self.expr(arg, reg);
+                }
+                let argc = args.len() as u16;
+                self.code.push(RegInstruction::Call {
+                    dst,
+                    callee,
+                    args: start,
+                    argc,
+                });
+            }
+            ASTNode::MethodCall {
+                object,
+                method,
+                args,
+                optional: false,
+            } if method == "len" && args.is_empty() => {
+                let src = self.operand(object);
+                self.code.push(RegInstruction::Len { dst, src });
+            }
+            _ => self.unsupported(node),
+        }
+        self.free(mark);
+    }
+
+    fn load_const(&mut self, dst: Reg, value: Value) {
+        let constant = self.constant(value);
+        self.code.push(RegInstruction::LoadConst { dst, constant });
+    }
+
+    /// Emits a jump whose target is patched later.
+    fn jump_placeholder(&mut self, instruction: RegInstruction) -> usize {
+        self.code.push(instruction);
+        self.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the end of the code so far.
+    fn patch(&mut self, at: usize) {
+        let here = self.code.len();
+        match &mut self.code[at] {
+            RegInstruction::Jmp(target)
+            | RegInstruction::JumpIfFalse { target, .. }
+            | RegInstruction::ForIter { exit: target, .. } => *target = here,
+            _ => {}
+        }
+    }
+
+    fn body(&mut self, nodes: &[ASTNode]) {
+        for node in nodes {
+            self.statement(node);
+        }
+    }
+
+    fn statement(&mut self, node: &ASTNode) {
+        let mark = self.scope.next;
+        match node {
+            ASTNode::VarDecl { name, value, .. } => self.assign(name, value),
+            ASTNode::If {
+                condition,
+                if_block,
+                else_block,
+            } => {
+                let cond = self.operand(condition);
+                self.free(mark);
+                let to_else = self.jump_placeholder(RegInstruction::JumpIfFalse { cond, target: 0 });
+                self.body(if_block);
+                if else_block.is_empty() {
+                    self.patch(to_else);
+                } else {
+                    let to_end = self.jump_placeholder(RegInstruction::Jmp(0));
+                    self.patch(to_else);
+                    self.body(else_block);
+                    self.patch(to_end);
+                }
+            }
+            ASTNode::While { condition, body } => {
+                let start = self.code.len();
+                let cond = self.operand(condition);
+                self.free(mark);
+                let to_end = self.jump_placeholder(RegInstruction::JumpIfFalse { cond, target: 0 });
+                self.body(body);
+                self.code.push(RegInstruction::Jmp(start));
+                self.patch(to_end);
+            }
+            ASTNode::For {
+                var,
+                iterable: iterable_node,
+                body,
+            } => {
+                // The iterable and a cursor stay in registers for the whole loop.
+                let iterable = self.temp();
+                self.expr(iterable_node, iterable);
+                let cursor = self.temp();
+                self.load_const(cursor, Value::Number(0));
+                let (dst, global) = match self.declare(var) {
+                    Resolved::Register(reg) => (reg, None),
+                    Resolved::Global(global) => (self.temp(), Some(global)),
+                };
+                let start = self.jump_placeholder(RegInstruction::ForIter {
+                    iterable,
+                    cursor,
+                    dst,
+                    exit: 0,
+                });
+                if let Some(global) = global {
+                    self.code.push(RegInstruction::StoreGlobal { global, src: dst });
+                }
+                self.body(body);
+                self.code.push(RegInstruction::Jmp(start));
+                self.patch(start);
+            }
+            ASTNode::Block(nodes) => {
+                self.open_block(nodes, Block::default());
+                self.body(nodes);
+                self.close_block();
+            }
+            ASTNode::ArrayAssign {
+                array, index, value, ..
+            } => {
+                let ASTNode::VarRef(name) = array.as_ref() else {
+                    return self.unsupported(node);
+                };
+                let (array, global) = match self.resolve(name) {
+                    Resolved::Register(reg) => (reg, None),
+                    Resolved::Global(global) => {
+                        let reg = self.temp();
+                        self.code.push(RegInstruction::LoadGlobal { dst: reg, global });
+                        (reg, Some(global))
+                    }
+                };
+                let index = self.operand(index);
+                let value = self.operand(value);
+                self.code.push(RegInstruction::SetIndex { array, index, value });
+                if let Some(global) = global {
+                    self.code.push(RegInstruction::StoreGlobal { global, src: array });
+                }
+            }
+            ASTNode::FnDecl {
+                name,
+                params,
+                body,
+                is_generator: false,
+                ..
+            } => {
+                let params: Vec<String> = params.iter().map(|param| param.name.clone()).collect();
+                let constant = self.function(name, params, body);
+                match self.declare(name) {
+                    Resolved::Register(dst) => self.code.push(RegInstruction::LoadConst { dst, constant }),
+                    Resolved::Global(global) => {
+                        let src = self.temp();
+                        self.code.push(RegInstruction::LoadConst { dst: src, constant });
+                        self.code.push(RegInstruction::StoreGlobal { global, src });
+                    }
+                }
+            }
+            ASTNode::Return { value, span } => {
+                if self.scope.is_main {
+                    self.errors.push(VMError::CompileError {
+                        message: "Return outside of a function".to_string(),
+                        span: *span,
+                    });
+                }
+                let reg = match value {
+                    Some(value) => self.operand(value),
+                    None => {
+                        let reg = self.temp();
+                        self.load_const(reg, Value::Null);
+                        reg
+                    }
+                };
+                self.code.push(RegInstruction::Return(reg));
+            }
+            ASTNode::Assert {
+                condition,
+                message,
+                text,
+                span,
+            } => {
+                if self.asserts {
+                    let cond = self.operand(condition);
+                    let message = message.as_ref().map(|message| self.operand(message));
+                    self.code.push(RegInstruction::Assert {
+                        cond,
+                        message,
+                        text: text.clone(),
+                        span: *span,
+                    });
+                }
+            }
+            _ if produces_value(node) => {
+                let reg = self.operand(node);
+                if self.scope.is_main && self.scope.blocks.len() == 1 {
+                    self.code.push(RegInstruction::Keep(reg));
+                }
+            }
+            _ => self.unsupported(node),
+        }
+        self.free(mark);
+    }
+
+    /// Compiles a function body on its own and returns the constant that
+    /// will hold the function once the code is laid out.
+    fn function(&mut self, name: &str, params: Vec<String>, body: &[ASTNode]) -> u32 {
+        let code = std::mem::replace(&mut self.code, vec![RegInstruction::Frame(0)]);
+        let scope = std::mem::take(&mut self.scope);
+        let mut block = Block::default();
+        for param in &params {
+            let reg = self.temp();
+            block.names.insert(param.clone(), reg);
+        }
+        self.open_block(body, block);
+        self.body(body);
+        let null = self.temp();
+        self.load_const(null, Value::Null);
+        self.code.push(RegInstruction::Return(null));
+        self.code[0] = RegInstruction::Frame(self.scope.size);
+
+        let code = std::mem::replace(&mut self.code, code);
+        self.scope = scope;
+        // Each function gets a pool entry of its own, filled in by `finish`.
+        let constant = self.constants.len() as u32;
+        self.constants.push(Value::Null);
+        self.functions.push(PendingFunction {
+            name: name.to_string(),
+            params,
+            code,
+            constant,
+        });
+        constant
+    }
+
+    pub fn compile_program(&mut self, nodes: Vec<ASTNode>) -> Result<RegisterProgram, VMError> {
+        for node in &nodes {
+            function_reads(node, false, &mut self.shared);
+        }
+        self.scope = RegisterScope {
+            is_main: true,
+            ..RegisterScope::default()
+        };
+        self.code = vec![RegInstruction::Frame(0)];
+        self.open_block(&nodes, Block::default());
+        self.body(&nodes);
+        self.code[0] = RegInstruction::Frame(self.scope.size);
+
+        let mut errors = std::mem::take(&mut self.errors);
+        match errors.len() {
+            0 => Ok(self.finish()),
+            1 => Err(errors.remove(0)),
+            _ => Err(VMError::CompileFailed { errors }),
+        }
+    }
+
+    /// Lays out the function bodies in front of the program and fills in
+    /// their constants now that their entry points are known.
+    fn finish(&mut self) -> RegisterProgram {
+        let mut instructions = Vec::new();
+        let mut constants = std::mem::take(&mut self.constants);
+        for function in std::mem::take(&mut self.functions) {
+            let entry = instructions.len();
+            instructions.extend(relocate(function.code, entry));
+            constants[function.constant as usize] = Value::Function(Rc::new(Function {
+                name: function.name,
+                params: function.params,
+                entry,
+                is_generator: false,
+            }));
+        }
+        let entry = instructions.len();
+        instructions.extend(relocate(std::mem::take(&mut self.code), entry));
+        RegisterProgram {
+            instructions,
+            entry,
+            constants,
+            globals: self.globals.clone(),
+            variables: std::mem::take(&mut self.variables),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RegisterFrame {
+    return_ip: usize,
+    /// The caller's register window.
+    base: usize,
+    /// The caller's register the result goes to.
+    dst: Reg,
+}
+
+/// Runs register-engine code. Each call gets a window of the register file
+/// above its caller's.
+#[derive(Debug)]
+pub struct RegisterVM {
+    registers: Vec<Value>,
+    frames: Vec<RegisterFrame>,
+    pub globals: Vec<Option<Value>>,
+    /// The values of top-level expression statements.
+    pub results: Vec<Value>,
+    max_call_depth: usize,
+}
+
+impl RegisterVM {
+    pub fn new() -> Self {
+        RegisterVM {
+            registers: Vec::new(),
+            frames: Vec::new(),
+            globals: Vec::new(),
+            results: Vec::new(),
+            max_call_depth: 1000,
+        }
+    }
+
+    /// Pairs each top-level variable that has been assigned with its name.
+    pub fn variables<'a>(&'a self, program: &'a RegisterProgram) -> Vec<(&'a str, &'a Value)> {
+        let globals = program
+            .globals
+            .iter()
+            .zip(&self.globals)
+            .filter_map(|(name, value)| Some((name.as_str(), value.as_ref()?)));
+        let registers = program
+            .variables
+            .iter()
+            .filter_map(|(name, reg)| Some((name.as_str(), self.registers.get(*reg as usize)?)));
+        globals.chain(registers).collect()
+    }
+
+    fn check_array_bounds(idx: i32, len: usize) -> Result<usize, VMError> {
+        if idx < 0 || idx as usize >= len {
+            return Err(VMError::IndexError { index: idx, len });
+        }
+        Ok(idx as usize)
+    }
+
+    pub fn execute(&mut self, program: &RegisterProgram) -> Result<(), VMError> {
+        let code = &program.instructions;
+        self.globals.resize(program.globals.len(), None);
+        let mut ip = program.entry;
+        let mut base = 0;
+        while let Some(instruction) = code.get(ip) {
+            let r = |reg: &Reg| base + *reg as usize;
+            match instruction {
+                RegInstruction::Frame(size) => {
+                    self.registers.resize(base + *size as usize, Value::Null);
+                }
+                RegInstruction::LoadConst { dst, constant } => {
+                    self.registers[r(dst)] = program.constants[*constant as usize].clone();
+                }
+                RegInstruction::Move { dst, src } => {
+                    self.registers[r(dst)] = self.registers[r(src)].clone();
+                }
+                RegInstruction::LoadGlobal { dst, global } => {
+                    let value = self.globals[*global as usize].clone().ok_or_else(|| VMError::UndefinedVariable {
+                        name: program.globals[*global as usize].clone(),
+                    })?;
+                    self.registers[r(dst)] = value;
+                }
+                RegInstruction::StoreGlobal { global, src } => {
+                    self.globals[*global as usize] = Some(self.registers[r(src)].clone());
+                }
+                RegInstruction::Binary { op, dst, left, right } => {
+                    let (a, b) = (&self.registers[r(left)], &self.registers[r(right)]);
+                    let value = match op {
+                        BinaryOp::Add => a.add(b)?,
+                        BinaryOp::Sub => a.sub(b)?,
+                        BinaryOp::Mul => a.mul(b)?,
+                        BinaryOp::Div => a.div(b)?,
+                        BinaryOp::Greater => Value::Boolean(a.gt(b)?),
+                        BinaryOp::Less => Value::Boolean(a.lt(b)?),
+                        BinaryOp::Equal => Value::Boolean(a.eq(b)),
+                        BinaryOp::NotEqual => Value::Boolean(!a.eq(b)),
+                    };
+                    self.registers[r(dst)] = value;
+                }
+                RegInstruction::Jmp(target) => {
+                    ip = *target;
+                    continue;
+                }
+                RegInstruction::JumpIfFalse { cond, target } => {
+                    if !self.registers[r(cond)].is_truthy() {
+                        ip = *target;
+                        continue;
+                    }
+                }
+                RegInstruction::NewArray { dst, start, count } => {
+                    let start = r(start);
+                    let items = self.registers[start..start + *count as usize].to_vec();
+                    self.registers[r(dst)] = Value::Array(items);
+                }
+                RegInstruction::GetIndex { dst, array, index } => {
+                    let array = &self.registers[r(array)];
+                    let (Value::Number(idx), Some(len)) = (&self.registers[r(index)], array.length()) else {
+                        return Err(VMError::TypeError {
+                            message: "Invalid array access".to_string(),
+                        });
+                    };
+                    let element = array.element(Self::check_array_bounds(*idx, len)?).unwrap();
+                    self.registers[r(dst)] = element;
+                }
+                RegInstruction::SetIndex { array, index, value } => {
+                    let value = self.registers[r(value)].clone();
+                    let Value::Number(idx) = self.registers[r(index)] else {
+                        return Err(VMError::TypeError {
+                            message: "Invalid array assignment".to_string(),
+                        });
+                    };
+                    match &mut self.registers[r(array)] {
+                        Value::Array(items) => {
+                            let idx = Self::check_array_bounds(idx, items.len())?;
+                            items[idx] = value;
+                        }
+                        Value::Tuple(_) => {
+                            return Err(VMError::TypeError {
+                                message: "Tuples are immutable".to_string(),
+                            })
+                        }
+                        _ => {
+                            return Err(VMError::TypeError {
+                                message: "Invalid array assignment".to_string(),
+                            })
+                        }
+                    }
+                }
+                RegInstruction::Len { dst, src } => {
+                    let value = &self.registers[r(src)];
+                    let Some(len) = value.length() else {
+                        return Err(VMError::UndefinedMember {
+                            type_name: value.type_name().to_string(),
+                            name: "len".to_string(),
+                        });
+                    };
+                    self.registers[r(dst)] = Value::Number(len as i32);
+                }
+                RegInstruction::ForIter {
+                    iterable,
+                    cursor,
+                    dst,
+                    exit,
+                } => {
+                    let Value::Number(position) = self.registers[r(cursor)] else {
+                        return Err(VMError::TypeError {
+                            message: "Invalid loop cursor".to_string(),
+                        });
+                    };
+                    let next = match &self.registers[r(iterable)] {
+                        iterable if iterable.length().is_some() => iterable.element(position as usize),
+                        other => {
+                            return Err(VMError::TypeError {
+                                message: format!("Cannot iterate over {}", other.type_name()),
+                            })
+                        }
+                    };
+                    let Some(item) = next else {
+                        ip = *exit;
+                        continue;
+                    };
+                    self.registers[r(cursor)] = Value::Number(position + 1);
+                    self.registers[r(dst)] = item;
+                }
+                RegInstruction::Call {
+                    dst,
+                    callee,
+                    args,
+                    argc,
+                } => {
+                    let function = match &self.registers[r(callee)] {
+                        Value::Function(function) if !function.is_generator => function.clone(),
+                        other => {
+                            return Err(VMError::TypeError {
+                                message: format!("Cannot call a value of type {}", other.type_name()),
+                            })
+                        }
+                    };
+                    if function.params.len() != *argc as usize {
+                        return Err(VMError::ArityMismatch {
+                            name: function.name.clone(),
+                            expected: function.params.len(),
+                            got: *argc as usize,
+                        });
+                    }
+                    if self.frames.len() >= self.max_call_depth {
+                        return Err(VMError::StackOverflow);
+                    }
+                    // The arguments are temporaries of the caller, so they are
+                    // moved rather than cloned into the callee's window.
+                    let new_base = self.registers.len();
+                    for arg in r(args)..r(args) + *argc as usize {
+                        let value = std::mem::replace(&mut self.registers[arg], Value::Null);
+                        self.registers.push(value);
+                    }
+                    self.frames.push(RegisterFrame {
+                        return_ip: ip + 1,
+                        base,
+                        dst: *dst,
+                    });
+                    base = new_base;
+                    ip = function.entry;
+                    continue;
+                }
+                RegInstruction::Return(reg) => {
+                    let value = std::mem::replace(&mut self.registers[r(reg)], Value::Null);
+                    let frame = self.frames.pop().ok_or_else(|| VMError::ExecutionError {
+                        message: "Return outside of a function".to_string(),
+                        line: 0,
+                        position: 0,
+                    })?;
+                    self.registers.truncate(base);
+                    base = frame.base;
+                    self.registers[base + frame.dst as usize] = value;
+                    ip = frame.return_ip;
+                    continue;
+                }
+                RegInstruction::Assert {
+                    cond,
+                    message,
+                    text,
+                    span,
+                } => {
+                    if !self.registers[r(cond)].is_truthy() {
+                        return Err(VMError::AssertionFailed {
+                            expression: text.clone(),
+                            message: message.map(|message| match &self.registers[r(&message)] {
+                                Value::String(s) => s.to_string(),
+                                other => format!("{:?}", other),
+                            }),
+                            span: *span,
+                        });
+                    }
+                }
+                RegInstruction::Keep(reg) => {
+                    self.results.push(self.registers[r(reg)].clone());
+                }
+            }
+            ip += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+    use crate::vm::{Compiler, VM};
+
+    fn parse(source: &str) -> Vec<ASTNode> {
+        Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .expect("test program parses")
+    }
+
+    /// Compiles and runs `source` on the register engine.
+    fn execute(source: &str) -> Result<(RegisterVM, RegisterProgram), VMError> {
+        let program = RegisterCompiler::default().compile_program(parse(source))?;
+        let mut vm = RegisterVM::new();
+        vm.execute(&program)?;
+        Ok((vm, program))
+    }
+
+    /// Runs `source` on both engines and checks that every top-level
+    /// variable other than a function ends up the same. Returns them in
+    /// debug form.
+    fn same_as_stack(source: &str) -> Vec<(String, String)> {
+        let (vm, program) = execute(source).unwrap_or_else(|error| panic!("{} failed: {}", source, error));
+        let mut variables: Vec<(String, String)> = vm
+            .variables(&program)
+            .into_iter()
+            .filter(|(_, value)| !matches!(value, Value::Function(_)))
+            .map(|(name, value)| (name.to_string(), format!("{:?}", value)))
+            .collect();
+        variables.sort();
+
+        let program = Compiler::default().compile_program(parse(source)).unwrap();
+        let mut vm = VM::new();
+        vm.execute(&program).unwrap();
+        let mut expected: Vec<(String, String)> = vm
+            .global_values(&program)
+            .into_iter()
+            .filter(|(_, value)| !matches!(value, Value::Function(_)))
+            .map(|(name, value)| (name.to_string(), format!("{:?}", value)))
+            .collect();
+        expected.sort();
+        assert_eq!(variables, expected, "{}", source);
+        variables
+    }
+
+    fn error(source: &str) -> String {
+        match execute(source) {
+            Ok(_) => panic!("{} ran without error", source),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn arithmetic_arrays_and_loops_match_the_stack_engine() {
+        let variables = same_as_stack(
+            "x = 1\nx = x + 2\na = [1, 2, 3]\na[1] = 9\ns = 0\nfor v in a { s = s + v }\ni = 0\nwhile (i < 4) { i = i + 1 }",
+        );
+        assert!(variables.contains(&("s".to_string(), "Number(13)".to_string())));
+        assert!(variables.contains(&("a".to_string(), "Array([Number(1), Number(9), Number(3)])".to_string())));
+    }
+
+    #[test]
+    fn functions_match_the_stack_engine() {
+        let variables = same_as_stack(
+            "g = 5\nfn f() { return g * 2 }\nfn fib(n) { if (n < 2) { return n }; return fib(n - 1) + fib(n - 2) }\nz = f()\ny = fib(10)\nn = \"h\\u{e9}llo\".len()",
+        );
+        assert!(variables.contains(&("y".to_string(), "Number(55)".to_string())));
+        assert!(variables.contains(&("z".to_string(), "Number(10)".to_string())));
+        assert!(variables.contains(&("n".to_string(), "Number(5)".to_string())));
+    }
+
+    #[test]
+    fn updates_write_straight_into_the_variable_register() {
+        let program = RegisterCompiler::default()
+            .compile_program(parse("x = 1\nx = x + 2"))
+            .unwrap();
+        let (_, x) = program.variables.iter().find(|(name, _)| name == "x").unwrap();
+        assert!(program
+            .instructions
+            .iter()
+            .any(|instruction| matches!(instruction, RegInstruction::Binary { dst, left, .. } if dst == x && left == x)));
+        assert!(!program
+            .instructions
+            .iter()
+            .any(|instruction| matches!(instruction, RegInstruction::Move { .. })));
+    }
+
+    #[test]
+    fn runtime_errors_match_the_stack_engine() {
+        assert_eq!(error("a = [1]\nb = a[3]"), "Index 3 out of bounds for array of length 1");
+        assert_eq!(error("x = 2147483647\ny = x + 1"), "Integer overflow");
+        assert_eq!(error("x = 0\ny = 1 / x"), "Division by zero");
+    }
+
+    #[test]
+    fn other_constructs_are_unsupported() {
+        assert_eq!(error("class C { }"), "The register engine does not support classes");
+        assert_eq!(error("{ defer f() }"), "The register engine does not support `defer`");
+    }
+}
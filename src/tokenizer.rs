@@ -1,4 +1,5 @@
 use crate::error::VMError;
+use unicode_ident::{is_xid_continue, is_xid_start};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
@@ -14,24 +15,55 @@ pub enum Token {
     LBracket,
     RBracket,
     Comma,
+    Semicolon,
     If,
     Else,
     While,
-    EOF,
+    For,
+    In,
+    Fn,
+    Class,
+    Return,
+    Yield,
+    Defer,
+    Comptime,
+    Const,
+    Assert,
+    Null,
+    Dot,
+    QuestionDot,
+    QuestionBracket,
+    QuestionQuestion,
+    Colon,
+    Arrow,
+    Eof,
     Greater,
     Less,
     Equal,
     NotEqual,
     Ident(String),
     String(String),
+    Bytes(Vec<u8>),
     Assignment,
 }
+/// What an escape sequence stands for: a character, or a raw byte in a
+/// byte string.
+enum Escaped {
+    Char(char),
+    Byte(u8),
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Tokenizer {
     pub input: String,
+    /// Byte offset into `input`; always on a character boundary.
     pub position: usize,
+    pub token_start: usize,
     pub line: usize,
+    /// 1-based column of `position`, counted in characters.
     pub line_position: usize,
+    /// Whether a line break separates the last token from the one before it.
+    pub newline_before: bool,
 }
 
 impl Tokenizer {
@@ -39,8 +71,10 @@ impl Tokenizer {
         Tokenizer {
             input,
             position: 0,
+            token_start: 0,
             line: 1,
             line_position: 1,
+            newline_before: false,
         }
     }
 
@@ -48,76 +82,387 @@ impl Tokenizer {
         VMError::tokenization_error(self.input.clone(), message, error_start, len)
     }
 
+    /// Reads a quoted literal starting at the opening quote. Byte strings
+    /// produce raw bytes and only accept ASCII text and `\xNN` escapes.
+    fn string_literal(&mut self, bytes: bool) -> Result<Token, VMError> {
+        let start_pos = if bytes { self.position - 1 } else { self.position };
+        let quote = self.input[self.position..].chars().next().unwrap();
+        self.position += 1; // Skip opening quote
+        self.line_position += 1;
+        let mut buffer = Vec::new();
+
+        while self.position < self.input.len() {
+            let c = self.input[self.position..].chars().next().unwrap();
+            let char_start = self.position;
+            self.position += c.len_utf8();
+            self.line_position += 1;
+
+            if c == quote {
+                return Ok(if bytes {
+                    Token::Bytes(buffer)
+                } else {
+                    // Only whole characters and ASCII escapes were pushed.
+                    Token::String(String::from_utf8(buffer).unwrap())
+                });
+            }
+            let c = if c == '\\' {
+                self.escape(char_start, bytes)?
+            } else {
+                if c == '\n' {
+                    self.line += 1;
+                    self.line_position = 1;
+                }
+                if bytes && !c.is_ascii() {
+                    return Err(self.create_error(
+                        format!("Non-ASCII character in byte string: {}", c),
+                        char_start,
+                        c.len_utf8()
+                    ));
+                }
+                Escaped::Char(c)
+            };
+            match c {
+                Escaped::Char(c) => {
+                    let mut encoded = [0; 4];
+                    buffer.extend_from_slice(c.encode_utf8(&mut encoded).as_bytes());
+                }
+                Escaped::Byte(b) => buffer.push(b),
+            }
+        }
+        Err(self.create_error(
+            "Unterminated string literal".to_string(),
+            start_pos,
+            self.position - start_pos
+        ))
+    }
+
+    /// Reads an integer literal: decimal, `0x`, `0o` or `0b`, with `_`
+    /// separators between digits. Decimal literals may use a fraction and an
+    /// exponent (`1.5e3`) as long as the value is a whole number.
+    fn number_literal(&mut self) -> Result<Token, VMError> {
+        let start = self.position;
+        let literal_end = |from: usize| {
+            from + self.input[from..]
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(self.input.len() - from)
+        };
+        let radix = match self.input.get(start..start + 2) {
+            Some("0x" | "0X") => Some((16, "hex")),
+            Some("0o" | "0O") => Some((8, "octal")),
+            Some("0b" | "0B") => Some((2, "binary")),
+            _ => None,
+        };
+
+        let (value, end) = if let Some((radix, name)) = radix {
+            let end = literal_end(start + 2);
+            let digits = self.digits(start + 2, end, radix, name)?;
+            if digits.is_empty() {
+                return Err(self.create_error(
+                    format!("Expected {} digits after {}", name, &self.input[start..start + 2]),
+                    start,
+                    end - start
+                ));
+            }
+            (u128::from_str_radix(&digits, radix).ok(), end)
+        } else {
+            let digit_run = |from: usize| {
+                from + self.input[from..]
+                    .find(|c: char| !c.is_ascii_digit() && c != '_')
+                    .unwrap_or(self.input.len() - from)
+            };
+            let mut end = digit_run(start);
+            let mut mantissa = self.digits(start, end, 10, "decimal")?;
+            let mut scale = 0i64;
+            let rest = &self.input[end..];
+            if rest.starts_with('.') && rest[1..].starts_with(|c: char| c.is_ascii_digit()) {
+                let fraction_end = digit_run(end + 1);
+                let fraction = self.digits(end + 1, fraction_end, 10, "decimal")?;
+                scale -= fraction.len() as i64;
+                mantissa.push_str(&fraction);
+                end = fraction_end;
+            }
+            let rest = &self.input[end..];
+            if rest.starts_with(['e', 'E']) {
+                let sign = rest[1..].starts_with(['+', '-']) as usize;
+                let exponent_start = end + 1 + sign;
+                let exponent_end = literal_end(exponent_start);
+                let exponent = self.digits(exponent_start, exponent_end, 10, "decimal")?;
+                if exponent.is_empty() {
+                    return Err(self.create_error(
+                        "Expected digits in exponent".to_string(),
+                        end,
+                        exponent_end - end
+                    ));
+                }
+                let exponent: i64 = exponent.parse().unwrap_or(i64::MAX);
+                if rest[1..].starts_with('-') {
+                    scale = scale.saturating_sub(exponent);
+                } else {
+                    scale = scale.saturating_add(exponent);
+                }
+                end = exponent_end;
+            }
+            let end = literal_end(end);
+            if let Some(bad) = self.input[start..end].find(|c: char| !c.is_ascii_digit() && !"_.eE+-".contains(c)) {
+                let c = self.input[start + bad..].chars().next().unwrap();
+                return Err(self.create_error(
+                    format!("Invalid digit '{}' in number literal", c),
+                    start + bad,
+                    c.len_utf8()
+                ));
+            }
+            let value = scaled(&mantissa, scale).map_err(|message| {
+                self.create_error(message.to_string(), start, end - start)
+            })?;
+            (value, end)
+        };
+
+        match value.and_then(|value| i32::try_from(value).ok()) {
+            Some(value) => {
+                self.advance_to(end);
+                Ok(Token::Number(value))
+            }
+            None => Err(self.create_error(
+                format!("Number literal {} does not fit in an int", &self.input[start..end]),
+                start,
+                end - start
+            )),
+        }
+    }
+
+    /// Collects the digits in `start..end`, rejecting digits outside `radix`
+    /// and misplaced `_` separators.
+    fn digits(&self, start: usize, end: usize, radix: u32, name: &str) -> Result<String, VMError> {
+        let text = &self.input[start..end];
+        if let Some(offset) = text.find("__") {
+            return Err(self.create_error(
+                "Digit separators must be single underscores".to_string(),
+                start + offset,
+                2
+            ));
+        }
+        if text.ends_with('_') {
+            return Err(self.create_error(
+                "Number literal cannot end with an underscore".to_string(),
+                end - 1,
+                1
+            ));
+        }
+        if let Some(offset) = text.find(|c: char| c != '_' && !c.is_digit(radix)) {
+            let c = text[offset..].chars().next().unwrap();
+            return Err(self.create_error(
+                format!("Invalid {} digit '{}'", name, c),
+                start + offset,
+                c.len_utf8()
+            ));
+        }
+        Ok(text.replace('_', ""))
+    }
+
+    /// Reads `r"..."` or `r#"..."#`; the content is taken verbatim and ends
+    /// at a quote followed by as many `#` as were opened with.
+    fn raw_string(&mut self) -> Result<Token, VMError> {
+        let start_pos = self.position;
+        let hashes = self.input[start_pos + 1..].bytes().take_while(|&b| b == b'#').count();
+        let content_start = start_pos + hashes + 2;
+        let closing = format!("\"{}", "#".repeat(hashes));
+        let Some(len) = self.input[content_start..].find(&closing) else {
+            return Err(self.create_error(
+                "Unterminated raw string literal".to_string(),
+                start_pos,
+                self.input.len() - start_pos
+            ));
+        };
+        let content = self.input[content_start..content_start + len].to_string();
+        self.advance_to(content_start + len + closing.len());
+        Ok(Token::String(content))
+    }
+
+    /// Reads a `"""` string. A line break right after the opening quotes and
+    /// the indentation of the closing quotes are dropped, and the indentation
+    /// common to all non-blank lines is stripped. Escapes are processed in
+    /// place so their error spans point into the source.
+    fn multiline_string(&mut self) -> Result<Token, VMError> {
+        let start_pos = self.position;
+        let content_start = start_pos + 3;
+        let mut close = content_start;
+        while !self.input[close..].starts_with("\"\"\"") {
+            let mut chars = self.input[close..].chars();
+            let Some(c) = chars.next() else {
+                return Err(self.create_error(
+                    "Unterminated multi-line string literal".to_string(),
+                    start_pos,
+                    self.input.len() - start_pos
+                ));
+            };
+            close += c.len_utf8();
+            if c == '\\' {
+                close += chars.next().map_or(0, char::len_utf8);
+            }
+        }
+
+        let mut lines = Vec::new();
+        let mut line_start = content_start;
+        for (offset, _) in self.input[content_start..close].match_indices('\n') {
+            lines.push(line_start..content_start + offset);
+            line_start = content_start + offset + 1;
+        }
+        lines.push(line_start..close);
+        let is_blank = |line: &std::ops::Range<usize>| {
+            self.input[line.clone()].trim_start_matches([' ', '\t']).is_empty()
+        };
+        if lines.len() > 1 && lines[0].is_empty() {
+            lines.remove(0);
+        }
+        if lines.len() > 1 && is_blank(&lines[lines.len() - 1]) {
+            lines.pop();
+        }
+        let indent = lines
+            .iter()
+            .filter(|line| !is_blank(line))
+            .map(|line| {
+                self.input[line.clone()].len()
+                    - self.input[line.clone()].trim_start_matches([' ', '\t']).len()
+            })
+            .min()
+            .unwrap_or(0);
+
+        let mut string = String::new();
+        for (i, line) in lines.into_iter().enumerate() {
+            if i > 0 {
+                string.push('\n');
+            }
+            self.position = (line.start + indent).min(line.end);
+            while self.position < line.end {
+                let c = self.input[self.position..].chars().next().unwrap();
+                let char_start = self.position;
+                self.position += c.len_utf8();
+                if c != '\\' {
+                    string.push(c);
+                } else if let Escaped::Char(c) = self.escape(char_start, false)? {
+                    string.push(c);
+                }
+            }
+        }
+        self.position = start_pos;
+        self.advance_to(close + 3);
+        Ok(Token::String(string))
+    }
+
+    /// Moves past the consumed input up to byte offset `end`, keeping the
+    /// line and column counters in step.
+    fn advance_to(&mut self, end: usize) {
+        let consumed = &self.input[self.position..end];
+        match consumed.rfind('\n') {
+            Some(last) => {
+                self.line += consumed.matches('\n').count();
+                self.line_position = consumed[last + 1..].chars().count() + 1;
+            }
+            None => self.line_position += consumed.chars().count(),
+        }
+        self.position = end;
+    }
+
+    /// Reads the escape sequence following a backslash at `start`.
+    fn escape(&mut self, start: usize, bytes: bool) -> Result<Escaped, VMError> {
+        let invalid = |tokenizer: &Self, message: String| {
+            let end = tokenizer.position.min(tokenizer.input.len());
+            tokenizer.create_error(message, start, (end - start).max(1))
+        };
+        let Some(c) = self.input[self.position..].chars().next() else {
+            return Err(invalid(self, "Unterminated string literal".to_string()));
+        };
+        self.position += c.len_utf8();
+        self.line_position += 1;
+
+        let c = match c {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '\\' => '\\',
+            '"' => '"',
+            '\'' => '\'',
+            'b' => '\x08',
+            'f' => '\x0c',
+            'v' => '\x0b',
+            '0' => '\0',
+            'x' => {
+                let digits = self.input.get(self.position..self.position + 2).unwrap_or("");
+                let value = u8::from_str_radix(digits, 16)
+                    .ok()
+                    .filter(|_| digits.chars().all(|c| c.is_ascii_hexdigit()));
+                self.position += digits.len();
+                self.line_position += digits.len();
+                let Some(value) = value else {
+                    return Err(invalid(self, "Expected two hex digits after \\x".to_string()));
+                };
+                if bytes {
+                    return Ok(Escaped::Byte(value));
+                }
+                if !value.is_ascii() {
+                    return Err(invalid(
+                        self,
+                        format!("\\x{} is not ASCII; use \\u{{...}} in strings", digits),
+                    ));
+                }
+                value as char
+            }
+            'u' if !bytes => {
+                let rest = &self.input[self.position..];
+                let digits = rest
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                    .map(|(digits, _)| digits)
+                    .filter(|digits| {
+                        (1..=6).contains(&digits.len())
+                            && digits.chars().all(|c| c.is_ascii_hexdigit())
+                    });
+                let Some(digits) = digits else {
+                    return Err(invalid(
+                        self,
+                        "Expected 1 to 6 hex digits in braces after \\u".to_string(),
+                    ));
+                };
+                let advance = digits.len() + 2;
+                let value = u32::from_str_radix(digits, 16).ok().and_then(char::from_u32);
+                self.position += advance;
+                self.line_position += advance;
+                value.ok_or_else(|| {
+                    invalid(self, format!("\\u{{{}}} is not a valid character", digits))
+                })?
+            }
+            _ => return Err(invalid(self, format!("Invalid escape sequence: \\{}", c))),
+        };
+        Ok(Escaped::Char(c))
+    }
+
     pub fn next_token(&mut self) -> Result<Token, VMError> {
+        self.newline_before = false;
         while self.position < self.input.len() {
+            self.token_start = self.position;
             let input_slice = &self.input[self.position..];
             let c = input_slice.chars().next().unwrap();
             
             match c {
                 '0'..='9' => {
-                    let mut num = 0;
-                    let mut start = self.position;
-                    while start < self.input.len() && self.input[start..].chars().next().unwrap().is_ascii_digit() {
-                        num = num * 10 + (self.input[start..].chars().next().unwrap() as i32 - '0' as i32);
-                        start += 1;
-                    }
-                    self.line_position += start - self.position;
-                    self.position = start;
-                    return Ok(Token::Number(num));
+                    return self.number_literal();
+                }
+                '"' if input_slice.starts_with("\"\"\"") => {
+                    return self.multiline_string();
                 }
                 '"' | '\'' => {
-                    let quote = c;
-                    let start_pos = self.position;
-                    self.position += 1; // Skip opening quote
-                    let mut string = String::new();
-                    let mut escaped = false;
-                    
-                    while self.position < self.input.len() {
-                        let c = self.input[self.position..].chars().next().unwrap();
-                        self.position += 1;
-                        self.line_position += 1;
-                        
-                        if escaped {
-                            string.push(match c {
-                                'n' => '\n',
-                                't' => '\t',
-                                'r' => '\r',
-                                '\\' => '\\',
-                                '"' => '"',
-                                '\'' => '\'',
-                                'b' => '\x08',
-                                'f' => '\x0c',
-                                'v' => '\x0b',
-                                '0' => '\0',
-                                _ => return Err(self.create_error(
-                                    format!("Invalid escape sequence: \\{}", c),
-                                    self.position - 2,
-                                    2
-                                )),
-                            });
-                            escaped = false;
-                        } else if c == '\\' {
-                            escaped = true;
-                        } else if c == quote {
-                            return Ok(Token::String(string));
-                        } else if c == '\n' {
-                            self.line += 1;
-                            self.line_position = 1;
-                            string.push(c);
-                        } else {
-                            string.push(c);
-                        }
-                    }
-                    return Err(self.create_error(
-                        format!("Unterminated string literal"),
-                        start_pos,
-                        self.position - start_pos
-                    ));
+                    return self.string_literal(false);
                 }
-                '+' | '-' | '*' | '/' | '(' | ')' | '{' | '}' | '>' | '<' | '!' | '[' | ']' | ',' | '=' => {
+                '+' | '-' | '*' | '/' | '(' | ')' | '{' | '}' | '>' | '<' | '!' | '[' | ']' | ',' | ';' | '.' | ':' | '?' | '=' => {
                     let (token, advance) = match c {
                         '+' => (Token::Plus, 1),
-                        '-' => (Token::Minus, 1),
+                        '-' => {
+                            if input_slice.starts_with("->") {
+                                (Token::Arrow, 2)
+                            } else {
+                                (Token::Minus, 1)
+                            }
+                        }
                         '*' => (Token::Star, 1),
                         '/' => (Token::Slash, 1),
                         '(' => (Token::LParen, 1),
@@ -127,6 +472,9 @@ impl Tokenizer {
                         '[' => (Token::LBracket, 1),
                         ']' => (Token::RBracket, 1),
                         ',' => (Token::Comma, 1),
+                        ';' => (Token::Semicolon, 1),
+                        '.' => (Token::Dot, 1),
+                        ':' => (Token::Colon, 1),
                         '>' => (Token::Greater, 1),
                         '<' => (Token::Less, 1),
                         '=' => {
@@ -136,6 +484,21 @@ impl Tokenizer {
                                 (Token::Assignment, 1)
                             }
                         }
+                        '?' => {
+                            if input_slice.starts_with("??") {
+                                (Token::QuestionQuestion, 2)
+                            } else if input_slice.starts_with("?.") {
+                                (Token::QuestionDot, 2)
+                            } else if input_slice.starts_with("?[") {
+                                (Token::QuestionBracket, 2)
+                            } else {
+                                return Err(self.create_error(
+                                    "Unexpected token: ?".to_string(),
+                                    self.position,
+                                    1
+                                ));
+                            }
+                        }
                         '!' => {
                             if self.input[self.position..].starts_with("!=") {
                                 (Token::NotEqual, 2)
@@ -154,31 +517,49 @@ impl Tokenizer {
                     return Ok(token);
                 }
 
-                'a'..='z' | 'A'..='Z' => {
-                    let mut ident = String::new();
-                    while self.position < self.input.len() {
-                        let c = self.input.chars().nth(self.position).unwrap();
-                        if !c.is_ascii_alphanumeric() && c != '_' {
-                            break;
-                        }
-                        ident.push(c);
-                        self.position += 1;
-                        self.line_position += 1;
-                    }
+                'r' if input_slice[1..].trim_start_matches('#').starts_with('"') => {
+                    return self.raw_string();
+                }
+
+                'b' if input_slice[1..].starts_with(['"', '\'']) => {
+                    self.position += 1;
+                    self.line_position += 1;
+                    return self.string_literal(true);
+                }
+
+                c if c == '_' || is_xid_start(c) => {
+                    let len = input_slice
+                        .find(|c: char| !is_xid_continue(c))
+                        .unwrap_or(input_slice.len());
+                    let ident = input_slice[..len].to_string();
+                    self.position += len;
+                    self.line_position += ident.chars().count();
                     match ident.as_str() {
                         "if" => return Ok(Token::If),
                         "else" => return Ok(Token::Else),
                         "while" => return Ok(Token::While),
+                        "for" => return Ok(Token::For),
+                        "in" => return Ok(Token::In),
+                        "fn" => return Ok(Token::Fn),
+                        "class" => return Ok(Token::Class),
+                        "return" => return Ok(Token::Return),
+                        "yield" => return Ok(Token::Yield),
+                        "defer" => return Ok(Token::Defer),
+                        "comptime" => return Ok(Token::Comptime),
+                        "const" => return Ok(Token::Const),
+                        "assert" => return Ok(Token::Assert),
+                        "null" => return Ok(Token::Null),
                         _ => return Ok(Token::Ident(ident)),
                     }
                 }
 
-                ' ' | '\t' => {
+                ' ' | '\t' | '\r' => {
                     self.position += 1;
                     self.line_position += 1;
                 }
 
                 '\n' => {
+                    self.newline_before = true;
                     self.position += 1;
                     self.line += 1;
                     self.line_position = 1;
@@ -188,11 +569,202 @@ impl Tokenizer {
                     return Err(self.create_error(
                         format!("Unexpected character: {}", c),
                         self.position,
-                        1
+                        c.len_utf8()
                     ));
                 }
             }
         }
-        Ok(Token::EOF)
+        self.token_start = self.position;
+        Ok(Token::Eof)
+    }
+}
+
+/// The value of the decimal digits `mantissa` times `10^scale`, which must be
+/// a whole number. `None` means it is too large to represent.
+fn scaled(mantissa: &str, scale: i64) -> Result<Option<u128>, &'static str> {
+    let digits = mantissa.trim_start_matches('0');
+    if digits.is_empty() {
+        return Ok(Some(0));
+    }
+    if scale < 0 {
+        let cut = digits.len().saturating_sub(scale.unsigned_abs() as usize);
+        if scale.unsigned_abs() as usize > digits.len()
+            || !digits[cut..].bytes().all(|b| b == b'0')
+        {
+            return Err("Number literal is not a whole number");
+        }
+        return Ok(digits[..cut].parse().ok());
+    }
+    let zeros = "0".repeat(scale.min(40) as usize);
+    Ok(format!("{}{}", digits, zeros).parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every token in `source` up to the end of the input.
+    fn tokens(source: &str) -> Vec<Token> {
+        let mut tokenizer = Tokenizer::new(source.to_string());
+        let mut tokens = Vec::new();
+        loop {
+            match tokenizer.next_token() {
+                Ok(Token::Eof) => return tokens,
+                Ok(token) => tokens.push(token),
+                Err(error) => panic!("{} failed to tokenize: {}", source, error),
+            }
+        }
+    }
+
+    /// The message and the source text of the span `source` fails to
+    /// tokenize with.
+    fn error(source: &str) -> (String, String) {
+        let mut tokenizer = Tokenizer::new(source.to_string());
+        loop {
+            match tokenizer.next_token() {
+                Ok(Token::Eof) => panic!("{} tokenized without error", source),
+                Ok(_) => {}
+                Err(VMError::TokenizationError { message, span, .. }) => {
+                    let text = source[span.offset()..span.offset() + span.len()].to_string();
+                    return (message, text);
+                }
+                Err(error) => panic!("{} failed with {:?}", source, error),
+            }
+        }
+    }
+
+    fn string(text: &str) -> Vec<Token> {
+        vec![Token::String(text.to_string())]
+    }
+
+    #[test]
+    fn unicode_escapes_produce_characters() {
+        assert_eq!(tokens(r#""caf\u{e9} \u{1F600}""#), string("café 😀"));
+        assert_eq!(tokens(r#""\x41\t\0""#), string("A\t\0"));
+        assert_eq!(
+            error(r#""\u{110000}""#),
+            ("\\u{110000} is not a valid character".to_string(), "\\u{110000}".to_string())
+        );
+        assert_eq!(
+            error(r#""\u{}""#),
+            ("Expected 1 to 6 hex digits in braces after \\u".to_string(), "\\u".to_string())
+        );
+    }
+
+    #[test]
+    fn hex_escapes_above_ascii_are_only_allowed_in_byte_strings() {
+        assert_eq!(
+            error(r#""\xff""#),
+            ("\\xff is not ASCII; use \\u{...} in strings".to_string(), "\\xff".to_string())
+        );
+        assert_eq!(tokens(r#"b"a\xff""#), vec![Token::Bytes(vec![b'a', 0xff])]);
+        assert_eq!(
+            error("b\"é\""),
+            ("Non-ASCII character in byte string: é".to_string(), "é".to_string())
+        );
+        assert_eq!(
+            error(r#"b"\u{41}""#),
+            ("Invalid escape sequence: \\u".to_string(), "\\u".to_string())
+        );
+    }
+
+    #[test]
+    fn raw_strings_keep_their_content_verbatim() {
+        assert_eq!(tokens(r#"r"a\nb""#), string("a\\nb"));
+        assert_eq!(tokens(r###"r#"say "hi""#"###), string("say \"hi\""));
+        assert_eq!(tokens(r###"r##"a"#b"##"###), string("a\"#b"));
+        assert_eq!(
+            error(r###"x = r#"open""###),
+            ("Unterminated raw string literal".to_string(), "r#\"open\"".to_string())
+        );
+    }
+
+    #[test]
+    fn multi_line_strings_strip_common_indentation() {
+        let source = "s = \"\"\"\n    one\n      two\n\n    three\\t\n    \"\"\"";
+        assert_eq!(
+            tokens(source)[2..],
+            string("one\n  two\n\nthree\t")[..]
+        );
+        assert_eq!(tokens(r#""""a "quoted" b""""#), string("a \"quoted\" b"));
+        assert_eq!(
+            error("\"\"\"\n  ok\n  \\q\n\"\"\""),
+            ("Invalid escape sequence: \\q".to_string(), "\\q".to_string())
+        );
+        assert_eq!(
+            error("\"\"\"never closed"),
+            ("Unterminated multi-line string literal".to_string(), "\"\"\"never closed".to_string())
+        );
+    }
+
+    #[test]
+    fn multi_line_strings_keep_line_numbers_in_step() {
+        let mut tokenizer = Tokenizer::new("\"\"\"\na\nb\n\"\"\" x".to_string());
+        tokenizer.next_token().unwrap();
+        assert_eq!(tokenizer.next_token().unwrap(), Token::Ident("x".to_string()));
+        assert_eq!((tokenizer.line, tokenizer.line_position), (4, 6));
+    }
+
+    #[test]
+    fn number_literals_accept_radixes_separators_and_exponents() {
+        let numbers = tokens("0x7FFF_FFFF 0o17 0b101 1_000 1.5e3 250e-1 2147483647");
+        assert_eq!(
+            numbers,
+            [0x7FFF_FFFF, 0o17, 0b101, 1000, 1500, 25, 2147483647].map(Token::Number)
+        );
+    }
+
+    #[test]
+    fn malformed_number_literals_are_rejected_at_the_offending_text() {
+        let cases = [
+            ("2147483648", "Number literal 2147483648 does not fit in an int", "2147483648"),
+            ("0xFFFF_FFFF", "Number literal 0xFFFF_FFFF does not fit in an int", "0xFFFF_FFFF"),
+            ("1__0", "Digit separators must be single underscores", "__"),
+            ("1_", "Number literal cannot end with an underscore", "_"),
+            ("0b102", "Invalid binary digit '2'", "2"),
+            ("1.5", "Number literal is not a whole number", "1.5"),
+            ("1e", "Expected digits in exponent", "e"),
+            ("0x", "Expected hex digits after 0x", "0x"),
+            ("12ab", "Invalid digit 'a' in number literal", "a"),
+        ];
+        for (source, message, span) in cases {
+            assert_eq!(
+                error(&format!("x = {}", source)),
+                (message.to_string(), span.to_string()),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn identifiers_may_use_unicode_letters() {
+        assert_eq!(
+            tokens("größe = π_2 + _ñ"),
+            vec![
+                Token::Ident("größe".to_string()),
+                Token::Assignment,
+                Token::Ident("π_2".to_string()),
+                Token::Plus,
+                Token::Ident("_ñ".to_string()),
+            ]
+        );
+        assert_eq!(
+            error("x = 1 € 2"),
+            ("Unexpected character: €".to_string(), "€".to_string())
+        );
+    }
+
+    #[test]
+    fn columns_count_characters_not_bytes() {
+        let mut tokenizer = Tokenizer::new("\"ü\\u{e9}\" größe\n日本 x".to_string());
+        tokenizer.next_token().unwrap();
+        assert_eq!(tokenizer.line_position, 10);
+        tokenizer.next_token().unwrap();
+        assert_eq!(tokenizer.line_position, 16);
+        tokenizer.next_token().unwrap();
+        assert_eq!((tokenizer.line, tokenizer.line_position), (2, 3));
+        tokenizer.next_token().unwrap();
+        assert_eq!((tokenizer.line, tokenizer.line_position), (2, 5));
     }
 }
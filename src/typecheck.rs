@@ -0,0 +1,980 @@
+use crate::ast::{ASTNode, Param};
+use crate::error::VMError;
+use crate::tokenizer::Token;
+use crate::types::{VMBinaryOp, VMCompare, Value};
+use miette::SourceSpan;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    String,
+    Char,
+    Bytes,
+    Bool,
+    Null,
+    Array(Box<Type>),
+    Tuple(Vec<Type>),
+    Function,
+    Generator,
+    Class,
+    Object,
+    Any,
+}
+
+impl Type {
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "int" => Some(Type::Int),
+            "string" => Some(Type::String),
+            "char" => Some(Type::Char),
+            "bytes" => Some(Type::Bytes),
+            "bool" => Some(Type::Bool),
+            "null" => Some(Type::Null),
+            "array" => Some(Type::Array(Box::new(Type::Any))),
+            "generator" => Some(Type::Generator),
+            "class" => Some(Type::Class),
+            "object" => Some(Type::Object),
+            "any" => Some(Type::Any),
+            _ => None,
+        }
+    }
+
+    fn of_value(value: &Value) -> Type {
+        match value {
+            Value::Number(_) => Type::Int,
+            Value::Boolean(_) => Type::Bool,
+            Value::Array(_) => Type::Array(Box::new(Type::Any)),
+            Value::Tuple(items) => Type::Tuple(items.iter().map(Type::of_value).collect()),
+            Value::String(_) => Type::String,
+            Value::Char(_) => Type::Char,
+            Value::Bytes(_) => Type::Bytes,
+            Value::Function(_) => Type::Function,
+            Value::Generator(_) => Type::Generator,
+            Value::Class(_) => Type::Class,
+            Value::Instance(_) => Type::Object,
+            Value::Null => Type::Null,
+        }
+    }
+
+    /// A representative runtime value, used to ask the operator
+    /// implementations in `types.rs` whether an operation is allowed.
+    fn sample(&self) -> Option<Value> {
+        match self {
+            Type::Int => Some(Value::Number(1)),
+            Type::String => Some(Value::String("".into())),
+            Type::Char => Some(Value::Char(' ')),
+            Type::Bytes => Some(Value::Bytes(Vec::new())),
+            Type::Bool => Some(Value::Boolean(true)),
+            Type::Null => Some(Value::Null),
+            Type::Array(_) => Some(Value::Array(Vec::new())),
+            Type::Tuple(elements) => elements
+                .iter()
+                .map(Type::sample)
+                .collect::<Option<Vec<_>>>()
+                .map(|items| Value::Tuple(items.into())),
+            Type::Function | Type::Generator | Type::Class | Type::Object | Type::Any => None,
+        }
+    }
+
+    /// The type of a value that may come from either `self` or `other`, used
+    /// where control flow merges.
+    fn join(&self, other: &Type) -> Type {
+        match (self, other) {
+            (Type::Array(a), Type::Array(b)) => Type::Array(Box::new(a.join(b))),
+            (Type::Tuple(a), Type::Tuple(b)) if a.len() == b.len() => {
+                Type::Tuple(a.iter().zip(b).map(|(a, b)| a.join(b)).collect())
+            }
+            (a, b) if a == b => a.clone(),
+            _ => Type::Any,
+        }
+    }
+
+    /// Whether a value of type `other` may be stored where `self` is expected.
+    fn accepts(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Array(a), Type::Array(b)) => a.accepts(b),
+            (Type::Tuple(a), Type::Tuple(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.accepts(b))
+            }
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::String => write!(f, "string"),
+            Type::Char => write!(f, "char"),
+            Type::Bytes => write!(f, "bytes"),
+            Type::Bool => write!(f, "bool"),
+            Type::Null => write!(f, "null"),
+            Type::Array(element) => write!(f, "[{}]", element),
+            Type::Tuple(elements) => {
+                let elements: Vec<String> = elements.iter().map(Type::to_string).collect();
+                match elements.len() {
+                    1 => write!(f, "({},)", elements[0]),
+                    _ => write!(f, "({})", elements.join(", ")),
+                }
+            }
+            Type::Function => write!(f, "fn"),
+            Type::Generator => write!(f, "generator"),
+            Type::Class => write!(f, "class"),
+            Type::Object => write!(f, "object"),
+            Type::Any => write!(f, "any"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Binding {
+    ty: Type,
+    /// Annotated bindings constrain every later assignment in the same scope.
+    declared: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Signature {
+    params: Vec<Type>,
+    return_type: Type,
+    is_generator: bool,
+}
+
+#[derive(Debug, Clone)]
+struct FunctionContext {
+    return_type: Option<Type>,
+    is_generator: bool,
+    returns: Vec<Type>,
+}
+
+pub struct TypeChecker<'a> {
+    src: &'a str,
+    /// Infer types for unannotated bindings instead of treating them as `any`.
+    infer: bool,
+    scopes: Vec<HashMap<String, Binding>>,
+    signatures: HashMap<String, Signature>,
+    function: Option<FunctionContext>,
+    errors: Vec<VMError>,
+    warnings: Vec<VMError>,
+}
+
+impl<'a> TypeChecker<'a> {
+    /// A checker that only enforces annotations; unannotated code is typed
+    /// as `any` and never rejected.
+    pub fn new(src: &'a str) -> Self {
+        TypeChecker {
+            src,
+            infer: false,
+            scopes: vec![HashMap::new()],
+            signatures: HashMap::new(),
+            function: None,
+            errors: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// A checker that also infers types for unannotated variables and
+    /// function results, as used by `mollusk check`.
+    pub fn inferring(src: &'a str) -> Self {
+        TypeChecker {
+            infer: true,
+            ..TypeChecker::new(src)
+        }
+    }
+
+    pub fn check_program(&mut self, nodes: &[ASTNode]) -> Result<(), VMError> {
+        self.check_body(nodes);
+        let mut errors = std::mem::take(&mut self.errors);
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(VMError::TypeCheckFailed { errors }),
+        }
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<VMError> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn error(&mut self, message: String, span: SourceSpan) {
+        self.errors
+            .push(VMError::type_check_error(self.src.to_string(), message, span));
+    }
+
+    fn warning(&mut self, message: String, span: SourceSpan) {
+        self.warnings.push(VMError::TypeCheckWarning {
+            src: self.src.to_string().into(),
+            message,
+            span,
+        });
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn bind(&mut self, name: &str, binding: Binding) {
+        self.scopes
+            .last_mut()
+            .expect("No scope on stack")
+            .insert(name.to_string(), binding);
+    }
+
+    /// Merges the bindings of another control-flow path into the current one.
+    fn merge_scopes(&mut self, other: Vec<HashMap<String, Binding>>) {
+        for (scope, other) in self.scopes.iter_mut().zip(other) {
+            for (name, binding) in other {
+                match scope.get_mut(&name) {
+                    Some(existing) => {
+                        existing.ty = existing.ty.join(&binding.ty);
+                        existing.declared |= binding.declared;
+                    }
+                    None => {
+                        scope.insert(name, binding);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Registers the signatures of the functions declared in `nodes`, so
+    /// calls may appear before the declaration.
+    fn hoist(&mut self, nodes: &[ASTNode]) {
+        for node in nodes {
+            if let ASTNode::FnDecl {
+                name,
+                params,
+                return_type,
+                is_generator,
+                ..
+            } = node
+            {
+                let signature = Signature {
+                    params: params
+                        .iter()
+                        .map(|param| param.ty.clone().unwrap_or(Type::Any))
+                        .collect(),
+                    return_type: return_type.clone().unwrap_or(Type::Any),
+                    is_generator: *is_generator,
+                };
+                self.signatures.insert(name.clone(), signature);
+            }
+        }
+    }
+
+    fn check_body(&mut self, nodes: &[ASTNode]) {
+        self.hoist(nodes);
+        // Function bodies go first so their inferred results are known at
+        // every call site in this body.
+        for node in nodes {
+            if let ASTNode::FnDecl {
+                name,
+                params,
+                return_type,
+                body,
+                is_generator,
+                ..
+            } = node
+            {
+                let inferred = self.check_function(params, return_type, body, *is_generator);
+                if let (Some(inferred), Some(signature)) = (inferred, self.signatures.get_mut(name)) {
+                    signature.return_type = inferred;
+                }
+            }
+        }
+        for node in nodes {
+            self.check_statement(node);
+        }
+    }
+
+    fn check_statement(&mut self, node: &ASTNode) {
+        match node {
+            ASTNode::If {
+                condition,
+                if_block,
+                else_block,
+            } => {
+                self.type_of(condition);
+                let before = self.scopes.clone();
+                self.check_body(if_block);
+                let after_if = std::mem::replace(&mut self.scopes, before);
+                self.check_body(else_block);
+                self.merge_scopes(after_if);
+            }
+            ASTNode::While { condition, body } => {
+                self.type_of(condition);
+                let before = self.scopes.clone();
+                self.check_body(body);
+                self.merge_scopes(before);
+            }
+            ASTNode::For {
+                var,
+                iterable,
+                body,
+            } => {
+                let element = match self.type_of(iterable) {
+                    Type::Array(element) => *element,
+                    Type::String => Type::Char,
+                    Type::Bytes => Type::Int,
+                    _ => Type::Any,
+                };
+                let before = self.scopes.clone();
+                self.bind(
+                    var,
+                    Binding {
+                        ty: element,
+                        declared: false,
+                    },
+                );
+                self.check_body(body);
+                self.merge_scopes(before);
+            }
+            ASTNode::VarDecl {
+                name,
+                ty,
+                value,
+                span,
+            } => {
+                let actual = self.type_of(value);
+                self.assign(name, ty.clone(), actual, *span);
+            }
+            ASTNode::Destructure { names, value, span } => {
+                let elements = match self.type_of(value) {
+                    Type::Tuple(elements) if elements.len() == names.len() => elements,
+                    Type::Tuple(elements) => {
+                        self.error(
+                            format!(
+                                "Cannot unpack a tuple of {} elements into {} variables",
+                                elements.len(),
+                                names.len()
+                            ),
+                            *span,
+                        );
+                        vec![Type::Any; names.len()]
+                    }
+                    Type::Any => vec![Type::Any; names.len()],
+                    other => {
+                        self.error(format!("Cannot unpack a value of type {}", other), *span);
+                        vec![Type::Any; names.len()]
+                    }
+                };
+                for (name, actual) in names.iter().zip(elements) {
+                    self.assign(name, None, actual, *span);
+                }
+            }
+            ASTNode::Block(nodes) => {
+                self.scopes.push(HashMap::new());
+                self.check_body(nodes);
+                self.scopes.pop();
+            }
+            ASTNode::ArrayAssign {
+                array,
+                index,
+                value,
+                span,
+            } => {
+                let element = self.index_type(array, index, false, *span);
+                let value = self.type_of(value);
+                if let ASTNode::VarRef(name) = array.as_ref() {
+                    if let Some(Type::Tuple(_)) = self.lookup(name).map(|binding| &binding.ty) {
+                        self.error("Tuples are immutable".to_string(), *span);
+                        return;
+                    }
+                }
+                if element.accepts(&value) {
+                    return;
+                }
+                // An inferred array simply widens; an annotated one is violated.
+                let widened = match array.as_ref() {
+                    ASTNode::VarRef(name) if self.infer => {
+                        self.scopes.iter_mut().rev().find_map(|scope| {
+                            scope.get_mut(name).filter(|binding| !binding.declared)
+                        })
+                    }
+                    _ => None,
+                };
+                match widened {
+                    Some(binding) => {
+                        binding.ty = Type::Array(Box::new(element.join(&value)));
+                    }
+                    None => self.error(
+                        format!("Cannot store a value of type {} in [{}]", value, element),
+                        *span,
+                    ),
+                }
+            }
+            ASTNode::FnDecl { name, .. } => {
+                self.bind(
+                    name,
+                    Binding {
+                        ty: Type::Function,
+                        declared: false,
+                    },
+                );
+            }
+            ASTNode::ClassDecl { name, methods } => {
+                self.bind(
+                    name,
+                    Binding {
+                        ty: Type::Class,
+                        declared: false,
+                    },
+                );
+                for method in methods {
+                    if let ASTNode::FnDecl {
+                        params,
+                        return_type,
+                        body,
+                        is_generator,
+                        ..
+                    } = method
+                    {
+                        self.check_function(params, return_type, body, *is_generator);
+                    }
+                }
+            }
+            ASTNode::FieldAssign { object, value, .. } => {
+                self.type_of(object);
+                self.type_of(value);
+            }
+            ASTNode::Return { value, span } => {
+                let actual = match value {
+                    Some(value) => self.type_of(value),
+                    None => Type::Null,
+                };
+                let Some(function) = self.function.as_mut() else {
+                    return;
+                };
+                function.returns.push(actual.clone());
+                let expected = function
+                    .return_type
+                    .clone()
+                    .filter(|_| !function.is_generator);
+                if let Some(expected) = expected {
+                    if !expected.accepts(&actual) {
+                        self.error(
+                            format!(
+                                "Function returns {} but is declared to return {}",
+                                actual, expected
+                            ),
+                            *span,
+                        );
+                    }
+                }
+            }
+            ASTNode::Defer(value) => {
+                self.type_of(value);
+            }
+            ASTNode::Assert {
+                condition, message, ..
+            } => {
+                self.type_of(condition);
+                if let Some(message) = message {
+                    self.type_of(message);
+                }
+            }
+            ASTNode::Yield { value, span } => {
+                let actual = self.type_of(value);
+                let expected = self
+                    .function
+                    .as_ref()
+                    .filter(|function| function.is_generator)
+                    .and_then(|function| function.return_type.clone());
+                if let Some(expected) = expected {
+                    if !expected.accepts(&actual) {
+                        self.error(
+                            format!(
+                                "Generator yields {} but is declared to yield {}",
+                                actual, expected
+                            ),
+                            *span,
+                        );
+                    }
+                }
+            }
+            expression => {
+                self.type_of(expression);
+            }
+        }
+    }
+
+    /// Checks a function body and, when inferring, returns the result type of
+    /// an unannotated function.
+    fn check_function(
+        &mut self,
+        params: &[Param],
+        return_type: &Option<Type>,
+        body: &[ASTNode],
+        is_generator: bool,
+    ) -> Option<Type> {
+        // A function body sees its own scopes and the globals, like the VM.
+        let outer_scopes = self.scopes.split_off(1);
+        let params = params
+            .iter()
+            .map(|param| {
+                let binding = Binding {
+                    ty: param.ty.clone().unwrap_or(Type::Any),
+                    declared: param.ty.is_some(),
+                };
+                (param.name.clone(), binding)
+            })
+            .collect();
+        self.scopes.push(params);
+        let outer_function = self.function.replace(FunctionContext {
+            return_type: return_type.clone(),
+            is_generator,
+            returns: Vec::new(),
+        });
+        self.check_body(body);
+        let function = std::mem::replace(&mut self.function, outer_function);
+        self.scopes.truncate(1);
+        self.scopes.extend(outer_scopes);
+
+        if !self.infer || return_type.is_some() || is_generator {
+            return None;
+        }
+        let mut returns = function.map(|function| function.returns).unwrap_or_default();
+        if !matches!(body.last(), Some(ASTNode::Return { .. })) {
+            returns.push(Type::Null);
+        }
+        returns
+            .split_first()
+            .map(|(first, rest)| rest.iter().fold(first.clone(), |acc, ty| acc.join(ty)))
+    }
+
+    /// Binds `name` to a value of type `actual`, honouring an annotation
+    /// given here or on the nearest earlier declaration of `name`, in this
+    /// scope or an enclosing one.
+    fn assign(&mut self, name: &str, ty: Option<Type>, actual: Type, span: SourceSpan) {
+        let expected = ty.or_else(|| {
+            self.lookup(name)
+                .filter(|binding| binding.declared)
+                .map(|binding| binding.ty.clone())
+        });
+        match expected {
+            Some(expected) => {
+                if !expected.accepts(&actual) {
+                    self.error(
+                        format!(
+                            "Cannot assign a value of type {} to `{}` declared as {}",
+                            actual, name, expected
+                        ),
+                        span,
+                    );
+                }
+                self.bind(
+                    name,
+                    Binding {
+                        ty: expected,
+                        declared: true,
+                    },
+                );
+            }
+            None => {
+                let ty = if self.infer { actual } else { Type::Any };
+                self.bind(name, Binding { ty, declared: false });
+            }
+        }
+    }
+
+    fn type_of(&mut self, node: &ASTNode) -> Type {
+        match node {
+            ASTNode::Number(_) => Type::Int,
+            ASTNode::String(_) => Type::String,
+            ASTNode::Bytes(_) => Type::Bytes,
+            ASTNode::Null => Type::Null,
+            ASTNode::BinOp {
+                left,
+                op,
+                right,
+                span,
+            } => {
+                let left = self.type_of(left);
+                let right = self.type_of(right);
+                self.binary_op_type(op, &left, &right, *span)
+            }
+            ASTNode::VarRef(name) => self
+                .lookup(name)
+                .map_or(Type::Any, |binding| binding.ty.clone()),
+            ASTNode::Array(elements) => {
+                let types: Vec<Type> = elements.iter().map(|e| self.type_of(e)).collect();
+                let element = match types.split_first() {
+                    Some((first, rest)) if rest.iter().all(|ty| ty == first) => first.clone(),
+                    _ => Type::Any,
+                };
+                Type::Array(Box::new(element))
+            }
+            ASTNode::Comptime { body, .. } => {
+                self.scopes.push(HashMap::new());
+                let ty = match body.split_last() {
+                    Some((last, rest)) if !matches!(last, ASTNode::FnDecl { .. }) => {
+                        self.hoist(body);
+                        self.check_body(rest);
+                        self.type_of(last)
+                    }
+                    _ => {
+                        self.check_body(body);
+                        Type::Null
+                    }
+                };
+                self.scopes.pop();
+                ty
+            }
+            ASTNode::Tuple(elements) => {
+                Type::Tuple(elements.iter().map(|e| self.type_of(e)).collect())
+            }
+            ASTNode::ArrayIndex {
+                array,
+                index,
+                optional,
+                span,
+            } => self.index_type(array, index, *optional, *span),
+            ASTNode::NullCoalesce { left, right } => {
+                let left = self.type_of(left);
+                let right = self.type_of(right);
+                match left {
+                    Type::Null => right,
+                    Type::Any => left.join(&right),
+                    left => left,
+                }
+            }
+            ASTNode::Call { callee, args, span } => {
+                let arg_types: Vec<Type> = args.iter().map(|arg| self.type_of(arg)).collect();
+                match callee.as_ref() {
+                    ASTNode::VarRef(name) => self.check_call(name, &arg_types, *span),
+                    callee => {
+                        self.type_of(callee);
+                        Type::Any
+                    }
+                }
+            }
+            ASTNode::FieldAccess { object, .. } => {
+                self.type_of(object);
+                Type::Any
+            }
+            ASTNode::MethodCall {
+                object,
+                method,
+                args,
+                ..
+            } => {
+                let object = self.type_of(object);
+                for arg in args {
+                    self.type_of(arg);
+                }
+                match object {
+                    Type::String | Type::Bytes | Type::Array(_) | Type::Tuple(_)
+                        if method == "len" && args.is_empty() =>
+                    {
+                        Type::Int
+                    }
+                    _ => Type::Any,
+                }
+            }
+            statement => {
+                self.check_statement(statement);
+                Type::Null
+            }
+        }
+    }
+
+    /// Checks `array[index]` and returns the element type.
+    fn index_type(
+        &mut self,
+        array: &ASTNode,
+        index: &ASTNode,
+        optional: bool,
+        span: SourceSpan,
+    ) -> Type {
+        let array = self.type_of(array);
+        let position = match index {
+            ASTNode::Number(n) => usize::try_from(*n).ok(),
+            _ => None,
+        };
+        let index = self.type_of(index);
+        if optional && array == Type::Null {
+            return Type::Null;
+        }
+        if !Type::Int.accepts(&index) {
+            self.error(format!("Array index must be int, got {}", index), span);
+        }
+        match array {
+            Type::Array(element) => *element,
+            Type::String => Type::Char,
+            Type::Bytes => Type::Int,
+            Type::Tuple(elements) => match position {
+                Some(position) if position < elements.len() => elements[position].clone(),
+                Some(position) => {
+                    self.error(
+                        format!(
+                            "Tuple index {} is out of bounds for {}",
+                            position,
+                            Type::Tuple(elements)
+                        ),
+                        span,
+                    );
+                    Type::Any
+                }
+                None => elements
+                    .split_first()
+                    .map_or(Type::Any, |(first, rest)| {
+                        rest.iter().fold(first.clone(), |acc, ty| acc.join(ty))
+                    }),
+            },
+            Type::Any => Type::Any,
+            other => {
+                self.error(format!("Cannot index into a value of type {}", other), span);
+                Type::Any
+            }
+        }
+    }
+
+    fn check_call(&mut self, name: &str, args: &[Type], span: SourceSpan) -> Type {
+        // Only trust the signature while the name still refers to the function.
+        match self.lookup(name).map(|binding| &binding.ty) {
+            Some(Type::Class) => return Type::Object,
+            Some(Type::Function) | None => {}
+            Some(_) => return Type::Any,
+        }
+        let Some(signature) = self.signatures.get(name).cloned() else {
+            return Type::Any;
+        };
+        if signature.params.len() != args.len() {
+            self.error(
+                format!(
+                    "Function {} expects {} arguments, got {}",
+                    name,
+                    signature.params.len(),
+                    args.len()
+                ),
+                span,
+            );
+        }
+        for (position, (expected, actual)) in signature.params.iter().zip(args).enumerate() {
+            if !expected.accepts(actual) {
+                self.error(
+                    format!(
+                        "Argument {} of {} expects {}, got {}",
+                        position + 1,
+                        name,
+                        expected,
+                        actual
+                    ),
+                    span,
+                );
+            }
+        }
+        if signature.is_generator {
+            Type::Generator
+        } else {
+            signature.return_type
+        }
+    }
+
+    /// Types a binary operation by running the operator from `types.rs` on
+    /// sample values of the operand types.
+    fn binary_op_type(&mut self, op: &Token, left: &Type, right: &Type, span: SourceSpan) -> Type {
+        let (Some(a), Some(b)) = (left.sample(), right.sample()) else {
+            return match op {
+                Token::Equal | Token::NotEqual => Type::Bool,
+                _ => Type::Any,
+            };
+        };
+        let (verb, result) = match op {
+            Token::Plus => ("add", a.add(&b)),
+            Token::Minus => ("subtract", a.sub(&b)),
+            Token::Star => ("multiply", a.mul(&b)),
+            Token::Slash => ("divide", a.div(&b)),
+            Token::Greater => ("compare", a.gt(&b).map(Value::Boolean)),
+            Token::Less => ("compare", a.lt(&b).map(Value::Boolean)),
+            Token::Equal | Token::NotEqual => return Type::Bool,
+            _ => return Type::Any,
+        };
+        match result {
+            Ok(value) => {
+                let result = Type::of_value(&value);
+                // The runtime accepts some mixed operands (e.g. `1 + "a"`) by
+                // converting one side; inference flags those as suspicious.
+                if self.infer && left != right {
+                    let symbol = match op {
+                        Token::Plus => "+",
+                        Token::Minus => "-",
+                        Token::Star => "*",
+                        Token::Slash => "/",
+                        Token::Greater => ">",
+                        _ => "<",
+                    };
+                    self.warning(
+                        format!(
+                            "Mixing {} and {} with `{}` implicitly produces {}",
+                            left, right, symbol, result
+                        ),
+                        span,
+                    );
+                }
+                result
+            }
+            Err(_) => {
+                let message = match op {
+                    Token::Greater => format!("Cannot compare {} and {} with >", left, right),
+                    Token::Less => format!("Cannot compare {} and {} with <", left, right),
+                    _ => format!("Cannot {} {} and {}", verb, left, right),
+                };
+                self.error(message, span);
+                Type::Any
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+
+    /// The messages of the errors `checker` finds in `source`, with the
+    /// source text each one points at.
+    fn errors_with(source: &str, mut checker: TypeChecker) -> Vec<(String, String)> {
+        let nodes = Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .expect("test program parses");
+        let errors = match checker.check_program(&nodes) {
+            Ok(()) => return Vec::new(),
+            Err(VMError::TypeCheckFailed { errors }) => errors,
+            Err(error) => vec![error],
+        };
+        errors
+            .into_iter()
+            .map(|error| match error {
+                VMError::TypeCheckError { message, span, .. } => {
+                    (message, source[span.offset()..span.offset() + span.len()].to_string())
+                }
+                other => panic!("unexpected error {:?}", other),
+            })
+            .collect()
+    }
+
+    fn errors(source: &str) -> Vec<(String, String)> {
+        errors_with(source, TypeChecker::new(source))
+    }
+
+    fn inferred_errors(source: &str) -> Vec<(String, String)> {
+        errors_with(source, TypeChecker::inferring(source))
+    }
+
+    /// The messages of the warnings inference gives for `source`.
+    fn warnings(source: &str) -> Vec<String> {
+        let nodes = Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .expect("test program parses");
+        let mut checker = TypeChecker::inferring(source);
+        let _ = checker.check_program(&nodes);
+        checker.take_warnings().iter().map(ToString::to_string).collect()
+    }
+
+    fn error(message: &str, at: &str) -> Vec<(String, String)> {
+        vec![(message.to_string(), at.to_string())]
+    }
+
+    #[test]
+    fn annotated_declarations_are_checked() {
+        assert_eq!(
+            errors("x: int = \"a\""),
+            error("Cannot assign a value of type string to `x` declared as int", "x: int = \"a\"")
+        );
+        assert_eq!(errors("x: int = 1\ns: string = \"a\"\nt: (int, string) = (x, s)"), vec![]);
+    }
+
+    #[test]
+    fn later_assignments_keep_the_annotation() {
+        assert_eq!(
+            errors("x: int = 1\nx = \"s\""),
+            error("Cannot assign a value of type string to `x` declared as int", "x = \"s\"")
+        );
+    }
+
+    #[test]
+    fn assignments_in_inner_blocks_keep_the_annotation() {
+        assert_eq!(
+            errors("x: int = 1\n{ { x = \"s\" } }"),
+            error("Cannot assign a value of type string to `x` declared as int", "x = \"s\"")
+        );
+        assert_eq!(
+            errors("fn f() { y: string = \"a\"\nif (y == \"a\") { y = 3 } }"),
+            error("Cannot assign a value of type int to `y` declared as string", "y = 3")
+        );
+    }
+
+    #[test]
+    fn unannotated_variables_take_anything() {
+        assert_eq!(errors("x = 1\nx = \"s\"\n{ x = [1] }"), vec![]);
+    }
+
+    #[test]
+    fn calls_and_returns_follow_signatures() {
+        assert_eq!(
+            errors("fn f(a: int) -> string { return a }"),
+            error("Function returns int but is declared to return string", "return a")
+        );
+        assert_eq!(
+            errors("fn f(a: int) { return a }\nf(\"s\")"),
+            error("Argument 1 of f expects int, got string", "f(\"s\")")
+        );
+    }
+
+    #[test]
+    fn annotated_arrays_only_take_their_element_type() {
+        assert_eq!(
+            errors("x: [int] = [1, 2]\nx[0] = \"s\""),
+            error("Cannot store a value of type string in [int]", "x[0] = \"s\"")
+        );
+    }
+
+    #[test]
+    fn inference_types_array_elements() {
+        assert_eq!(
+            inferred_errors("x = [1, 2, 3]\ny = x[0] - \"a\""),
+            error("Cannot subtract int and string", "x[0] - \"a\"")
+        );
+        assert_eq!(inferred_errors("x = [1, 2]\ny = x[0] * 2"), vec![]);
+    }
+
+    #[test]
+    fn inference_rejects_indexing_non_arrays() {
+        assert_eq!(
+            inferred_errors("x = 5\ny = x[0]"),
+            error("Cannot index into a value of type int", "x[0]")
+        );
+    }
+
+    #[test]
+    fn inference_follows_reassignment_and_function_results() {
+        assert_eq!(
+            inferred_errors("x = 1\nx = \"s\"\ny = x - 1"),
+            error("Cannot subtract string and int", "x - 1")
+        );
+        assert_eq!(
+            inferred_errors("fn f() { return 1 }\ny = f() - \"s\""),
+            error("Cannot subtract int and string", "f() - \"s\"")
+        );
+    }
+
+    #[test]
+    fn inference_warns_about_implicit_string_conversion() {
+        assert_eq!(
+            warnings("x = [1, 2, 3]\ny = x[0] + \"a\""),
+            vec!["Warning: Mixing int and string with `+` implicitly produces string"]
+        );
+        assert_eq!(inferred_errors("x = [1, 2, 3]\ny = x[0] + \"a\""), vec![]);
+    }
+
+    #[test]
+    fn unannotated_code_is_only_checked_when_inferring() {
+        assert_eq!(errors("x = 5\ny = x[0]\nz = y - \"s\""), vec![]);
+    }
+}
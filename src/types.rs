@@ -1,31 +1,96 @@
 use crate::error::VMError;
+use crate::vm::Generator;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub entry: usize,
+    pub is_generator: bool,
+}
+
+/// A class and its method table. Methods take the receiver as their first
+/// parameter, `self`.
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<String, Rc<Function>>,
+}
+
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: HashMap<String, Value>,
+}
+
+impl fmt::Debug for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Instance")
+            .field("class", &self.class.name)
+            .field("fields", &self.fields)
+            .finish()
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Value {
     Number(i32),
     Boolean(bool),
     Array(Vec<Value>),
-    String(String),
+    Tuple(Rc<[Value]>),
+    String(Rc<str>),
+    Char(char),
+    Bytes(Vec<u8>),
+    Function(Rc<Function>),
+    Generator(Rc<RefCell<Generator>>),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
     Null,
 }
 
 impl Value {
-    fn type_name(&self) -> &'static str {
+    pub fn type_name(&self) -> &'static str {
         match self {
             Value::Number(_) => "number",
             Value::Boolean(_) => "boolean",
             Value::Array(_) => "array",
+            Value::Tuple(_) => "tuple",
             Value::String(_) => "string",
+            Value::Char(_) => "char",
+            Value::Bytes(_) => "bytes",
+            Value::Function(_) => "function",
+            Value::Generator(_) => "generator",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
             Value::Null => "null",
         }
     }
 
-    fn as_array_mut(&mut self) -> Result<&mut Vec<Value>, VMError> {
+    /// The number of elements of an indexable value. Strings are measured in
+    /// characters, not bytes.
+    pub fn length(&self) -> Option<usize> {
         match self {
-            Value::Array(arr) => Ok(arr),
-            _ => Err(VMError::TypeError {
-                message: format!("Expected array, got {}", self.type_name()),
-            }),
+            Value::Array(items) => Some(items.len()),
+            Value::Tuple(items) => Some(items.len()),
+            Value::String(s) => Some(s.chars().count()),
+            Value::Bytes(bytes) => Some(bytes.len()),
+            _ => None,
+        }
+    }
+
+    /// The element at `index` of an indexable value: a `Char` for strings and
+    /// a number for byte strings.
+    pub fn element(&self, index: usize) -> Option<Value> {
+        match self {
+            Value::Array(items) => items.get(index).cloned(),
+            Value::Tuple(items) => items.get(index).cloned(),
+            Value::String(s) => s.chars().nth(index).map(Value::Char),
+            Value::Bytes(bytes) => bytes.get(index).map(|&b| Value::Number(b as i32)),
+            _ => None,
         }
     }
 
@@ -34,7 +99,11 @@ impl Value {
             Value::Number(n) => *n > 0,
             Value::Boolean(b) => *b,
             Value::Array(arr) => !arr.is_empty(),
+            Value::Tuple(items) => !items.is_empty(),
             Value::String(s) => !s.is_empty(),
+            Value::Char(_) => true,
+            Value::Bytes(bytes) => !bytes.is_empty(),
+            Value::Function(_) | Value::Generator(_) | Value::Class(_) | Value::Instance(_) => true,
             Value::Null => false,
         }
     }
@@ -56,17 +125,25 @@ pub trait VMCompare {
 pub trait VMArray {
     fn push(&mut self, value: Value) -> Result<(), VMError>;
     fn pop(&mut self) -> Result<Value, VMError>;
-    fn get(&self, index: Option<i32>) -> Result<Value, VMError>;
-    fn set(&mut self, index: Option<i32>, value: Value) -> Result<(), VMError>;
+}
+
+/// The result of integer arithmetic, which is an error when it does not
+/// fit in an int.
+fn number(result: Option<i32>) -> Result<Value, VMError> {
+    result.map(Value::Number).ok_or(VMError::IntegerOverflow)
 }
 
 impl VMBinaryOp for Value {
     fn add(&self, other: &Value) -> Result<Value, VMError> {
         match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
-            (Value::String(a), Value::String(b)) => Ok(Value::String(format!("{}{}", a, b))),
-            (Value::String(a), b) => Ok(Value::String(format!("{:?}{:?}", a, b))),
-            (a, Value::String(b)) => Ok(Value::String(format!("{:?}{:?}", a, b))),
+            (Value::Number(a), Value::Number(b)) => number(a.checked_add(*b)),
+            (Value::String(a), Value::String(b)) => Ok(Value::String(format!("{}{}", a, b).into())),
+            (Value::String(a), Value::Char(b)) => Ok(Value::String(format!("{}{}", a, b).into())),
+            (Value::Char(a), Value::String(b)) => Ok(Value::String(format!("{}{}", a, b).into())),
+            (Value::Char(a), Value::Char(b)) => Ok(Value::String(format!("{}{}", a, b).into())),
+            (Value::Bytes(a), Value::Bytes(b)) => Ok(Value::Bytes([&a[..], &b[..]].concat())),
+            (Value::String(a), b) => Ok(Value::String(format!("{:?}{:?}", a, b).into())),
+            (a, Value::String(b)) => Ok(Value::String(format!("{:?}{:?}", a, b).into())),
             _ => Err(VMError::TypeError {
                 message: format!("Cannot add {:?} and {:?}", self, other),
             }),
@@ -75,7 +152,7 @@ impl VMBinaryOp for Value {
 
     fn sub(&self, other: &Value) -> Result<Value, VMError> {
         match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
+            (Value::Number(a), Value::Number(b)) => number(a.checked_sub(*b)),
             _ => Err(VMError::TypeError {
                 message: format!("Cannot subtract {:?} and {:?}", self, other),
             }),
@@ -84,7 +161,7 @@ impl VMBinaryOp for Value {
 
     fn mul(&self, other: &Value) -> Result<Value, VMError> {
         match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
+            (Value::Number(a), Value::Number(b)) => number(a.checked_mul(*b)),
             _ => Err(VMError::TypeError {
                 message: format!("Cannot multiply {:?} and {:?}", self, other),
             }),
@@ -97,7 +174,7 @@ impl VMBinaryOp for Value {
                 if *b == 0 {
                     Err(VMError::DivisionByZero)
                 } else {
-                    Ok(Value::Number(a / b))
+                    number(a.checked_div(*b))
                 }
             }
             _ => Err(VMError::TypeError {
@@ -118,7 +195,16 @@ impl VMCompare for Value {
                 }
                 a.iter().zip(b.iter()).all(|(a, b)| a.eq(b))
             }
+            (Value::Tuple(a), Value::Tuple(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.eq(b))
+            }
             (Value::String(a), Value::String(b)) => a == b,
+            (Value::Char(a), Value::Char(b)) => a == b,
+            (Value::Bytes(a), Value::Bytes(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Generator(a), Value::Generator(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
             (Value::Null, Value::Null) => true,
             _ => false,
         }
@@ -128,6 +214,9 @@ impl VMCompare for Value {
         match (self, other) {
             (Value::Number(a), Value::Number(b)) => Ok(a < b),
             (Value::String(a), Value::String(b)) => Ok(a < b),
+            (Value::Char(a), Value::Char(b)) => Ok(a < b),
+            (Value::Bytes(a), Value::Bytes(b)) => Ok(a < b),
+            (Value::Tuple(a), Value::Tuple(b)) => Ok(compare_tuples(a, b)? == Ordering::Less),
             _ => Err(VMError::TypeError {
                 message: format!("Cannot compare {:?} and {:?} with <", self, other),
             }),
@@ -138,6 +227,9 @@ impl VMCompare for Value {
         match (self, other) {
             (Value::Number(a), Value::Number(b)) => Ok(a > b),
             (Value::String(a), Value::String(b)) => Ok(a > b),
+            (Value::Char(a), Value::Char(b)) => Ok(a > b),
+            (Value::Bytes(a), Value::Bytes(b)) => Ok(a > b),
+            (Value::Tuple(a), Value::Tuple(b)) => Ok(compare_tuples(a, b)? == Ordering::Greater),
             _ => Err(VMError::TypeError {
                 message: format!("Cannot compare {:?} and {:?} with >", self, other),
             }),
@@ -145,6 +237,24 @@ impl VMCompare for Value {
     }
 }
 
+/// Orders tuples lexicographically, element by element. Only the first pair
+/// of elements that differ is ordered, so equal elements need not be
+/// orderable.
+fn compare_tuples(a: &[Value], b: &[Value]) -> Result<Ordering, VMError> {
+    for (a, b) in a.iter().zip(b.iter()) {
+        if a.eq(b) {
+            continue;
+        }
+        if a.lt(b)? {
+            return Ok(Ordering::Less);
+        }
+        if a.gt(b)? {
+            return Ok(Ordering::Greater);
+        }
+    }
+    Ok(a.len().cmp(&b.len()))
+}
+
 impl VMArray for Value {
     fn push(&mut self, value: Value) -> Result<(), VMError> {
         match self {
@@ -165,45 +275,56 @@ impl VMArray for Value {
             _ => Err(VMError::NotAnArray),
         }
     }
+}
 
-    fn get(&self, index: Option<i32>) -> Result<Value, VMError> {
-        match self {
-            Value::Array(arr) => {
-                let idx = index.ok_or(VMError::IndexError {
-                    index: -1,
-                    len: arr.len(),
-                })?;
-                if idx < 0 || idx >= arr.len() as i32 {
-                    Err(VMError::IndexError {
-                        index: idx,
-                        len: arr.len(),
-                    })
-                } else {
-                    Ok(arr[idx as usize].clone())
-                }
-            }
-            _ => Err(VMError::NotAnArray),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tuple(items: Vec<Value>) -> Value {
+        Value::Tuple(items.into())
     }
 
-    fn set(&mut self, index: Option<i32>, value: Value) -> Result<(), VMError> {
-        match self {
-            Value::Array(arr) => {
-                let idx = index.ok_or(VMError::IndexError {
-                    index: -1,
-                    len: arr.len(),
-                })?;
-                if idx < 0 || idx >= arr.len() as i32 {
-                    Err(VMError::IndexError {
-                        index: idx,
-                        len: arr.len(),
-                    })
-                } else {
-                    arr[idx as usize] = value;
-                    Ok(())
-                }
-            }
-            _ => Err(VMError::NotAnArray),
-        }
+    #[test]
+    fn tuples_compare_element_by_element() {
+        let small = tuple(vec![Value::Number(1), Value::Number(5)]);
+        let large = tuple(vec![Value::Number(2), Value::Number(0)]);
+        assert!(small.lt(&large).unwrap());
+        assert!(large.gt(&small).unwrap());
+        assert!(!small.gt(&large).unwrap());
+    }
+
+    #[test]
+    fn shorter_tuples_order_first() {
+        let short = tuple(vec![Value::Number(1), Value::Number(2)]);
+        let long = tuple(vec![Value::Number(1), Value::Number(2), Value::Number(0)]);
+        assert!(short.lt(&long).unwrap());
+        assert!(!short.lt(&short).unwrap());
+    }
+
+    #[test]
+    fn equal_elements_need_not_be_orderable() {
+        let a = tuple(vec![Value::Null, Value::Number(1)]);
+        let b = tuple(vec![Value::Null, Value::Number(2)]);
+        assert!(a.lt(&b).unwrap());
+        assert!(b.gt(&a).unwrap());
+    }
+
+    #[test]
+    fn the_first_differing_elements_must_be_orderable() {
+        let a = tuple(vec![Value::Number(1), Value::Null]);
+        let b = tuple(vec![Value::Number(1), Value::Number(2)]);
+        assert!(a.lt(&b).is_err());
+    }
+
+    #[test]
+    fn arithmetic_that_does_not_fit_is_an_error() {
+        let n = Value::Number;
+        assert!(matches!(n(i32::MAX).add(&n(1)), Err(VMError::IntegerOverflow)));
+        assert!(matches!(n(i32::MIN).sub(&n(1)), Err(VMError::IntegerOverflow)));
+        assert!(matches!(n(65536).mul(&n(65536)), Err(VMError::IntegerOverflow)));
+        assert!(matches!(n(i32::MIN).div(&n(-1)), Err(VMError::IntegerOverflow)));
+        assert!(matches!(n(1).div(&n(0)), Err(VMError::DivisionByZero)));
+        assert!(matches!(n(i32::MAX - 1).add(&n(1)), Ok(Value::Number(i32::MAX))));
     }
 }
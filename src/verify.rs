@@ -0,0 +1,553 @@
+use crate::bytecode::Bytecode;
+use crate::error::VMError;
+use crate::types::{Function, Value};
+use crate::vm::{ArrayOperation, Instruction, Program};
+
+/// What started the code an instruction belongs to, which decides how
+/// that code may end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Context {
+    /// The program itself, which runs until it falls off the end.
+    Main,
+    /// A function body, which only ends by returning.
+    Function,
+    /// The body of a generator function, which may also yield.
+    Generator,
+    /// A deferred action, which ends with `EndDefer`.
+    Deferred,
+}
+
+/// What is known before an instruction runs: its context, how many values
+/// its frame (or deferred action) has on the stack and how many scopes it
+/// has begun and not yet ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct State {
+    context: Context,
+    depth: usize,
+    scopes: usize,
+}
+
+impl State {
+    fn start(context: Context) -> Self {
+        State {
+            context,
+            depth: 0,
+            scopes: 0,
+        }
+    }
+}
+
+/// How many values an instruction takes off the stack and how many it
+/// leaves in their place when it carries on with the next instruction.
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    match instruction {
+        Instruction::Push(_)
+        | Instruction::PushConst(_)
+        | Instruction::LoadLocal(_)
+        | Instruction::LoadGlobal(_)
+        | Instruction::CreateArray => (0, 1),
+        Instruction::Pop
+        | Instruction::Jz(_)
+        | Instruction::StoreLocal(_)
+        | Instruction::StoreGlobal(_)
+        | Instruction::Return
+        | Instruction::Yield => (1, 0),
+        Instruction::Add
+        | Instruction::Sub
+        | Instruction::Mul
+        | Instruction::Div
+        | Instruction::Greater
+        | Instruction::Less
+        | Instruction::Equal
+        | Instruction::NotEqual => (2, 1),
+        Instruction::JmpIfNull(_)
+        | Instruction::JmpIfNotNull(_)
+        | Instruction::TeeLocal(_)
+        | Instruction::TeeGlobal(_)
+        | Instruction::GetField(_) => (1, 1),
+        Instruction::Jmp(_)
+        | Instruction::Label(_)
+        | Instruction::BeginScope
+        | Instruction::EndScope
+        | Instruction::Defer(_)
+        | Instruction::EndDefer => (0, 0),
+        Instruction::ArrayOp(ArrayOperation::Push | ArrayOperation::Get(_)) => (2, 1),
+        Instruction::ArrayOp(ArrayOperation::Pop) => (1, 2),
+        Instruction::ArrayOp(ArrayOperation::Set(_)) => (3, 1),
+        // The callee and its arguments make way for what the call returns.
+        Instruction::Call(argc) | Instruction::CallMethod(_, argc) => (argc.saturating_add(1), 1),
+        // The iterable and cursor stay, and the next item goes on top.
+        Instruction::ForIter(_) => (2, 3),
+        Instruction::Assert { has_message, .. } => (1 + *has_message as usize, 0),
+        Instruction::MakeTuple(count) | Instruction::MakeClass(_, count) => (*count, 1),
+        Instruction::UnpackTuple(count) => (1, *count),
+        Instruction::SetField(_) => (2, 0),
+    }
+}
+
+/// Collects the functions in the constant pool, including those nested in
+/// arrays and tuples. It keeps a list of values still to look at rather
+/// than recursing, so nesting depth cannot overflow the stack.
+fn functions(constants: &[Value]) -> Vec<&Function> {
+    let mut found = Vec::new();
+    let mut pending: Vec<&Value> = constants.iter().collect();
+    while let Some(value) = pending.pop() {
+        match value {
+            Value::Function(function) => found.push(function.as_ref()),
+            Value::Array(items) => pending.extend(items.iter()),
+            Value::Tuple(items) => pending.extend(items.iter()),
+            _ => {}
+        }
+    }
+    found
+}
+
+/// Follows every path through the code, from the start of the program and
+/// from every function entry and deferred action, working out the state
+/// before each instruction it reaches. An instruction reached along two
+/// paths has to be reached in the same state.
+struct Verifier<'a> {
+    instructions: &'a [Instruction],
+    /// The address of each instruction, and then that of the end of the
+    /// code.
+    addresses: &'a [usize],
+    constants: &'a [Value],
+    globals: usize,
+    states: Vec<Option<State>>,
+    pending: Vec<usize>,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&self, index: usize, message: impl Into<String>) -> VMError {
+        VMError::VerifyError {
+            address: self.addresses[index],
+            message: message.into(),
+        }
+    }
+
+    fn end(&self) -> usize {
+        self.addresses[self.instructions.len()]
+    }
+
+    /// The instruction at `target`, or the end of the code, as jumped to
+    /// from instruction `from`.
+    fn target(&self, from: usize, target: usize) -> Result<usize, VMError> {
+        if target > self.end() {
+            return Err(VMError::InvalidJump {
+                target,
+                max: self.end(),
+            });
+        }
+        self.addresses.binary_search(&target).map_err(|_| {
+            self.error(
+                from,
+                format!("jump to {}, which is inside an instruction", target),
+            )
+        })
+    }
+
+    /// Goes on to instruction `index` from `from` in `state`.
+    fn enter(&mut self, from: usize, index: usize, state: State) -> Result<(), VMError> {
+        if index == self.instructions.len() {
+            return match state {
+                State {
+                    context: Context::Main,
+                    scopes: 0,
+                    ..
+                } => Ok(()),
+                State {
+                    context: Context::Main,
+                    scopes,
+                    ..
+                } => Err(self.error(
+                    from,
+                    format!("scopes still open at the end of the code: {}", scopes),
+                )),
+                _ => Err(self.error(from, "runs off the end of the code")),
+            };
+        }
+        match self.states[index] {
+            None => {
+                self.states[index] = Some(state);
+                self.pending.push(index);
+                Ok(())
+            }
+            Some(known) if known == state => Ok(()),
+            Some(known) if known.context != state.context => Err(self.error(
+                index,
+                format!(
+                    "reached as part of both {:?} and {:?} code",
+                    known.context, state.context
+                ),
+            )),
+            Some(known) => Err(self.error(
+                index,
+                format!(
+                    "reached with stack depth {} and scope depth {}, and also with {} and {}",
+                    known.depth, known.scopes, state.depth, state.scopes
+                ),
+            )),
+        }
+    }
+
+    /// Starts following the body of `function`.
+    fn function(&mut self, function: &Function) -> Result<(), VMError> {
+        match self.addresses.binary_search(&function.entry) {
+            Ok(index) if index < self.instructions.len() => {
+                let context = if function.is_generator {
+                    Context::Generator
+                } else {
+                    Context::Function
+                };
+                self.enter(index, index, State::start(context))
+            }
+            _ => Err(VMError::VerifyError {
+                address: function.entry,
+                message: format!("function {} does not start at an instruction", function.name),
+            }),
+        }
+    }
+
+    fn check_constant(&self, index: usize, constant: u32) -> Result<(), VMError> {
+        if constant as usize >= self.constants.len() {
+            return Err(self.error(
+                index,
+                format!(
+                    "constant {} out of range ({} in the pool)",
+                    constant,
+                    self.constants.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_global(&self, index: usize, global: u32) -> Result<(), VMError> {
+        if global as usize >= self.globals {
+            return Err(self.error(
+                index,
+                format!("global {} out of range ({} in the table)", global, self.globals),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks instruction `index` and goes on to where it leads.
+    fn step(&mut self, index: usize) -> Result<(), VMError> {
+        let state = self.states[index].expect("pending instructions have a state");
+        let instruction = &self.instructions[index];
+        let (pops, pushes) = stack_effect(instruction);
+        let Some(depth) = state.depth.checked_sub(pops) else {
+            return Err(self.error(
+                index,
+                format!(
+                    "{:?} pops {} but the stack only holds {}",
+                    instruction, pops, state.depth
+                ),
+            ));
+        };
+        let after = State {
+            depth: depth
+                .checked_add(pushes)
+                .ok_or_else(|| self.error(index, "stack depth overflows"))?,
+            ..state
+        };
+        let next = index + 1;
+        match instruction {
+            Instruction::Push(Value::Function(function)) => self.function(function)?,
+            Instruction::PushConst(constant) => self.check_constant(index, *constant)?,
+            Instruction::LoadGlobal(global)
+            | Instruction::StoreGlobal(global)
+            | Instruction::TeeGlobal(global) => self.check_global(index, *global)?,
+            Instruction::Label(label) => {
+                return Err(self.error(index, format!("label {} was never linked", label)));
+            }
+            Instruction::Jmp(target) => {
+                let target = self.target(index, *target)?;
+                return self.enter(index, target, after);
+            }
+            Instruction::Jz(target) | Instruction::JmpIfNull(target) | Instruction::JmpIfNotNull(target) => {
+                let target = self.target(index, *target)?;
+                self.enter(index, target, after)?;
+            }
+            Instruction::ForIter(exit) => {
+                // An exhausted loop drops the iterable and cursor.
+                let exit = self.target(index, *exit)?;
+                self.enter(index, exit, State { depth, ..state })?;
+            }
+            Instruction::BeginScope => {
+                return self.enter(
+                    index,
+                    next,
+                    State {
+                        scopes: state.scopes + 1,
+                        ..after
+                    },
+                );
+            }
+            Instruction::EndScope => {
+                let Some(scopes) = state.scopes.checked_sub(1) else {
+                    return Err(self.error(index, "EndScope without a matching BeginScope"));
+                };
+                return self.enter(index, next, State { scopes, ..after });
+            }
+            Instruction::Defer(action) => {
+                let action = self.target(index, *action)?;
+                self.enter(index, action, State::start(Context::Deferred))?;
+            }
+            Instruction::Return | Instruction::Yield if state.context == Context::Deferred => {
+                return Err(self.error(index, format!("{:?} inside a deferred action", instruction)));
+            }
+            Instruction::Return if state.context == Context::Main => {
+                return Err(self.error(index, "Return outside a function"));
+            }
+            Instruction::Yield if state.context != Context::Generator => {
+                return Err(self.error(index, "Yield outside a generator"));
+            }
+            // A function may return from inside its scopes; they all end
+            // with its frame.
+            Instruction::Return => return Ok(()),
+            Instruction::EndDefer => {
+                return match state {
+                    State {
+                        context: Context::Deferred,
+                        depth: 0,
+                        scopes: 0,
+                    } => Ok(()),
+                    State {
+                        context: Context::Deferred,
+                        depth,
+                        scopes,
+                    } => Err(self.error(
+                        index,
+                        format!(
+                            "deferred action ends with stack depth {} and scope depth {}",
+                            depth, scopes
+                        ),
+                    )),
+                    _ => Err(self.error(index, "EndDefer outside a deferred action")),
+                };
+            }
+            _ => {}
+        }
+        self.enter(index, next, after)
+    }
+
+    fn run(mut self) -> Result<(), VMError> {
+        for function in functions(self.constants) {
+            self.function(function)?;
+        }
+        self.enter(0, 0, State::start(Context::Main))?;
+        while let Some(index) = self.pending.pop() {
+            self.step(index)?;
+        }
+        Ok(())
+    }
+}
+
+fn verify_code(
+    instructions: &[Instruction],
+    addresses: &[usize],
+    constants: &[Value],
+    globals: usize,
+) -> Result<(), VMError> {
+    Verifier {
+        instructions,
+        addresses,
+        constants,
+        globals,
+        states: vec![None; instructions.len()],
+        pending: Vec::new(),
+    }
+    .run()
+}
+
+/// Checks `program` without running it. Along every path through it, no
+/// instruction may take more values off the stack than are there, every
+/// jump has to land on an instruction or the end of the code, every scope
+/// that begins has to end, and only functions may return and only
+/// generators yield. Code that passes can still fail at run time, but only
+/// because of the values it works with.
+pub fn verify(program: &Program) -> Result<(), VMError> {
+    let addresses: Vec<usize> = (0..=program.instructions.len()).collect();
+    verify_code(
+        &program.instructions,
+        &addresses,
+        &program.constants,
+        program.globals.len(),
+    )
+}
+
+/// Like `verify`, for encoded code, which first has to decode into whole
+/// instructions from start to end.
+pub fn verify_bytecode(bytecode: &Bytecode) -> Result<(), VMError> {
+    let mut instructions = Vec::new();
+    let mut addresses = Vec::new();
+    let mut at = 0;
+    while at < bytecode.code.len() {
+        let (instruction, next) = bytecode.decode(at)?;
+        instructions.push(instruction);
+        addresses.push(at);
+        at = next;
+    }
+    addresses.push(at);
+    verify_code(
+        &instructions,
+        &addresses,
+        &bytecode.constants,
+        bytecode.globals.len(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+    use crate::vm::Compiler;
+    use std::rc::Rc;
+
+    fn program(instructions: Vec<Instruction>) -> Program {
+        Program::new(instructions, vec!["g".to_string()])
+    }
+
+    fn function(entry: usize, is_generator: bool) -> Value {
+        Value::Function(Rc::new(Function {
+            name: "f".to_string(),
+            params: Vec::new(),
+            entry,
+            is_generator,
+        }))
+    }
+
+    /// The address and message `instructions` are rejected with.
+    fn rejected(instructions: Vec<Instruction>) -> (usize, String) {
+        match verify(&program(instructions)) {
+            Err(VMError::VerifyError { address, message }) => (address, message),
+            other => panic!("expected a verify error, got {:?}", other),
+        }
+    }
+
+    fn num(n: i32) -> Instruction {
+        Instruction::Push(Value::Number(n))
+    }
+
+    #[test]
+    fn compiled_programs_pass() {
+        let source = "fn* count(n) { i = 0; while (i < n) { yield i; i = i + 1 } }\nfn f(a) { defer g(); { b = a }; return [a, (a, a)] }\ns = 0\nfor x in count(3) { defer g(); s = s + x }";
+        let nodes = Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .unwrap();
+        let program = Compiler::default().compile_program(nodes).unwrap();
+        verify(&program).unwrap();
+        verify_bytecode(&Bytecode::encode(&program)).unwrap();
+    }
+
+    #[test]
+    fn rejects_stack_underflow() {
+        assert_eq!(
+            rejected(vec![num(1), Instruction::Add]),
+            (1, "Add pops 2 but the stack only holds 1".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_scopes() {
+        assert_eq!(
+            rejected(vec![Instruction::EndScope]),
+            (0, "EndScope without a matching BeginScope".to_string())
+        );
+        assert_eq!(
+            rejected(vec![Instruction::BeginScope]),
+            (0, "scopes still open at the end of the code: 1".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_jump_targets() {
+        assert!(matches!(
+            verify(&program(vec![Instruction::Jmp(5)])),
+            Err(VMError::InvalidJump { target: 5, max: 1 })
+        ));
+        // In bytecode, addresses are byte offsets that must start an
+        // instruction.
+        let bytecode = Bytecode::encode(&program(vec![Instruction::Jmp(1), num(300)]));
+        let mut inside = bytecode.clone();
+        inside.code[1] = 3;
+        assert!(matches!(
+            verify_bytecode(&inside),
+            Err(VMError::VerifyError { address: 0, ref message })
+                if message == "jump to 3, which is inside an instruction"
+        ));
+        verify_bytecode(&bytecode).unwrap();
+    }
+
+    #[test]
+    fn rejects_inconsistent_depths_where_paths_join() {
+        // The taken branch reaches the Pop with nothing to pop.
+        assert_eq!(
+            rejected(vec![
+                num(1),
+                Instruction::Jz(3),
+                num(2),
+                Instruction::Pop,
+            ]),
+            (3, "reached with stack depth 0 and scope depth 0, and also with 1 and 0".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_return_and_yield_where_they_cannot_run() {
+        assert_eq!(
+            rejected(vec![num(1), Instruction::Return]),
+            (1, "Return outside a function".to_string())
+        );
+        assert_eq!(
+            rejected(vec![
+                Instruction::Defer(3),
+                Instruction::Jmp(6),
+                Instruction::Pop,
+                num(1),
+                Instruction::Return,
+                Instruction::EndDefer,
+                Instruction::BeginScope,
+                Instruction::EndScope,
+            ]),
+            (4, "Return inside a deferred action".to_string())
+        );
+
+        // A body that yields: fine in a generator, not in a plain function.
+        let body = |is_generator| {
+            vec![
+                Instruction::Jmp(5),
+                num(1),
+                Instruction::Yield,
+                Instruction::Push(Value::Null),
+                Instruction::Return,
+                Instruction::Push(function(1, is_generator)),
+                Instruction::StoreGlobal(0),
+            ]
+        };
+        verify(&program(body(true))).unwrap();
+        assert_eq!(rejected(body(false)), (2, "Yield outside a generator".to_string()));
+        assert_eq!(
+            rejected(vec![num(1), Instruction::Yield]),
+            (1, "Yield outside a generator".to_string())
+        );
+    }
+
+    #[test]
+    fn finds_functions_nested_deep_in_constants() {
+        let mut nested = Value::Null;
+        for _ in 0..100_000 {
+            nested = Value::Array(vec![nested]);
+        }
+        let constants = [Value::Array(vec![function(0, false), nested])];
+        assert_eq!(functions(&constants).len(), 1);
+        // Dropping a value this deep recurses too, so take it apart first.
+        let [Value::Array(mut items)] = constants else { unreachable!() };
+        while let Some(Value::Array(inner)) = items.pop() {
+            items = inner;
+        }
+    }
+}
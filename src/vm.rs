@@ -1,8 +1,13 @@
-use crate::ast::ASTNode;
+use crate::ast::{ASTNode, Param};
+use crate::bytecode::{read_operand, Bytecode, Opcode};
 use crate::error::VMError;
+use crate::peephole;
 use crate::tokenizer::Token;
-use crate::types::{VMArray, VMBinaryOp, VMCompare, Value};
+use crate::types::{Class, Function, Instance, VMArray, VMBinaryOp, VMCompare, Value};
+use miette::SourceSpan;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub enum ArrayOperation {
@@ -14,7 +19,11 @@ pub enum ArrayOperation {
 
 #[derive(Debug, Clone)]
 pub enum Instruction {
+    /// A constant inline in the code. The compiler emits these while it
+    /// lays out fragments; `Program::new` moves them into the pool.
     Push(Value),
+    /// Pushes an entry of the program's constant pool.
+    PushConst(u32),
     Pop,
     Add,
     Sub,
@@ -26,20 +35,185 @@ pub enum Instruction {
     NotEqual,
     Jmp(usize),
     Jz(usize),
-    Label(String),
-    Store(String),
-    Load(String),
+    /// Jumps if the top of the stack is null, leaving it in place.
+    JmpIfNull(usize),
+    /// Jumps if the top of the stack is not null, leaving it in place.
+    JmpIfNotNull(usize),
+    /// Marks the position a label stands for. Until the code is linked,
+    /// code addresses in other instructions name labels rather than
+    /// positions.
+    Label(usize),
+    /// Reads a slot of the current frame's locals.
+    LoadLocal(u16),
+    StoreLocal(u16),
+    /// Reads an entry of the program's globals table.
+    LoadGlobal(u32),
+    StoreGlobal(u32),
+    /// Like `StoreLocal`, but leaves the value on the stack.
+    TeeLocal(u16),
+    /// Like `StoreGlobal`, but leaves the value on the stack.
+    TeeGlobal(u32),
     BeginScope,
     EndScope,
     CreateArray,
     ArrayOp(ArrayOperation),
+    Call(usize),
+    CallMethod(String, usize),
+    Return,
+    Yield,
+    /// Registers the deferred action starting at the target with the
+    /// current scope.
+    Defer(usize),
+    /// Ends a deferred action, resuming the instruction that ran it.
+    EndDefer,
+    ForIter(usize),
+    /// Pops the condition, preceded by the message when `has_message` is
+    /// set, and fails if the condition is falsy.
+    Assert {
+        text: String,
+        span: SourceSpan,
+        has_message: bool,
+    },
+    MakeTuple(usize),
+    UnpackTuple(usize),
+    MakeClass(String, usize),
+    GetField(String),
+    SetField(String),
+}
+
+/// Compiled code together with the constants and the names of the globals
+/// it refers to by index.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub constants: Vec<Value>,
+    pub globals: Vec<String>,
+}
+
+/// The constants that are pooled once however often they occur.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum ConstantKey {
+    Number(i32),
+    Boolean(bool),
+    String(Rc<str>),
+    Char(char),
+    Bytes(Vec<u8>),
+    Null,
+}
+
+impl ConstantKey {
+    pub fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(ConstantKey::Number(*n)),
+            Value::Boolean(b) => Some(ConstantKey::Boolean(*b)),
+            Value::String(s) => Some(ConstantKey::String(s.clone())),
+            Value::Char(c) => Some(ConstantKey::Char(*c)),
+            Value::Bytes(bytes) => Some(ConstantKey::Bytes(bytes.clone())),
+            Value::Null => Some(ConstantKey::Null),
+            _ => None,
+        }
+    }
+}
+
+impl Program {
+    /// Finishes laid-out code by moving its inline constants into the pool,
+    /// so that pushing one only clones a pooled value.
+    pub fn new(mut instructions: Vec<Instruction>, globals: Vec<String>) -> Self {
+        let mut constants = Vec::new();
+        let mut indices = HashMap::new();
+        for instruction in &mut instructions {
+            let Instruction::Push(value) = instruction else {
+                continue;
+            };
+            let value = std::mem::replace(value, Value::Null);
+            let key = ConstantKey::of(&value);
+            let index = match key.as_ref().and_then(|key| indices.get(key)) {
+                Some(&index) => index,
+                None => {
+                    let index = constants.len() as u32;
+                    constants.push(value);
+                    if let Some(key) = key {
+                        indices.insert(key, index);
+                    }
+                    index
+                }
+            };
+            *instruction = Instruction::PushConst(index);
+        }
+        Program {
+            instructions,
+            constants,
+            globals,
+        }
+    }
+}
+
+/// A lexical scope at run time: the entry points of its pending `defer`
+/// actions, run last-in first-out when the scope is left. Its variables live
+/// in the slots of the enclosing frame.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    pub deferred: Vec<usize>,
+}
+
+/// A generator activation parked at a `yield`: everything needed to pick the
+/// frame back up where it left off.
+#[derive(Debug, Clone)]
+pub struct SuspendedFrame {
+    pub ip: usize,
+    pub stack: Vec<Value>,
+    pub env: Vec<Scope>,
+    pub locals: Vec<Value>,
+}
+
+#[derive(Debug)]
+pub enum Generator {
+    Suspended(SuspendedFrame),
+    Running,
+    Done,
+}
+
+/// What to do when a resumed generator yields or runs to completion.
+#[derive(Debug, Clone, Copy)]
+enum Resume {
+    /// Resumed by `next()`: an exhausted generator produces `null`.
+    Next,
+    /// Resumed by `ForIter`: an exhausted generator ends the loop.
+    ForIter(usize),
+}
+
+#[derive(Debug)]
+struct CallFrame {
+    return_ip: usize,
+    env_base: usize,
+    stack_base: usize,
+    locals_base: usize,
+    generator: Option<(Rc<RefCell<Generator>>, Resume)>,
+    /// The instance being initialised when this frame runs a constructor; it
+    /// replaces whatever `init` returns.
+    constructing: Option<Value>,
+    /// Set when an overloaded `eq` runs on behalf of `NotEqual`.
+    negate_result: bool,
 }
 
 pub struct VM {
     pub stack: Vec<Value>,
     pub ip: usize,
-    pub env_stack: Vec<HashMap<String, Value>>,
+    /// The address of the instruction after the one running.
+    next_ip: usize,
+    pub env_stack: Vec<Scope>,
+    /// The local slots of every active frame, the innermost frame's last.
+    locals: Vec<Value>,
+    /// The values of the globals, indexed like `Program::globals`; `None`
+    /// until first assigned.
+    pub globals: Vec<Option<Value>>,
+    frames: Vec<CallFrame>,
+    /// Where to continue after each deferred action that is running.
+    defer_returns: Vec<usize>,
     max_stack_size: usize,
+    max_call_depth: usize,
+    /// How many more instructions may run, when execution is limited.
+    fuel: Option<usize>,
 }
 
 impl VM {
@@ -47,22 +221,60 @@ impl VM {
         VM {
             stack: Vec::new(),
             ip: 0,
-            env_stack: vec![HashMap::new()], // Start with global scope
+            next_ip: 0,
+            env_stack: vec![Scope::default()], // Start with global scope
+            locals: Vec::new(),
+            globals: Vec::new(),
+            frames: Vec::new(),
+            defer_returns: Vec::new(),
             max_stack_size: 4000, 
+            max_call_depth: 1000,
+            fuel: None,
         }
     }
 
-    fn current_env(&mut self) -> &mut HashMap<String, Value> {
-        self.env_stack.last_mut().expect("No environment on stack")
+    /// A VM that fails with `OutOfFuel` after running `fuel` instructions.
+    pub fn with_fuel(fuel: usize) -> Self {
+        VM {
+            fuel: Some(fuel),
+            ..VM::new()
+        }
     }
 
-    fn get_var(&self, name: &str) -> Option<Value> {
-        for env in self.env_stack.iter().rev() {
-            if let Some(value) = env.get(name) {
-                return Some(value.clone());
-            }
+    /// Where the current frame's local slots start; the top level is a
+    /// frame of its own at 0.
+    fn locals_base(&self) -> usize {
+        self.frames.last().map_or(0, |frame| frame.locals_base)
+    }
+
+    fn set_local(&mut self, slot: u16, value: Value) {
+        let slot = self.locals_base() + slot as usize;
+        if slot >= self.locals.len() {
+            self.locals.resize(slot + 1, Value::Null);
+        }
+        self.locals[slot] = value;
+    }
+
+    fn set_global(&mut self, index: u32, value: Value) {
+        let index = index as usize;
+        if index >= self.globals.len() {
+            self.globals.resize(index + 1, None);
         }
-        None
+        self.globals[index] = Some(value);
+    }
+
+    /// Pairs each global that has been assigned with its name.
+    pub fn global_values<'a>(&'a self, program: &'a Program) -> Vec<(&'a str, &'a Value)> {
+        self.named_globals(&program.globals)
+    }
+
+    /// Like `global_values`, with the names of the globals given directly.
+    pub fn named_globals<'a>(&'a self, names: &'a [String]) -> Vec<(&'a str, &'a Value)> {
+        names
+            .iter()
+            .zip(&self.globals)
+            .filter_map(|(name, value)| Some((name.as_str(), value.as_ref()?)))
+            .collect()
     }
 
     fn push(&mut self, value: Value) -> Result<(), VMError> {
@@ -80,294 +292,1978 @@ impl VM {
         Ok(idx as usize)
     }
 
-    pub fn execute(&mut self, instructions: &[Instruction]) -> Result<(), VMError> {
-        let mut scope_depth = 0;
-        
-        while self.ip < instructions.len() {
-            match &instructions[self.ip] {
-                Instruction::Push(value) => {
-                    self.push(value.clone())?;
-                }
-                Instruction::Pop => {
-                    self.stack.pop().ok_or(VMError::StackUnderflow)?;
+    /// Validates a null-check jump and reports whether the value it inspects
+    /// is null.
+    fn top_is_null(&self, target: usize, max: usize) -> Result<bool, VMError> {
+        if target > max {
+            return Err(VMError::InvalidJump { target, max });
+        }
+        let top = self.stack.last().ok_or(VMError::StackUnderflow)?;
+        Ok(matches!(top, Value::Null))
+    }
+
+    fn push_frame(
+        &mut self,
+        env: Vec<Scope>,
+        locals: Vec<Value>,
+        generator: Option<(Rc<RefCell<Generator>>, Resume)>,
+    ) -> Result<(), VMError> {
+        if self.frames.len() >= self.max_call_depth {
+            return Err(VMError::StackOverflow);
+        }
+        self.frames.push(CallFrame {
+            return_ip: self.next_ip,
+            env_base: self.env_stack.len(),
+            stack_base: self.stack.len(),
+            locals_base: self.locals.len(),
+            generator,
+            constructing: None,
+            negate_result: false,
+        });
+        self.env_stack.extend(env);
+        self.locals.extend(locals);
+        Ok(())
+    }
+
+    fn call(&mut self, argc: usize) -> Result<(), VMError> {
+        if self.stack.len() < argc + 1 {
+            return Err(VMError::StackUnderflow);
+        }
+        let args = self.stack.split_off(self.stack.len() - argc);
+        match self.stack.pop() {
+            Some(Value::Function(function)) => self.invoke(&function, args),
+            Some(Value::Class(class)) => self.construct(class, args),
+            Some(other) => Err(VMError::TypeError {
+                message: format!("Cannot call a value of type {}", other.type_name()),
+            }),
+            None => Err(VMError::StackUnderflow),
+        }
+    }
+
+    /// Enters `function` with `args` in its first slots, or creates a
+    /// suspended generator if it is a generator function.
+    fn invoke(&mut self, function: &Function, args: Vec<Value>) -> Result<(), VMError> {
+        if function.params.len() != args.len() {
+            return Err(VMError::ArityMismatch {
+                name: function.name.clone(),
+                expected: function.params.len(),
+                got: args.len(),
+            });
+        }
+        if function.is_generator {
+            let frame = SuspendedFrame {
+                ip: function.entry,
+                stack: Vec::new(),
+                env: vec![Scope::default()],
+                locals: args,
+            };
+            self.push(Value::Generator(Rc::new(RefCell::new(Generator::Suspended(frame)))))?;
+            self.ip = self.next_ip;
+        } else {
+            self.push_frame(vec![Scope::default()], args, None)?;
+            self.ip = function.entry;
+        }
+        Ok(())
+    }
+
+    fn construct(&mut self, class: Rc<Class>, args: Vec<Value>) -> Result<(), VMError> {
+        let instance = Value::Instance(Rc::new(RefCell::new(Instance {
+            class: class.clone(),
+            fields: HashMap::new(),
+        })));
+        match class.methods.get("init") {
+            Some(init) if init.is_generator => Err(VMError::TypeError {
+                message: format!("Constructor of {} cannot be a generator", class.name),
+            }),
+            Some(init) => {
+                let mut init_args = vec![instance.clone()];
+                init_args.extend(args);
+                self.invoke(init, init_args)?;
+                if let Some(frame) = self.frames.last_mut() {
+                    frame.constructing = Some(instance);
                 }
-                Instruction::Add => {
-                    let b = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let result = a.add(&b)?;
-                    self.stack.push(result);
+                Ok(())
+            }
+            None if args.is_empty() => {
+                self.push(instance)?;
+                self.ip = self.next_ip;
+                Ok(())
+            }
+            None => Err(VMError::ArityMismatch {
+                name: class.name.clone(),
+                expected: 0,
+                got: args.len(),
+            }),
+        }
+    }
+
+    fn call_method(&mut self, name: &str, argc: usize) -> Result<(), VMError> {
+        if self.stack.len() < argc + 1 {
+            return Err(VMError::StackUnderflow);
+        }
+        let receiver = self.stack[self.stack.len() - argc - 1].clone();
+        match (&receiver, name, argc) {
+            (Value::Generator(generator), "next", 0) => {
+                self.stack.pop();
+                self.resume(generator.clone(), Resume::Next)
+            }
+            (receiver, "len", 0) if receiver.length().is_some() => {
+                let len = receiver.length().unwrap_or_default();
+                self.stack.pop();
+                self.push(Value::Number(len as i32))?;
+                self.ip = self.next_ip;
+                Ok(())
+            }
+            (Value::Instance(instance), _, _) => {
+                let class = instance.borrow().class.clone();
+                let method = class.methods.get(name).ok_or_else(|| VMError::UndefinedMember {
+                    type_name: class.name.clone(),
+                    name: name.to_string(),
+                })?;
+                // The receiver is already in place as the `self` argument.
+                let args = self.stack.split_off(self.stack.len() - argc - 1);
+                self.invoke(method, args)
+            }
+            _ => Err(VMError::TypeError {
+                message: format!(
+                    "Value of type {} has no method {} taking {} arguments",
+                    receiver.type_name(),
+                    name,
+                    argc
+                ),
+            }),
+        }
+    }
+
+    /// Pops the operands of a binary instruction. When the left operand is an
+    /// instance whose class implements the `protocol` method, that method is
+    /// called with both operands instead and `None` is returned.
+    fn binary_operands(
+        &mut self,
+        protocol: &str,
+        negate: bool,
+    ) -> Result<Option<(Value, Value)>, VMError> {
+        let b = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+        let a = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+        let method = match &a {
+            Value::Instance(instance) => instance.borrow().class.methods.get(protocol).cloned(),
+            _ => None,
+        };
+        let Some(method) = method else {
+            return Ok(Some((a, b)));
+        };
+        self.invoke(&method, vec![a, b])?;
+        if negate && !method.is_generator {
+            if let Some(frame) = self.frames.last_mut() {
+                frame.negate_result = true;
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_field(&mut self, name: &str) -> Result<(), VMError> {
+        let object = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+        let Value::Instance(instance) = &object else {
+            return Err(VMError::TypeError {
+                message: format!("Cannot read field {} of {}", name, object.type_name()),
+            });
+        };
+        let instance = instance.borrow();
+        let value = instance
+            .fields
+            .get(name)
+            .cloned()
+            .ok_or_else(|| VMError::UndefinedMember {
+                type_name: instance.class.name.clone(),
+                name: name.to_string(),
+            })?;
+        self.push(value)
+    }
+
+    fn set_field(&mut self, name: &str) -> Result<(), VMError> {
+        let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+        let object = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+        match object {
+            Value::Instance(instance) => {
+                instance.borrow_mut().fields.insert(name.to_string(), value);
+                Ok(())
+            }
+            other => Err(VMError::TypeError {
+                message: format!("Cannot set field {} on {}", name, other.type_name()),
+            }),
+        }
+    }
+
+    fn make_class(&mut self, name: &str, method_count: usize) -> Result<(), VMError> {
+        if self.stack.len() < method_count {
+            return Err(VMError::StackUnderflow);
+        }
+        let mut methods = HashMap::new();
+        for method in self.stack.split_off(self.stack.len() - method_count) {
+            match method {
+                Value::Function(function) => {
+                    methods.insert(function.name.clone(), function);
                 }
-                Instruction::Sub => {
-                    let b = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let result = a.sub(&b)?;
-                    self.stack.push(result);
+                other => {
+                    return Err(VMError::TypeError {
+                        message: format!("Expected method, got {}", other.type_name()),
+                    })
                 }
-                Instruction::Mul => {
-                    let b = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let result = a.mul(&b)?;
-                    self.stack.push(result);
+            }
+        }
+        self.push(Value::Class(Rc::new(Class {
+            name: name.to_string(),
+            methods,
+        })))
+    }
+
+    fn resume(&mut self, generator: Rc<RefCell<Generator>>, resume: Resume) -> Result<(), VMError> {
+        let state = std::mem::replace(&mut *generator.borrow_mut(), Generator::Running);
+        match state {
+            Generator::Suspended(frame) => {
+                self.push_frame(frame.env, frame.locals, Some((generator, resume)))?;
+                self.stack.extend(frame.stack);
+                self.ip = frame.ip;
+                Ok(())
+            }
+            Generator::Running => Err(VMError::ExecutionError {
+                message: "Generator resumed while already running".to_string(),
+                line: 0,
+                position: 0,
+            }),
+            Generator::Done => {
+                *generator.borrow_mut() = Generator::Done;
+                self.finish_generator(resume, self.next_ip)
+            }
+        }
+    }
+
+    fn finish_generator(&mut self, resume: Resume, return_ip: usize) -> Result<(), VMError> {
+        match resume {
+            Resume::Next => {
+                self.push(Value::Null)?;
+                self.ip = return_ip;
+            }
+            Resume::ForIter(exit) => {
+                // Drop the iterable and its cursor.
+                self.stack.truncate(self.stack.len().saturating_sub(2));
+                self.ip = exit;
+            }
+        }
+        Ok(())
+    }
+
+    fn return_from_call(&mut self) -> Result<(), VMError> {
+        let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+        let frame = self.frames.pop().ok_or_else(|| VMError::ExecutionError {
+            message: "Return outside of a function".to_string(),
+            line: 0,
+            position: 0,
+        })?;
+        self.stack.truncate(frame.stack_base);
+        self.env_stack.truncate(frame.env_base);
+        self.locals.truncate(frame.locals_base);
+        match frame.generator {
+            Some((generator, resume)) => {
+                *generator.borrow_mut() = Generator::Done;
+                self.finish_generator(resume, frame.return_ip)
+            }
+            None => {
+                let value = match frame.constructing {
+                    Some(instance) => instance,
+                    None if frame.negate_result => Value::Boolean(!value.is_truthy()),
+                    None => value,
+                };
+                self.push(value)?;
+                self.ip = frame.return_ip;
+                Ok(())
+            }
+        }
+    }
+
+    fn yield_value(&mut self) -> Result<(), VMError> {
+        let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+        let frame = match self.frames.pop() {
+            Some(frame) if frame.generator.is_some() => frame,
+            other => {
+                self.frames.extend(other);
+                return Err(VMError::ExecutionError {
+                    message: "Yield outside of a generator".to_string(),
+                    line: 0,
+                    position: 0,
+                });
+            }
+        };
+        let (generator, _) = frame.generator.expect("generator frame");
+        *generator.borrow_mut() = Generator::Suspended(SuspendedFrame {
+            ip: self.next_ip,
+            stack: self.stack.split_off(frame.stack_base),
+            env: self.env_stack.split_off(frame.env_base),
+            locals: self.locals.split_off(frame.locals_base),
+        });
+        self.push(value)?;
+        self.ip = frame.return_ip;
+        Ok(())
+    }
+
+    fn for_iter(&mut self, exit: usize) -> Result<(), VMError> {
+        let len = self.stack.len();
+        if len < 2 {
+            return Err(VMError::StackUnderflow);
+        }
+        let cursor = match self.stack[len - 1] {
+            Value::Number(n) => n as usize,
+            _ => {
+                return Err(VMError::TypeError {
+                    message: "Invalid loop cursor".to_string(),
+                })
+            }
+        };
+        let next = match &self.stack[len - 2] {
+            Value::Generator(generator) => {
+                let generator = generator.clone();
+                return self.resume(generator, Resume::ForIter(exit));
+            }
+            iterable if iterable.length().is_some() => iterable.element(cursor),
+            other => {
+                return Err(VMError::TypeError {
+                    message: format!("Cannot iterate over {}", other.type_name()),
+                })
+            }
+        };
+        if let Some(item) = next {
+            self.stack[len - 1] = Value::Number(cursor as i32 + 1);
+            self.push(item)?;
+            self.ip = self.next_ip;
+        } else {
+            self.stack.truncate(len - 2);
+            self.ip = exit;
+        }
+        Ok(())
+    }
+
+    fn unpack_tuple(&mut self, count: usize) -> Result<(), VMError> {
+        let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+        match value {
+            Value::Tuple(items) if items.len() == count => {
+                for item in items.iter() {
+                    self.push(item.clone())?;
                 }
-                Instruction::Div => {
-                    let b = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let result = a.div(&b)?;
-                    self.stack.push(result);
+                Ok(())
+            }
+            other => Err(VMError::TypeError {
+                message: format!("Cannot unpack {:?} into {} variables", other, count),
+            }),
+        }
+    }
+
+    /// Takes the innermost pending deferred action of the scopes from `base`
+    /// up, dropping the scopes (and calls) above its own, and jumps to it. It
+    /// returns to the current instruction, which then takes the next one.
+    fn run_deferred(&mut self, base: usize) -> bool {
+        let Some(depth) = self
+            .env_stack
+            .iter()
+            .rposition(|scope| !scope.deferred.is_empty())
+            .filter(|&depth| depth >= base)
+        else {
+            return false;
+        };
+        self.env_stack.truncate(depth + 1);
+        if let Some(dropped) = self.frames.iter().position(|frame| frame.env_base > depth) {
+            self.locals.truncate(self.frames[dropped].locals_base);
+            self.frames.truncate(dropped);
+        }
+        let entry = self.env_stack[depth].deferred.pop().expect("pending deferred action");
+        self.defer_returns.push(self.ip);
+        self.ip = entry;
+        true
+    }
+
+    pub fn execute(&mut self, program: &Program) -> Result<(), VMError> {
+        self.execute_with(program.instructions.len(), |vm| vm.run(program))
+    }
+
+    /// Like `execute`, but decodes each instruction from compact bytecode
+    /// as it goes.
+    pub fn execute_bytecode(&mut self, bytecode: &Bytecode) -> Result<(), VMError> {
+        self.execute_with(bytecode.code.len(), |vm| vm.run_bytecode(bytecode))
+    }
+
+    /// Runs code that is `end` long with `run`, then what is still deferred.
+    fn execute_with(
+        &mut self,
+        end: usize,
+        mut run: impl FnMut(&mut Self) -> Result<(), VMError>,
+    ) -> Result<(), VMError> {
+        let mut result = run(self);
+        // What is still deferred belongs to the global scope, or to every
+        // scope still open when unwinding from an error. The first error wins.
+        self.ip = end;
+        self.defer_returns.clear();
+        while self.run_deferred(0) {
+            let deferred = run(self);
+            if result.is_ok() {
+                result = deferred;
+            }
+            self.ip = end;
+            self.defer_returns.clear();
+        }
+        result?;
+
+        let unclosed = self.env_stack.len().saturating_sub(1);
+        if unclosed != 0 {
+            return Err(VMError::ExecutionError {
+                message: format!("Unclosed scopes at end of execution: {}", unclosed),
+                line: 0, 
+                position: 0,
+            });
+        }
+        
+        Ok(())
+    }
+
+    fn run(&mut self, program: &Program) -> Result<(), VMError> {
+        let instructions = &program.instructions;
+        let end = instructions.len();
+        while self.ip < end {
+            if let Some(fuel) = &mut self.fuel {
+                *fuel = fuel.checked_sub(1).ok_or(VMError::OutOfFuel)?;
+            }
+            self.next_ip = self.ip + 1;
+            if self.step(&instructions[self.ip], &program.constants, &program.globals, end)? {
+                self.ip = self.next_ip;
+            }
+        }
+        Ok(())
+    }
+
+    fn run_bytecode(&mut self, bytecode: &Bytecode) -> Result<(), VMError> {
+        let code = &bytecode.code;
+        let (constants, globals, end) = (&bytecode.constants, &bytecode.globals, code.len());
+        while self.ip < end {
+            if let Some(fuel) = &mut self.fuel {
+                *fuel = fuel.checked_sub(1).ok_or(VMError::OutOfFuel)?;
+            }
+            // The commonest instructions are decoded right here, so that each
+            // inlined `step` only handles the one it is given; the rest take
+            // the general decoder.
+            let mut next_ip = self.ip + 1;
+            let instruction = match Opcode::from_byte(code[self.ip]) {
+                Some(Opcode::PushConst) => Instruction::PushConst(read_operand(code, &mut next_ip)?),
+                Some(Opcode::LoadLocal) => Instruction::LoadLocal(read_operand(code, &mut next_ip)?),
+                Some(Opcode::StoreLocal) => Instruction::StoreLocal(read_operand(code, &mut next_ip)?),
+                Some(Opcode::LoadGlobal) => Instruction::LoadGlobal(read_operand(code, &mut next_ip)?),
+                Some(Opcode::StoreGlobal) => Instruction::StoreGlobal(read_operand(code, &mut next_ip)?),
+                Some(Opcode::Jmp) => Instruction::Jmp(read_operand(code, &mut next_ip)?),
+                Some(Opcode::Jz) => Instruction::Jz(read_operand(code, &mut next_ip)?),
+                Some(Opcode::TeeLocal) => Instruction::TeeLocal(read_operand(code, &mut next_ip)?),
+                Some(Opcode::TeeGlobal) => Instruction::TeeGlobal(read_operand(code, &mut next_ip)?),
+                Some(Opcode::Call) => Instruction::Call(read_operand(code, &mut next_ip)?),
+                Some(Opcode::Pop) => Instruction::Pop,
+                Some(Opcode::Add) => Instruction::Add,
+                Some(Opcode::Sub) => Instruction::Sub,
+                Some(Opcode::Mul) => Instruction::Mul,
+                Some(Opcode::Less) => Instruction::Less,
+                Some(Opcode::Greater) => Instruction::Greater,
+                Some(Opcode::BeginScope) => Instruction::BeginScope,
+                Some(Opcode::EndScope) => Instruction::EndScope,
+                Some(Opcode::Return) => Instruction::Return,
+                _ => {
+                    let (instruction, next) = bytecode.decode(self.ip)?;
+                    next_ip = next;
+                    instruction
                 }
-                Instruction::Greater => {
-                    let b = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let result = a.gt(&b)?;
-                    self.stack.push(Value::Boolean(result));
+            };
+            self.next_ip = next_ip;
+            if self.step(&instruction, constants, globals, end)? {
+                self.ip = self.next_ip;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs one instruction of code that is `end` long, with the given
+    /// constants and global names. It returns whether to go on with the
+    /// instruction at `next_ip`; otherwise the instruction has set `ip`.
+    #[inline(always)]
+    fn step(
+        &mut self,
+        instruction: &Instruction,
+        constants: &[Value],
+        globals: &[String],
+        end: usize,
+    ) -> Result<bool, VMError> {
+        match instruction {
+            Instruction::Push(value) => {
+                self.push(value.clone())?;
+            }
+            Instruction::PushConst(index) => {
+                let value = constants.get(*index as usize).cloned().ok_or_else(|| {
+                    VMError::ExecutionError {
+                        message: format!("Constant {} out of range", index),
+                        line: 0,
+                        position: 0,
+                    }
+                })?;
+                self.push(value)?;
+            }
+            Instruction::Pop => {
+                self.stack.pop().ok_or(VMError::StackUnderflow)?;
+            }
+            Instruction::Add => {
+                let Some((a, b)) = self.binary_operands("add", false)? else {
+                    return Ok(false);
+                };
+                let result = a.add(&b)?;
+                self.stack.push(result);
+            }
+            Instruction::Sub => {
+                let Some((a, b)) = self.binary_operands("sub", false)? else {
+                    return Ok(false);
+                };
+                let result = a.sub(&b)?;
+                self.stack.push(result);
+            }
+            Instruction::Mul => {
+                let Some((a, b)) = self.binary_operands("mul", false)? else {
+                    return Ok(false);
+                };
+                let result = a.mul(&b)?;
+                self.stack.push(result);
+            }
+            Instruction::Div => {
+                let Some((a, b)) = self.binary_operands("div", false)? else {
+                    return Ok(false);
+                };
+                let result = a.div(&b)?;
+                self.stack.push(result);
+            }
+            Instruction::Greater => {
+                let Some((a, b)) = self.binary_operands("gt", false)? else {
+                    return Ok(false);
+                };
+                let result = a.gt(&b)?;
+                self.stack.push(Value::Boolean(result));
+            }
+            Instruction::Less => {
+                let Some((a, b)) = self.binary_operands("lt", false)? else {
+                    return Ok(false);
+                };
+                let result = a.lt(&b)?;
+                self.stack.push(Value::Boolean(result));
+            }
+            Instruction::Equal => {
+                let Some((a, b)) = self.binary_operands("eq", false)? else {
+                    return Ok(false);
+                };
+                let result = a.eq(&b);
+                self.stack.push(Value::Boolean(result));
+            }
+            Instruction::NotEqual => {
+                let Some((a, b)) = self.binary_operands("eq", true)? else {
+                    return Ok(false);
+                };
+                let result = !a.eq(&b);
+                self.stack.push(Value::Boolean(result));
+            }
+            Instruction::Jmp(target) => {
+                if *target > end {
+                    return Err(VMError::InvalidJump {
+                        target: *target,
+                        max: end,
+                    });
                 }
-                Instruction::Less => {
-                    let b = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let result = a.lt(&b)?;
-                    self.stack.push(Value::Boolean(result));
+                self.ip = *target;
+                return Ok(false);
+            }
+            Instruction::Jz(target) => {
+                if *target > end {
+                    return Err(VMError::InvalidJump { 
+                        target: *target,
+                        max: end 
+                    });
                 }
-                Instruction::Equal => {
-                    let b = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let result = a.eq(&b);
-                    self.stack.push(Value::Boolean(result));
+                let condition = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                if !condition.is_truthy() {
+                    self.ip = *target;
+                    return Ok(false);
                 }
-                Instruction::NotEqual => {
-                    let b = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    let result = !a.eq(&b);
-                    self.stack.push(Value::Boolean(result));
+            }
+            Instruction::JmpIfNull(target) => {
+                if self.top_is_null(*target, end)? {
+                    self.ip = *target;
+                    return Ok(false);
                 }
-                Instruction::Jmp(target) => {
-                    if *target >= instructions.len() {
-                        return Err(VMError::ExecutionError {
-                            message: format!("Jump target {} out of bounds", target),
-                            line: 0,
-                            position: 0,
-                        });
-                    }
+            }
+            Instruction::JmpIfNotNull(target) => {
+                if !self.top_is_null(*target, end)? {
                     self.ip = *target;
-                    continue;
+                    return Ok(false);
                 }
-                Instruction::Jz(target) => {
-                    if *target >= instructions.len() {
-                        return Err(VMError::InvalidJump { 
-                            target: *target,
-                            max: instructions.len() 
-                        });
+            }
+            Instruction::Label(_) => {}
+            Instruction::LoadLocal(slot) => {
+                // A slot that was never assigned reads as null.
+                let slot = self.locals_base() + *slot as usize;
+                let value = self.locals.get(slot).cloned().unwrap_or(Value::Null);
+                self.push(value)?;
+            }
+            Instruction::StoreLocal(slot) => {
+                let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                self.set_local(*slot, value);
+            }
+            Instruction::TeeLocal(slot) => {
+                let value = self.stack.last().cloned().ok_or(VMError::StackUnderflow)?;
+                self.set_local(*slot, value);
+            }
+            Instruction::LoadGlobal(index) => {
+                let index = *index as usize;
+                match self.globals.get(index) {
+                    Some(Some(value)) => {
+                        let value = value.clone();
+                        self.push(value)?;
                     }
-                    let condition = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    if !condition.is_truthy() {
-                        self.ip = *target;
-                        continue;
+                    _ => {
+                        return Err(VMError::UndefinedVariable {
+                            name: globals.get(index).cloned().unwrap_or_default(),
+                        })
                     }
                 }
-                Instruction::Label(_) => {}
-                Instruction::Store(name) => {
+            }
+            Instruction::StoreGlobal(index) => {
+                let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                self.set_global(*index, value);
+            }
+            Instruction::TeeGlobal(index) => {
+                let value = self.stack.last().cloned().ok_or(VMError::StackUnderflow)?;
+                self.set_global(*index, value);
+            }
+            Instruction::BeginScope => {
+                self.env_stack.push(Scope::default());
+            }
+            Instruction::EndScope => {
+                if self.run_deferred(self.env_stack.len().saturating_sub(1)) {
+                    return Ok(false);
+                }
+                if self.env_stack.pop().is_none() {
+                    return Err(VMError::NoScopeToEnd);
+                }
+            }
+            Instruction::CreateArray => {
+                self.stack.push(Value::Array(Vec::new()));
+            }
+            Instruction::ArrayOp(op) => match op {
+                ArrayOperation::Push => {
                     let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                    self.current_env().insert(name.clone(), value);
+                    let mut array = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                    array.push(value)?;
+                    self.stack.push(array);
+                }
+                ArrayOperation::Pop => {
+                    let mut array = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                    let value = array.pop()?;
+                    self.stack.push(array);
+                    self.stack.push(value);
                 }
-                Instruction::Load(name) => {
-                    if let Some(value) = self.get_var(name) {
-                        self.stack.push(value);
+                ArrayOperation::Get(_) => {
+                    let index = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                    let array = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                    
+                    let (Value::Number(idx), Some(len)) = (index, array.length()) else {
+                        return Err(VMError::TypeError {
+                            message: "Invalid array access".to_string(),
+                        });
+                    };
+                    let bound_idx = self.check_array_bounds(idx, len)?;
+                    self.stack.push(array.element(bound_idx).unwrap());
+                }
+                ArrayOperation::Set(_) => {
+                    let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                    let index = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                    let array = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                    if let Value::Tuple(_) = array {
+                        return Err(VMError::TypeError {
+                            message: "Tuples are immutable".to_string(),
+                        });
+                    }
+
+                    // The updated array is left for the compiler to store back.
+                    if let (Value::Number(idx), Value::Array(mut arr)) = (index, array) {
+                        let bound_idx = self.check_array_bounds(idx, arr.len())?;
+                        arr[bound_idx] = value;
+                        self.stack.push(Value::Array(arr));
                     } else {
-                        return Err(VMError::UndefinedVariable { name: name.clone() });
+                        return Err(VMError::TypeError {
+                            message: "Invalid array assignment".to_string(),
+                        });
                     }
                 }
-                Instruction::BeginScope => {
-                    scope_depth += 1;
-                    self.env_stack.push(HashMap::new());
+            },
+            Instruction::Call(argc) => {
+                self.call(*argc)?;
+                return Ok(false);
+            }
+            Instruction::CallMethod(name, argc) => {
+                self.call_method(name, *argc)?;
+                return Ok(false);
+            }
+            Instruction::Return => {
+                let base = self.frames.last().map_or(0, |frame| frame.env_base);
+                if !self.frames.is_empty() && self.run_deferred(base) {
+                    return Ok(false);
                 }
-                Instruction::EndScope => {
-                    scope_depth -= 1;
-                    if self.env_stack.pop().is_none() {
-                        return Err(VMError::NoScopeToEnd);
-                    }
+                self.return_from_call()?;
+                return Ok(false);
+            }
+            Instruction::Defer(entry) => {
+                let entry = *entry;
+                self.env_stack
+                    .last_mut()
+                    .ok_or(VMError::NoScopeToEnd)?
+                    .deferred
+                    .push(entry);
+            }
+            Instruction::Assert {
+                text,
+                span,
+                has_message,
+            } => {
+                let message = if *has_message {
+                    Some(self.stack.pop().ok_or(VMError::StackUnderflow)?)
+                } else {
+                    None
+                };
+                let condition = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                if !condition.is_truthy() {
+                    return Err(VMError::AssertionFailed {
+                        expression: text.clone(),
+                        message: message.map(|message| match message {
+                            Value::String(s) => s.to_string(),
+                            other => format!("{:?}", other),
+                        }),
+                        span: *span,
+                    });
                 }
-                Instruction::CreateArray => {
-                    self.stack.push(Value::Array(Vec::new()));
+            }
+            Instruction::EndDefer => {
+                self.ip = self.defer_returns.pop().ok_or_else(|| VMError::ExecutionError {
+                    message: "End of a deferred action that is not running".to_string(),
+                    line: 0,
+                    position: 0,
+                })?;
+                return Ok(false);
+            }
+            Instruction::Yield => {
+                self.yield_value()?;
+                return Ok(false);
+            }
+            Instruction::ForIter(exit) => {
+                self.for_iter(*exit)?;
+                return Ok(false);
+            }
+            Instruction::MakeTuple(count) => {
+                if self.stack.len() < *count {
+                    return Err(VMError::StackUnderflow);
                 }
-                Instruction::ArrayOp(op) => match op {
-                    ArrayOperation::Push => {
-                        let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                        let mut array = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                        array.push(value)?;
-                        self.stack.push(array);
+                let items = self.stack.split_off(self.stack.len() - count);
+                self.push(Value::Tuple(items.into()))?;
+            }
+            Instruction::UnpackTuple(count) => {
+                self.unpack_tuple(*count)?;
+            }
+            Instruction::MakeClass(name, method_count) => {
+                self.make_class(name, *method_count)?;
+            }
+            Instruction::GetField(name) => {
+                self.get_field(name)?;
+            }
+            Instruction::SetField(name) => {
+                self.set_field(name)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// The absolute code address an instruction refers to: a jump target, a
+/// deferred action or a function entry point.
+pub fn address(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Jmp(target)
+        | Instruction::Jz(target)
+        | Instruction::JmpIfNull(target)
+        | Instruction::JmpIfNotNull(target)
+        | Instruction::ForIter(target)
+        | Instruction::Defer(target) => Some(*target),
+        Instruction::Push(Value::Function(function)) => Some(function.entry),
+        _ => None,
+    }
+}
+
+/// Replaces the code address an instruction refers to, if any, with `f` of
+/// it.
+pub fn map_address(instruction: &mut Instruction, f: impl FnOnce(usize) -> usize) {
+    match instruction {
+        Instruction::Jmp(target)
+        | Instruction::Jz(target)
+        | Instruction::JmpIfNull(target)
+        | Instruction::JmpIfNotNull(target)
+        | Instruction::ForIter(target)
+        | Instruction::Defer(target) => *target = f(*target),
+        Instruction::Push(Value::Function(function)) => {
+            let mut moved = Function::clone(function);
+            moved.entry = f(moved.entry);
+            *function = Rc::new(moved);
+        }
+        _ => {}
+    }
+}
+
+/// Resolves the labels that code addresses name to the positions of their
+/// `Label` instructions, and strips the `Label`s.
+pub fn link(instructions: Vec<Instruction>) -> Result<Vec<Instruction>, VMError> {
+    let mut positions = HashMap::new();
+    let mut position = 0;
+    for instruction in &instructions {
+        match instruction {
+            Instruction::Label(label) => {
+                positions.insert(*label, position);
+            }
+            _ => position += 1,
+        }
+    }
+    let mut linked = Vec::with_capacity(position);
+    for mut instruction in instructions {
+        if let Instruction::Label(_) = instruction {
+            continue;
+        }
+        if let Some(label) = address(&instruction) {
+            let target = *positions.get(&label).ok_or(VMError::UndefinedLabel { label })?;
+            map_address(&mut instruction, |_| target);
+        }
+        linked.push(instruction);
+    }
+    Ok(linked)
+}
+
+/// Whether a value can be baked into a `Push` instruction: it must not refer
+/// to code or to mutable state of the VM that produced it.
+fn is_constant(value: &Value) -> bool {
+    match value {
+        Value::Array(items) => items.iter().all(is_constant),
+        Value::Tuple(items) => items.iter().all(is_constant),
+        Value::Function(_) | Value::Generator(_) | Value::Class(_) | Value::Instance(_) => false,
+        _ => true,
+    }
+}
+
+/// Whether a statement leaves a value on the stack.
+pub fn produces_value(node: &ASTNode) -> bool {
+    !matches!(
+        node,
+        ASTNode::If { .. }
+            | ASTNode::While { .. }
+            | ASTNode::For { .. }
+            | ASTNode::VarDecl { .. }
+            | ASTNode::Block(_)
+            | ASTNode::ArrayAssign { .. }
+            | ASTNode::FnDecl { .. }
+            | ASTNode::ClassDecl { .. }
+            | ASTNode::FieldAssign { .. }
+            | ASTNode::Destructure { .. }
+            | ASTNode::Return { .. }
+            | ASTNode::Yield { .. }
+            | ASTNode::Defer(_)
+            | ASTNode::Assert { .. }
+    )
+}
+
+/// How many instructions a `comptime` block may execute, so that
+/// compilation always terminates.
+const COMPTIME_FUEL: usize = 1_000_000;
+
+/// The names visible in the function being compiled (or the top level):
+/// its open blocks, innermost last, each mapping names to slots of the frame.
+#[derive(Debug, Default)]
+struct FrameScope {
+    blocks: Vec<HashMap<String, u16>>,
+    /// The next free slot. Slots are not reused when a block closes.
+    slots: u16,
+}
+
+/// Where a variable lives at run time.
+#[derive(Debug, Clone, Copy)]
+enum Resolved {
+    Local(u16),
+    Global(u32),
+}
+
+/// Turns the AST into instructions.
+#[derive(Debug)]
+pub struct Compiler {
+    /// Whether `assert` statements are compiled in.
+    asserts: bool,
+    /// Whether the finished code goes through the peephole pass.
+    peephole: bool,
+    /// The `const fn` declarations seen so far, callable from `comptime`.
+    const_fns: Vec<ASTNode>,
+    /// One entry per function being compiled, the innermost last; the first
+    /// is the top level, whose names outside any block are globals.
+    frames: Vec<FrameScope>,
+    globals: Vec<String>,
+    global_indices: HashMap<String, u32>,
+    /// The next unused label.
+    next_label: usize,
+    errors: Vec<VMError>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Compiler {
+            asserts: true,
+            peephole: true,
+            const_fns: Vec::new(),
+            frames: vec![FrameScope::default()],
+            globals: Vec::new(),
+            global_indices: HashMap::new(),
+            next_label: 0,
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl Compiler {
+    pub fn with_asserts(mut self, asserts: bool) -> Self {
+        self.asserts = asserts;
+        self
+    }
+
+    pub fn with_peephole(mut self, peephole: bool) -> Self {
+        self.peephole = peephole;
+        self
+    }
+
+    fn label(&mut self) -> usize {
+        self.next_label += 1;
+        self.next_label - 1
+    }
+
+    /// Emits the null check of an optional access (`?.`, `?[`), which jumps
+    /// to `end`, the label after the whole chain the access belongs to.
+    fn null_guard(&mut self, instructions: &mut Vec<Instruction>, optional: bool, end: &mut Option<usize>) {
+        if optional {
+            let label = *end.get_or_insert_with(|| self.label());
+            instructions.push(Instruction::JmpIfNull(label));
+        }
+    }
+
+    /// Compiles a link of a postfix chain such as `a?.b.c[0]`. A null met by
+    /// any `?.` or `?[` skips the rest of the chain, so the caller has to
+    /// place `end` after the outermost link.
+    fn compile_link(&mut self, node: ASTNode, end: &mut Option<usize>) -> Vec<Instruction> {
+        match node {
+            ASTNode::ArrayIndex {
+                array,
+                index,
+                optional,
+                ..
+            } => {
+                let mut instructions = self.compile_link(*array, end);
+                self.null_guard(&mut instructions, optional, end);
+                instructions.extend(self.compile(*index));
+                instructions.push(Instruction::ArrayOp(ArrayOperation::Get(0)));
+                instructions
+            }
+            ASTNode::FieldAccess {
+                object,
+                field,
+                optional,
+            } => {
+                let mut instructions = self.compile_link(*object, end);
+                self.null_guard(&mut instructions, optional, end);
+                instructions.push(Instruction::GetField(field));
+                instructions
+            }
+            ASTNode::MethodCall {
+                object,
+                method,
+                args,
+                optional,
+            } => {
+                let argc = args.len();
+                let mut instructions = self.compile_link(*object, end);
+                self.null_guard(&mut instructions, optional, end);
+                for arg in args {
+                    instructions.extend(self.compile(arg));
+                }
+                instructions.push(Instruction::CallMethod(method, argc));
+                instructions
+            }
+            node => self.compile(node),
+        }
+    }
+
+    fn frame(&mut self) -> &mut FrameScope {
+        self.frames.last_mut().expect("top-level frame")
+    }
+
+    fn global(&mut self, name: &str) -> u32 {
+        if let Some(&index) = self.global_indices.get(name) {
+            return index;
+        }
+        let index = self.globals.len() as u32;
+        self.globals.push(name.to_string());
+        self.global_indices.insert(name.to_string(), index);
+        index
+    }
+
+    /// Finds the variable a read of `name` refers to: the innermost block of
+    /// the current function that declares it, or else a global.
+    fn resolve(&mut self, name: &str) -> Resolved {
+        let local = self
+            .frame()
+            .blocks
+            .iter()
+            .rev()
+            .find_map(|block| block.get(name).copied());
+        match local {
+            Some(slot) => Resolved::Local(slot),
+            None => Resolved::Global(self.global(name)),
+        }
+    }
+
+    /// Finds the variable an assignment to `name` writes: the innermost block
+    /// declares it if it does not yet, and the top level outside any block
+    /// writes globals.
+    fn declare(&mut self, name: &str) -> Resolved {
+        let frame = self.frame();
+        if frame.blocks.is_empty() {
+            return Resolved::Global(self.global(name));
+        }
+        if let Some(&slot) = frame.blocks.last().and_then(|block| block.get(name)) {
+            return Resolved::Local(slot);
+        }
+        let Some(next) = frame.slots.checked_add(1) else {
+            self.errors.push(VMError::TypeError {
+                message: "Too many local variables in one function".to_string(),
+            });
+            return Resolved::Local(u16::MAX);
+        };
+        let slot = std::mem::replace(&mut frame.slots, next);
+        if let Some(block) = frame.blocks.last_mut() {
+            block.insert(name.to_string(), slot);
+        }
+        Resolved::Local(slot)
+    }
+
+    fn load(&mut self, name: &str) -> Instruction {
+        match self.resolve(name) {
+            Resolved::Local(slot) => Instruction::LoadLocal(slot),
+            Resolved::Global(index) => Instruction::LoadGlobal(index),
+        }
+    }
+
+    fn store(&mut self, name: &str) -> Instruction {
+        match self.declare(name) {
+            Resolved::Local(slot) => Instruction::StoreLocal(slot),
+            Resolved::Global(index) => Instruction::StoreGlobal(index),
+        }
+    }
+
+    /// Writes back to the variable a read of `name` refers to, rather than
+    /// declaring it in the innermost block.
+    fn store_resolved(&mut self, name: &str) -> Instruction {
+        match self.resolve(name) {
+            Resolved::Local(slot) => Instruction::StoreLocal(slot),
+            Resolved::Global(index) => Instruction::StoreGlobal(index),
+        }
+    }
+
+    /// Compiles a nested statement list. Values of expression statements are
+    /// discarded so that loops and calls leave the stack balanced.
+    fn compile_body(&mut self, nodes: Vec<ASTNode>) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+        for node in nodes {
+            let discard = produces_value(&node);
+            instructions.extend(self.compile(node));
+            if discard {
+                instructions.push(Instruction::Pop);
+            }
+        }
+        instructions
+    }
+
+    /// Compiles the body of an `if`, `while` or `for`. A body that defers
+    /// actions runs in a scope of its own, so that they run each time it
+    /// finishes rather than when the function does. Variables assigned in it
+    /// still belong to the enclosing block.
+    fn compile_branch(&mut self, nodes: Vec<ASTNode>) -> Vec<Instruction> {
+        if !nodes.iter().any(|node| matches!(node, ASTNode::Defer(_))) {
+            return self.compile_body(nodes);
+        }
+        let mut instructions = vec![Instruction::BeginScope];
+        instructions.extend(self.compile_body(nodes));
+        instructions.push(Instruction::EndScope);
+        instructions
+    }
+
+    /// Compiles a function into code that leaves the function value on the
+    /// stack. The body is laid out inline and skipped over; the function value
+    /// records where it starts.
+    fn compile_function(
+        &mut self,
+        name: String,
+        params: Vec<Param>,
+        body: Vec<ASTNode>,
+        is_generator: bool,
+    ) -> Vec<Instruction> {
+        // The arguments arrive in the first slots of the new frame.
+        let params: Vec<String> = params.into_iter().map(|param| param.name).collect();
+        let slots = params.len().min(u16::MAX as usize) as u16;
+        self.frames.push(FrameScope {
+            blocks: vec![params.iter().cloned().zip(0..slots).collect()],
+            slots,
+        });
+        let entry = self.label();
+        let after = self.label();
+        let mut instructions = vec![Instruction::Jmp(after), Instruction::Label(entry)];
+        instructions.extend(self.compile_body(body));
+        self.frames.pop();
+        instructions.push(Instruction::Push(Value::Null));
+        instructions.push(Instruction::Return);
+        instructions.push(Instruction::Label(after));
+        instructions.push(Instruction::Push(Value::Function(Rc::new(Function {
+            name,
+            params,
+            entry,
+            is_generator,
+        }))));
+        instructions
+    }
+
+    pub fn compile(&mut self, node: ASTNode) -> Vec<Instruction> {
+        match node {
+            ASTNode::Number(n) => vec![Instruction::Push(Value::Number(n))],
+            ASTNode::String(s) => vec![Instruction::Push(Value::String(s.into()))],
+            ASTNode::Bytes(bytes) => vec![Instruction::Push(Value::Bytes(bytes))],
+            ASTNode::Null => vec![Instruction::Push(Value::Null)],
+            ASTNode::BinOp { left, op, right, .. } => {
+                let mut instructions = self.compile(*left);
+                instructions.extend(self.compile(*right));
+                match op {
+                    Token::Plus => instructions.push(Instruction::Add),
+                    Token::Minus => instructions.push(Instruction::Sub),
+                    Token::Star => instructions.push(Instruction::Mul),
+                    Token::Slash => instructions.push(Instruction::Div),
+                    Token::Greater => instructions.push(Instruction::Greater),
+                    Token::Less => instructions.push(Instruction::Less),
+                    Token::Equal => instructions.push(Instruction::Equal),
+                    Token::NotEqual => instructions.push(Instruction::NotEqual),
+                    _ => panic!("Unsupported operation"),
+                }
+                instructions
+            }
+            ASTNode::If {
+                condition,
+                if_block,
+                else_block,
+            } => {
+                let else_start = self.label();
+                let after_else = self.label();
+                let mut instructions = self.compile(*condition);
+                instructions.push(Instruction::Jz(else_start));
+                instructions.extend(self.compile_branch(if_block));
+                instructions.push(Instruction::Jmp(after_else));
+                instructions.push(Instruction::Label(else_start));
+                instructions.extend(self.compile_branch(else_block));
+                instructions.push(Instruction::Label(after_else));
+                instructions
+            }
+            ASTNode::While { condition, body } => {
+                let condition_start = self.label();
+                let after_loop = self.label();
+                let mut instructions = vec![Instruction::Label(condition_start)];
+                instructions.extend(self.compile(*condition));
+                instructions.push(Instruction::Jz(after_loop));
+                instructions.extend(self.compile_branch(body));
+                instructions.push(Instruction::Jmp(condition_start));
+                instructions.push(Instruction::Label(after_loop));
+                instructions
+            }
+            ASTNode::For {
+                var,
+                iterable,
+                body,
+            } => {
+                // The iterable and a cursor stay on the stack for the whole loop.
+                let loop_start = self.label();
+                let after_loop = self.label();
+                let mut instructions = self.compile(*iterable);
+                instructions.push(Instruction::Push(Value::Number(0)));
+                instructions.push(Instruction::Label(loop_start));
+                instructions.push(Instruction::ForIter(after_loop));
+                let store = self.store(&var);
+                instructions.push(store);
+                instructions.extend(self.compile_branch(body));
+                instructions.push(Instruction::Jmp(loop_start));
+                instructions.push(Instruction::Label(after_loop));
+                instructions
+            }
+            ASTNode::VarDecl { name, value, .. } => {
+                let mut instructions = self.compile(*value);
+                let store = self.store(&name);
+                instructions.push(store);
+                instructions
+            }
+            ASTNode::VarRef(name) => vec![self.load(&name)],
+            ASTNode::Block(nodes) => {
+                let mut instructions = vec![Instruction::BeginScope];
+                self.frame().blocks.push(HashMap::new());
+                instructions.extend(self.compile_body(nodes));
+                self.frame().blocks.pop();
+                instructions.push(Instruction::EndScope);
+                instructions
+            }
+            ASTNode::Array(elements) => {
+                let mut instructions = vec![Instruction::CreateArray];
+                for element in elements {
+                    instructions.extend(self.compile(element));
+                    instructions.push(Instruction::ArrayOp(ArrayOperation::Push));
+                }
+                instructions
+            }
+            ASTNode::Tuple(elements) => {
+                let count = elements.len();
+                let mut instructions = Vec::new();
+                for element in elements {
+                    instructions.extend(self.compile(element));
+                }
+                instructions.push(Instruction::MakeTuple(count));
+                instructions
+            }
+            ASTNode::Destructure { names, value, .. } => {
+                let mut instructions = self.compile(*value);
+                instructions.push(Instruction::UnpackTuple(names.len()));
+                // The last element ends up on top of the stack.
+                for name in names.iter().rev() {
+                    let store = self.store(name);
+                    instructions.push(store);
+                }
+                instructions
+            }
+            link @ (ASTNode::ArrayIndex { .. }
+            | ASTNode::FieldAccess { .. }
+            | ASTNode::MethodCall { .. }) => {
+                let mut end = None;
+                let mut instructions = self.compile_link(link, &mut end);
+                instructions.extend(end.map(Instruction::Label));
+                instructions
+            }
+            ASTNode::ArrayAssign {
+                array,
+                index,
+                value,
+                span,
+            } => {
+                // Arrays are values, so the updated array is written back to
+                // where it was read from.
+                let mut instructions = Vec::new();
+                let write_back = match *array {
+                    ASTNode::VarRef(name) => {
+                        instructions.push(self.load(&name));
+                        vec![self.store_resolved(&name)]
                     }
-                    ArrayOperation::Pop => {
-                        let mut array = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                        let value = array.pop()?;
-                        self.stack.push(array);
-                        self.stack.push(value);
+                    ASTNode::FieldAccess {
+                        object,
+                        field,
+                        optional: false,
+                    } if matches!(*object, ASTNode::VarRef(_)) => {
+                        // The object stays below the array for `SetField`.
+                        let object = self.compile(*object);
+                        instructions.extend(object.clone());
+                        instructions.extend(object);
+                        instructions.push(Instruction::GetField(field.clone()));
+                        vec![Instruction::SetField(field)]
                     }
-                    ArrayOperation::Get(_) => {
-                        let index = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                        let array = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                        
-                        if let (Value::Number(idx), Value::Array(arr)) = (index, array) {
-                            let bound_idx = self.check_array_bounds(idx, arr.len())?;
-                            self.stack.push(arr[bound_idx].clone());
-                        } else {
-                            return Err(VMError::TypeError {
-                                message: "Invalid array access".to_string(),
-                            });
-                        }
+                    _ => {
+                        self.errors.push(VMError::CompileError {
+                            message: "Can only assign to elements of variables and fields"
+                                .to_string(),
+                            span,
+                        });
+                        return Vec::new();
                     }
-                    ArrayOperation::Set(_) => {
-                        let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                        let index = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                        let array = self.stack.pop().ok_or(VMError::StackUnderflow)?;
-                        
-                        if let (Value::Number(idx), Value::Array(mut arr)) = (index, array) {
-                            let bound_idx = self.check_array_bounds(idx, arr.len())?;
-                            arr[bound_idx] = value;
-                            let array_value = Value::Array(arr);
-                            if let Some(name) = self.current_env().iter().find_map(|(k, v)| 
-                                if matches!(v, Value::Array(_)) { Some(k.clone()) } else { None }
-                            ) {
-                                self.current_env().insert(name, array_value);
-                            }
-                        } else {
-                            return Err(VMError::TypeError {
-                                message: "Invalid array assignment".to_string(),
-                            });
-                        }
+                };
+                instructions.extend(self.compile(*index));
+                instructions.extend(self.compile(*value));
+                instructions.push(Instruction::ArrayOp(ArrayOperation::Set(0)));
+                instructions.extend(write_back);
+                instructions
+            }
+            ASTNode::FnDecl {
+                name,
+                params,
+                return_type,
+                body,
+                is_generator,
+                is_const,
+            } => {
+                if is_const {
+                    self.const_fns.push(ASTNode::FnDecl {
+                        name: name.clone(),
+                        params: params.clone(),
+                        return_type,
+                        body: body.clone(),
+                        is_generator,
+                        is_const,
+                    });
+                }
+                let mut instructions = self.compile_function(name.clone(), params, body, is_generator);
+                let store = self.store(&name);
+                instructions.push(store);
+                instructions
+            }
+            ASTNode::ClassDecl { name, methods } => {
+                let method_count = methods.len();
+                let mut instructions = Vec::new();
+                for method in methods {
+                    if let ASTNode::FnDecl {
+                        name,
+                        params,
+                        body,
+                        is_generator,
+                        ..
+                    } = method
+                    {
+                        instructions.extend(self.compile_function(name, params, body, is_generator));
                     }
-                },
+                }
+                instructions.push(Instruction::MakeClass(name.clone(), method_count));
+                let store = self.store(&name);
+                instructions.push(store);
+                instructions
+            }
+            ASTNode::NullCoalesce { left, right } => {
+                let skip = self.label();
+                let mut instructions = self.compile(*left);
+                instructions.push(Instruction::JmpIfNotNull(skip));
+                instructions.push(Instruction::Pop);
+                instructions.extend(self.compile(*right));
+                instructions.push(Instruction::Label(skip));
+                instructions
+            }
+            ASTNode::FieldAssign {
+                object,
+                field,
+                value,
+            } => {
+                let mut instructions = self.compile(*object);
+                instructions.extend(self.compile(*value));
+                instructions.push(Instruction::SetField(field));
+                instructions
+            }
+            ASTNode::Call { callee, args, .. } => {
+                let argc = args.len();
+                let mut instructions = self.compile(*callee);
+                for arg in args {
+                    instructions.extend(self.compile(arg));
+                }
+                instructions.push(Instruction::Call(argc));
+                instructions
+            }
+            ASTNode::Return { value, .. } => {
+                let mut instructions = match value {
+                    Some(value) => self.compile(*value),
+                    None => vec![Instruction::Push(Value::Null)],
+                };
+                instructions.push(Instruction::Return);
+                instructions
+            }
+            ASTNode::Yield { value, .. } => {
+                let mut instructions = self.compile(*value);
+                instructions.push(Instruction::Yield);
+                instructions
+            }
+            ASTNode::Assert { .. } if !self.asserts => Vec::new(),
+            ASTNode::Assert {
+                condition,
+                message,
+                text,
+                span,
+            } => {
+                let mut instructions = self.compile(*condition);
+                let has_message = message.is_some();
+                if let Some(message) = message {
+                    instructions.extend(self.compile(*message));
+                }
+                instructions.push(Instruction::Assert {
+                    text,
+                    span,
+                    has_message,
+                });
+                instructions
+            }
+            ASTNode::Comptime { body, span } => {
+                vec![Instruction::Push(self.evaluate(body, span))]
+            }
+            ASTNode::Defer(value) => {
+                // The action is laid out inline and skipped until the scope ends.
+                let action = self.label();
+                let after = self.label();
+                let mut instructions = vec![
+                    Instruction::Defer(action),
+                    Instruction::Jmp(after),
+                    Instruction::Label(action),
+                ];
+                instructions.extend(self.compile(*value));
+                instructions.push(Instruction::Pop);
+                instructions.push(Instruction::EndDefer);
+                instructions.push(Instruction::Label(after));
+                instructions
             }
-            self.ip += 1;
         }
-        
-        if scope_depth != 0 {
-            return Err(VMError::ExecutionError {
-                message: format!("Unclosed scopes at end of execution: {}", scope_depth),
-                line: 0, 
-                position: 0,
-            });
+    }
+
+    pub fn compile_program(&mut self, nodes: Vec<ASTNode>) -> Result<Program, VMError> {
+        let mut instructions = Vec::new();
+        for node in nodes {
+            instructions.extend(self.compile(node));
+        }
+        let mut errors = std::mem::take(&mut self.errors);
+        match errors.len() {
+            0 => {
+                let mut instructions = link(instructions)?;
+                if self.peephole {
+                    instructions = peephole::optimize(instructions);
+                }
+                Ok(Program::new(instructions, self.globals.clone()))
+            }
+            1 => Err(errors.remove(0)),
+            _ => Err(VMError::CompileFailed { errors }),
         }
-        
-        Ok(())
     }
-}
 
-pub fn compile(node: ASTNode) -> Vec<Instruction> {
-    match node {
-        ASTNode::Number(n) => vec![Instruction::Push(Value::Number(n))],
-        ASTNode::String(s) => vec![Instruction::Push(Value::String(s))],
-        ASTNode::BinOp { left, op, right } => {
-            let mut instructions = compile(*left);
-            instructions.extend(compile(*right));
-            match op {
-                Token::Plus => instructions.push(Instruction::Add),
-                Token::Minus => instructions.push(Instruction::Sub),
-                Token::Star => instructions.push(Instruction::Mul),
-                Token::Slash => instructions.push(Instruction::Div),
-                Token::Greater => instructions.push(Instruction::Greater),
-                Token::Less => instructions.push(Instruction::Less),
-                Token::Equal => instructions.push(Instruction::Equal),
-                Token::NotEqual => instructions.push(Instruction::NotEqual),
-                _ => panic!("Unsupported operation"),
-            }
-            instructions
-        }
-        ASTNode::If {
-            condition,
-            if_block,
-            else_block,
-        } => {
-            let mut instructions = compile(*condition);
-            let if_instructions: Vec<Instruction> =
-                if_block.into_iter().flat_map(compile).collect();
-            let else_instructions: Vec<Instruction> =
-                else_block.into_iter().flat_map(compile).collect();
-
-            let else_start = instructions.len() + if_instructions.len() + 2;
-            instructions.push(Instruction::Jz(else_start));
-
-            instructions.extend(if_instructions);
-
-            let after_else = else_start + else_instructions.len();
-            instructions.push(Instruction::Jmp(after_else));
-
-            instructions.extend(else_instructions);
-            instructions
-        }
-        ASTNode::While { condition, body } => {
-            let mut instructions = Vec::new();
-            // Record where condition check starts
-            let condition_start = instructions.len();
-            instructions.extend(compile(*condition));
-
-            // Record where we'll put the Jz instruction
-            let jz_placeholder_index = instructions.len();
-            instructions.push(Instruction::Jz(0)); // Temporary placeholder
-
-            let body_instructions: Vec<Instruction> = body.into_iter().flat_map(compile).collect();
-            let body_len = body_instructions.len();
-            instructions.extend(body_instructions);
-            instructions.push(Instruction::Jmp(condition_start));
-
-            let after_loop = jz_placeholder_index + 1 + body_len + 1;
-            instructions[jz_placeholder_index] = Instruction::Jz(after_loop);
-
-            instructions
-        }
-        ASTNode::VarDecl(name, value) => {
-            let mut instructions = compile(*value);
-            instructions.push(Instruction::Store(name));
-            instructions
-        }
-        ASTNode::VarAssign(name, value) => {
-            let mut instructions = compile(*value);
-            instructions.push(Instruction::Store(name));
-            instructions
-        }
-        ASTNode::VarRef(name) => vec![Instruction::Load(name)],
-        ASTNode::Block(nodes) => {
-            let mut instructions = vec![Instruction::BeginScope];
-            instructions.extend(nodes.into_iter().flat_map(compile));
-            instructions.push(Instruction::EndScope);
-            instructions
-        }
-        ASTNode::Array(elements) => {
-            let mut instructions = vec![Instruction::CreateArray];
-            for element in elements {
-                instructions.extend(compile(element));
-                instructions.push(Instruction::ArrayOp(ArrayOperation::Push));
-            }
-            instructions
-        }
-        ASTNode::ArrayIndex { array, index } => {
-            let mut instructions = compile(*array);
-            instructions.extend(compile(*index));
-            instructions.push(Instruction::ArrayOp(ArrayOperation::Get(0)));
-            instructions
-        }
-        ASTNode::ArrayAssign { array, index, value } => {
-            let mut instructions = compile(*array);
-            instructions.extend(compile(*index));
-            instructions.extend(compile(*value));
-            instructions.push(Instruction::ArrayOp(ArrayOperation::Set(0)));
-            instructions
+    /// Runs a `comptime` block in a nested VM that only sees the `const fn`s
+    /// declared so far, and returns the value of its last expression.
+    fn evaluate(&mut self, mut body: Vec<ASTNode>, span: SourceSpan) -> Value {
+        // A compiler of its own keeps the block's globals out of the program's.
+        let mut compiler = Compiler::default().with_asserts(self.asserts);
+        let mut instructions = Vec::new();
+        for function in self.const_fns.clone() {
+            instructions.extend(compiler.compile(function));
         }
+        let last = body.pop();
+        instructions.extend(compiler.compile_body(body));
+        match last {
+            Some(node) if produces_value(&node) => instructions.extend(compiler.compile(node)),
+            Some(node) => {
+                instructions.extend(compiler.compile(node));
+                instructions.push(Instruction::Push(Value::Null));
+            }
+            None => instructions.push(Instruction::Push(Value::Null)),
+        }
+        self.errors.append(&mut compiler.errors);
+
+        let mut vm = VM::with_fuel(COMPTIME_FUEL);
+        let result = link(instructions)
+            .map(|instructions| Program::new(instructions, compiler.globals))
+            .and_then(|program| vm.execute(&program))
+            .and_then(|()| {
+            let value = vm.stack.pop().unwrap_or(Value::Null);
+            if is_constant(&value) {
+                Ok(value)
+            } else {
+                Err(VMError::TypeError {
+                    message: format!("Cannot embed a {} in the program", value.type_name()),
+                })
+            }
+        });
+        result.unwrap_or_else(|error| {
+            self.errors.push(VMError::ComptimeError {
+                message: error.to_string(),
+                span,
+            });
+            Value::Null
+        })
+    }
 }
-}
 
-pub fn run_instructions(nodes: Vec<ASTNode>) -> Vec<Instruction> {
-    let mut instr = Vec::new();
-    let mut offset = 0;
-    for node in nodes {
-        let mut node_instructions = compile(node);
-        for instruction in &mut node_instructions {
-            match instruction {
-                Instruction::Jmp(target) => *target += offset,
-                Instruction::Jz(target) => *target += offset,
-                _ => {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+
+    /// Compiles and runs `source`, returning the VM it ran on, the program
+    /// and how it ended.
+    fn execute(source: &str) -> (VM, Program, Result<(), VMError>) {
+        let nodes = Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .expect("test program parses");
+        let program = Compiler::default()
+            .compile_program(nodes)
+            .expect("test program compiles");
+        let mut vm = VM::new();
+        let outcome = vm.execute(&program);
+        (vm, program, outcome)
+    }
+
+    /// The global `name` after `source` runs to completion, in debug form.
+    fn global(source: &str, name: &str) -> String {
+        let (vm, program, outcome) = execute(source);
+        if let Err(error) = outcome {
+            panic!("{} failed: {}", source, error);
+        }
+        let value = vm
+            .global_values(&program)
+            .into_iter()
+            .find(|(global, _)| *global == name)
+            .map(|(_, value)| format!("{:?}", value));
+        value.unwrap_or_else(|| panic!("{} is not set", name))
+    }
+
+    /// The error `source` fails with.
+    fn error(source: &str) -> String {
+        match execute(source).2 {
+            Ok(()) => panic!("{} ran without error", source),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    const COUNT: &str = "fn* count(n) { i = 0; while (i < n) { yield i; i = i + 1 } }\n";
+
+    #[test]
+    fn generators_resume_where_they_yielded() {
+        let source = format!("{}g = count(3)\na = g.next()\nb = g.next()\nc = g.next()", COUNT);
+        assert_eq!(global(&source, "a"), "Number(0)");
+        assert_eq!(global(&source, "b"), "Number(1)");
+        assert_eq!(global(&source, "c"), "Number(2)");
+    }
+
+    #[test]
+    fn exhausted_generators_produce_null() {
+        let source = format!("{}g = count(1)\na = g.next()\nb = g.next()\nc = g.next()", COUNT);
+        assert_eq!(global(&source, "a"), "Number(0)");
+        assert_eq!(global(&source, "b"), "Null");
+        assert_eq!(global(&source, "c"), "Null");
+    }
+
+    #[test]
+    fn generators_end_at_return() {
+        let source = "fn* once() { yield 1; return 5; yield 2 }\ng = once()\na = g.next()\nb = g.next()";
+        assert_eq!(global(source, "a"), "Number(1)");
+        assert_eq!(global(source, "b"), "Null");
+    }
+
+    #[test]
+    fn for_loops_run_generators_to_the_end() {
+        let source = format!("{}s = 0\nfor v in count(5) {{ s = s * 10 + v }}", COUNT);
+        assert_eq!(global(&source, "s"), "Number(1234)");
+    }
+
+    #[test]
+    fn for_loops_resume_partly_used_generators() {
+        let source = format!("{}g = count(4)\nfirst = g.next()\ns = 0\nfor v in g {{ s = s + v }}", COUNT);
+        assert_eq!(global(&source, "first"), "Number(0)");
+        assert_eq!(global(&source, "s"), "Number(6)");
+    }
+
+    #[test]
+    fn for_loops_walk_arrays() {
+        assert_eq!(global("s = 0\nfor v in [1, 2, 3] { s = s * 10 + v }", "s"), "Number(123)");
+    }
+
+    #[test]
+    fn yield_outside_a_generator_fails() {
+        assert_eq!(
+            error("fn f() { yield 1 }\nf()"),
+            "Execution error: Yield outside of a generator"
+        );
+    }
+
+    const COUNTER: &str = "class Counter {
+        fn init(self, start) { self.n = start }
+        fn inc(self) { self.n = self.n + 1; return self }
+        fn get(self) { return self.n }
+    }
+    ";
+
+    #[test]
+    fn constructors_run_init_and_methods_share_the_instance() {
+        let source = format!("{}c = Counter(5)\nc.inc()\nc.inc().inc()\nv = c.get()\nn = c.n", COUNTER);
+        assert_eq!(global(&source, "v"), "Number(8)");
+        assert_eq!(global(&source, "n"), "Number(8)");
+    }
+
+    #[test]
+    fn classes_without_init_take_no_arguments() {
+        assert_eq!(
+            global("class Empty { }\ne = Empty()\ne.x = 1\nx = e.x", "x"),
+            "Number(1)"
+        );
+        assert_eq!(
+            error("class Empty { }\ne = Empty(1)"),
+            "Function Empty expects 0 arguments, got 1"
+        );
+    }
+
+    #[test]
+    fn missing_members_and_arity_are_reported() {
+        let source = format!("{}c = Counter(1)\n", COUNTER);
+        assert_eq!(error(&format!("{}c.reset()", source)), "Counter has no member reset");
+        assert_eq!(error(&format!("{}x = c.missing", source)), "Counter has no member missing");
+        assert_eq!(error(&format!("{}d = Counter()", COUNTER)), "Function init expects 2 arguments, got 1");
+        assert_eq!(error("x = 1\nx.f = 2"), "Type error: Cannot set field f on number");
+    }
+
+    const VECTOR: &str = "class V {
+        fn init(self, x) { self.x = x }
+        fn add(self, o) { return V(self.x + o.x) }
+        fn sub(self, o) { return V(self.x - o.x) }
+        fn mul(self, k) { return V(self.x * k) }
+        fn div(self, k) { return V(self.x / k) }
+        fn eq(self, o) { return self.x == o.x }
+        fn lt(self, o) { return self.x < o.x }
+        fn gt(self, o) { return self.x > o.x }
+    }
+    ";
+
+    #[test]
+    fn arithmetic_dispatches_to_script_methods() {
+        let source = format!(
+            "{}a = (V(1) + V(2)).x\nb = (V(5) - V(2)).x\nc = (V(3) * 4).x\nd = (V(8) / 2).x",
+            VECTOR
+        );
+        assert_eq!(global(&source, "a"), "Number(3)");
+        assert_eq!(global(&source, "b"), "Number(3)");
+        assert_eq!(global(&source, "c"), "Number(12)");
+        assert_eq!(global(&source, "d"), "Number(4)");
+    }
+
+    #[test]
+    fn comparisons_dispatch_to_script_methods() {
+        let source = format!(
+            "{}e = V(1) == V(1)\nf = V(1) != V(1)\ng = V(1) < V(2)\nh = V(1) > V(2)",
+            VECTOR
+        );
+        assert_eq!(global(&source, "e"), "Boolean(true)");
+        assert_eq!(global(&source, "f"), "Boolean(false)");
+        assert_eq!(global(&source, "g"), "Boolean(true)");
+        assert_eq!(global(&source, "h"), "Boolean(false)");
+    }
+
+    #[test]
+    fn instances_without_the_protocol_method_are_rejected() {
+        assert!(error("class A { }\nx = A() + 1").starts_with("Type error: Cannot add Instance"));
+    }
+
+    #[test]
+    fn null_safe_access_short_circuits_on_null() {
+        let source = "n = null\na = n?.x\nb = n?[0]\nc = n?.f(1)\narr = [7]\nd = arr?[0]";
+        assert_eq!(global(source, "a"), "Null");
+        assert_eq!(global(source, "b"), "Null");
+        assert_eq!(global(source, "c"), "Null");
+        assert_eq!(global(source, "d"), "Number(7)");
+        assert_eq!(error("n = null\nx = n.x"), "Type error: Cannot read field x of null");
+    }
+
+    #[test]
+    fn null_safe_access_skips_the_rest_of_the_chain() {
+        let source = "n = null\na = n?.b.c\nb = n?[0][1]\nc = n?.f(1).g[2]\nd = n?.b.c ?? 4\narr = [[5, 6]]\ne = arr[(n?.b.c ?? 0)][1]";
+        assert_eq!(global(source, "a"), "Null");
+        assert_eq!(global(source, "b"), "Null");
+        assert_eq!(global(source, "c"), "Null");
+        assert_eq!(global(source, "d"), "Number(4)");
+        assert_eq!(global(source, "e"), "Number(6)");
+        // Only the links after the `?` are skipped.
+        let source = "class A { fn init(self) { self.b = null } }\na = A()";
+        assert_eq!(
+            error(&format!("{}\nx = a?.b.c", source)),
+            "Type error: Cannot read field c of null"
+        );
+    }
+
+    #[test]
+    fn null_coalescing_only_evaluates_the_fallback_for_null() {
+        let source = "n = null\na = n ?? 5\nb = 3 ?? (1 - \"s\")";
+        assert_eq!(global(source, "a"), "Number(5)");
+        assert_eq!(global(source, "b"), "Number(3)");
+    }
+
+    #[test]
+    fn tuples_destructure_and_index() {
+        let source = "t = (1, \"a\", 3)\n(x, y, z) = t\nl = t.len()\ne = t[1]\nc = (null, 1) < (null, 2)";
+        assert_eq!(global(source, "x"), "Number(1)");
+        assert_eq!(global(source, "y"), "String(\"a\")");
+        assert_eq!(global(source, "z"), "Number(3)");
+        assert_eq!(global(source, "l"), "Number(3)");
+        assert_eq!(global(source, "e"), "String(\"a\")");
+        assert_eq!(global(source, "c"), "Boolean(true)");
+    }
+
+    #[test]
+    fn tuples_are_immutable_and_unpack_exactly() {
+        assert_eq!(error("t = (1, 2)\nt[0] = 5"), "Type error: Tuples are immutable");
+        assert_eq!(
+            error("t = (1, 2)\n(a, b, c) = t"),
+            "Type error: Cannot unpack Tuple([Number(1), Number(2)]) into 3 variables"
+        );
+    }
+
+    #[test]
+    fn strings_index_and_count_by_character() {
+        let source = "s = \"h\\u{e9}llo\"\nc = s[1]\nn = s.len()\nt = \"\"\nfor ch in \"\u{e9}a\" { t = ch + t }";
+        assert_eq!(global(source, "c"), "Char('\u{e9}')");
+        assert_eq!(global(source, "n"), "Number(5)");
+        assert_eq!(global(source, "t"), "String(\"a\u{e9}\")");
+    }
+
+    #[test]
+    fn byte_strings_hold_raw_bytes() {
+        let source = "b = b\"a\\xff\"\nx = b[1]\nn = b.len()\nc = b + b\"!\"";
+        assert_eq!(global(source, "x"), "Number(255)");
+        assert_eq!(global(source, "n"), "Number(2)");
+        assert_eq!(global(source, "c"), "Bytes([97, 255, 33])");
+    }
+
+    const LOG: &str = "class Log {\n  fn init(self) { self.s = 0 }\n  fn add(self, i) { self.s = self.s * 10 + i }\n}\nl = Log()\n";
+
+    #[test]
+    fn loop_bodies_run_their_deferred_actions_each_time_round() {
+        let source = format!("{}fn h() {{ for i in [1, 2] {{ defer l.add(i) }} }}\nh()\ns = l.s", LOG);
+        assert_eq!(global(&source, "s"), "Number(12)");
+        let source = format!(
+            "{}fn h() {{ i = 1; while (i < 3) {{ defer l.add(i); i = i + 1 }}; l.add(9) }}\nh()\ns = l.s",
+            LOG
+        );
+        assert_eq!(global(&source, "s"), "Number(239)");
+    }
+
+    #[test]
+    fn if_bodies_run_their_deferred_actions_when_they_finish() {
+        let source = format!("{}fn h() {{ if (1) {{ defer l.add(1) }} else {{ defer l.add(3) }}; l.add(2) }}\nh()\ns = l.s", LOG);
+        assert_eq!(global(&source, "s"), "Number(12)");
+    }
+
+    #[test]
+    fn returning_from_a_loop_body_runs_its_deferred_actions() {
+        let source = format!(
+            "{}fn h() {{ for i in [1, 2, 3] {{ defer l.add(i); if (i == 2) {{ return 0 }} }} }}\nh()\ns = l.s",
+            LOG
+        );
+        assert_eq!(global(&source, "s"), "Number(12)");
+    }
+
+    #[test]
+    fn failed_assertions_carry_the_expression_message_and_span() {
+        let source = "x = 1\nassert x == 1\nassert x == 2, \"x is \" + \"one\"";
+        match execute(source).2 {
+            Err(VMError::AssertionFailed {
+                expression,
+                message,
+                span,
+            }) => {
+                assert_eq!(expression, "x == 2");
+                assert_eq!(message.as_deref(), Some("x is one"));
+                assert_eq!(&source[span.offset()..span.offset() + span.len()], "x == 2");
+            }
+            other => panic!("expected a failed assertion, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn assertions_can_be_compiled_out() {
+        let nodes = Parser::new(Tokenizer::new("assert 0\nx = 1".to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .unwrap();
+        let program = Compiler::default()
+            .with_asserts(false)
+            .compile_program(nodes)
+            .unwrap();
+        assert!(!program
+            .instructions
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::Assert { .. })));
+        assert!(VM::new().execute(&program).is_ok());
+    }
+
+    /// The messages of the errors compiling `source` fails with.
+    fn compile_errors(source: &str) -> Vec<String> {
+        let nodes = Parser::new(Tokenizer::new(source.to_string()))
+            .and_then(|mut parser| parser.parse_program())
+            .expect("test program parses");
+        match Compiler::default().compile_program(nodes) {
+            Ok(_) => panic!("{} compiled without error", source),
+            Err(VMError::CompileFailed { errors }) => {
+                errors.iter().map(ToString::to_string).collect()
+            }
+            Err(error) => vec![error.to_string()],
+        }
+    }
+
+    #[test]
+    fn comptime_blocks_are_replaced_by_their_value() {
+        let source = "const fn sq(x) { return x * x }\ny = comptime { a = sq(7); a + 1 }";
+        let (_, program, outcome) = execute(source);
+        assert!(outcome.is_ok());
+        assert!(program.constants.iter().any(|c| matches!(c, Value::Number(50))));
+        assert!(!program.instructions.iter().any(|i| matches!(i, Instruction::Call(_))));
+        // The block's own variables stay out of the program.
+        assert_eq!(program.globals, ["sq", "y"]);
+        assert_eq!(global(source, "y"), "Number(50)");
+    }
+
+    #[test]
+    fn comptime_blocks_only_see_const_fns() {
+        assert_eq!(
+            compile_errors("fn f() { return 1 }\ny = comptime { f() }"),
+            ["Compile-time evaluation failed: Undefined variable: f"]
+        );
+    }
+
+    #[test]
+    fn comptime_blocks_run_out_of_fuel() {
+        assert_eq!(
+            compile_errors("y = comptime { while (1) { } }"),
+            ["Compile-time evaluation failed: Ran out of fuel"]
+        );
+    }
+
+    #[test]
+    fn comptime_values_must_be_embeddable() {
+        assert_eq!(
+            compile_errors("y = comptime { fn g() { return 1 }; g }"),
+            ["Compile-time evaluation failed: Type error: Cannot embed a function in the program"]
+        );
+    }
+
+    #[test]
+    fn comptime_errors_are_reported_together() {
+        assert_eq!(
+            compile_errors("a = comptime { q() }\nb = comptime { r() }"),
+            [
+                "Compile-time evaluation failed: Undefined variable: q",
+                "Compile-time evaluation failed: Undefined variable: r",
+            ]
+        );
+    }
+
+    #[test]
+    fn function_variables_live_in_slots() {
+        let source = "fn f(a) { b = a + 1; return b }\nx = f(1)";
+        let (_, program, _) = execute(source);
+        assert_eq!(program.globals, ["f", "x"]);
+        assert!(program.instructions.iter().any(|i| matches!(i, Instruction::LoadLocal(0))));
+        assert_eq!(global(source, "x"), "Number(2)");
+    }
+
+    #[test]
+    fn each_call_gets_its_own_slots() {
+        let source = "fn fib(n) { if (n < 2) { return n }; a = fib(n - 1); b = fib(n - 2); return a + b }\nx = fib(10)";
+        assert_eq!(global(source, "x"), "Number(55)");
+    }
+
+    #[test]
+    fn assignments_in_a_block_declare_block_locals() {
+        let source = "x = 1\n{ y = x; x = 2; z = x }\nw = x";
+        let (_, program, _) = execute(source);
+        assert_eq!(program.globals, ["x", "w"]);
+        assert_eq!(global(source, "w"), "Number(1)");
+    }
+
+    #[test]
+    fn element_assignments_write_back_to_their_variable_or_field() {
+        let source = "a = [1, 2]\na[0] = 5\nclass C { fn init(self) { self.items = [0] } }\nc = C()\nc.items[0] = 7\nv = c.items[0]";
+        assert_eq!(global(source, "a"), "Array([Number(5), Number(2)])");
+        assert_eq!(global(source, "v"), "Number(7)");
+        assert_eq!(
+            compile_errors("f()[0] = 1"),
+            ["Compile error: Can only assign to elements of variables and fields"]
+        );
+    }
+
+    #[test]
+    fn plain_constants_are_pooled_once() {
+        let function = Value::Function(Rc::new(Function {
+            name: "f".to_string(),
+            params: Vec::new(),
+            entry: 0,
+            is_generator: false,
+        }));
+        let program = Program::new(
+            vec![
+                Instruction::Push(Value::Number(1)),
+                Instruction::Push(Value::String("s".into())),
+                Instruction::Push(Value::Number(1)),
+                Instruction::Push(Value::String("s".into())),
+                Instruction::Push(function.clone()),
+                Instruction::Push(function),
+                Instruction::Push(Value::Null),
+            ],
+            Vec::new(),
+        );
+        let indices: Vec<u32> = program
+            .instructions
+            .iter()
+            .map(|instruction| match instruction {
+                Instruction::PushConst(index) => *index,
+                other => panic!("{:?} was not pooled", other),
+            })
+            .collect();
+        // Functions get an entry for every occurrence.
+        assert_eq!(indices, [0, 1, 0, 1, 2, 3, 4]);
+        assert_eq!(program.constants.len(), 5);
+    }
+
+    #[test]
+    fn pushing_a_pooled_string_shares_its_text() {
+        let (vm, program, outcome) = execute("a = \"text\"\nb = \"text\"");
+        assert!(outcome.is_ok());
+        let values = vm.global_values(&program);
+        match (&values[0].1, &values[1].1, &program.constants[0]) {
+            (Value::String(a), Value::String(b), Value::String(pooled)) => {
+                assert!(Rc::ptr_eq(a, pooled) && Rc::ptr_eq(b, pooled));
             }
+            other => panic!("expected pooled strings, got {:?}", other),
         }
-        offset += node_instructions.len();
-        instr.extend(node_instructions);
     }
-    instr
+
+    #[test]
+    fn overflow_at_run_time_is_an_error() {
+        assert_eq!(error("x = 2147483647\ny = x + 1"), "Integer overflow");
+        assert_eq!(error("fn f(a) { return a * 65536 }\ny = f(65536)"), "Integer overflow");
+    }
+
+    #[test]
+    fn linking_resolves_labels_and_strips_them() {
+        let function = Rc::new(Function {
+            name: "f".to_string(),
+            params: Vec::new(),
+            entry: 7,
+            is_generator: false,
+        });
+        let linked = link(vec![
+            Instruction::Jmp(8),
+            Instruction::Label(7),
+            Instruction::Push(Value::Null),
+            Instruction::Return,
+            Instruction::Label(8),
+            Instruction::Push(Value::Function(function)),
+            Instruction::Jz(8),
+        ])
+        .unwrap();
+        assert_eq!(linked.len(), 5);
+        assert!(matches!(linked[0], Instruction::Jmp(3)));
+        assert!(matches!(&linked[3], Instruction::Push(Value::Function(f)) if f.entry == 1));
+        assert!(matches!(linked[4], Instruction::Jz(3)));
+    }
+
+    #[test]
+    fn linking_rejects_undefined_labels() {
+        assert!(matches!(
+            link(vec![Instruction::Label(0), Instruction::Jmp(1)]),
+            Err(VMError::UndefinedLabel { label: 1 })
+        ));
+    }
 }